@@ -1,6 +1,43 @@
+use std::path::PathBuf;
+use clap::Parser;
 use dray_lib::distributed::client::{run_client};
+use dray_lib::distributed::config::ClusterArgs;
+use dray_lib::raytracer::scene::DEFAULT_SCENE;
+
+#[derive(Parser)]
+struct Args {
+    /// Scene description file to render
+    #[arg(default_value = DEFAULT_SCENE)]
+    scene: PathBuf,
+    /// Image files to write once the render finishes (.ppm, .png or .pfm)
+    #[arg(short, long)]
+    output: Vec<PathBuf>,
+    /// Denoise the image after every sample pass, showing the denoised image in the preview window
+    /// and writing it next to each output (e.g. img.denoised.png)
+    #[arg(long)]
+    denoise: bool,
+    /// Also write the depth, normal, albedo and object id of the first hits, next to each output
+    /// (e.g. img.depth.pfm)
+    #[arg(long)]
+    aovs: bool,
+    /// Render without opening a preview window
+    #[arg(long)]
+    headless: bool,
+    #[command(flatten)]
+    cluster: ClusterArgs,
+}
 
 #[tokio::main]
 async fn main() {
-    let _ = run_client().await;
-}
\ No newline at end of file
+    let args = Args::parse();
+    let cluster = match args.cluster.load() {
+        Ok(cluster) => cluster,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run_client(&args.scene, &args.output, args.denoise, args.aovs, args.headless, &cluster).await {
+        eprintln!("Client failed: {}", e);
+    }
+}
@@ -1,15 +1,17 @@
 use dray_lib::distributed::orchestrator_server::run_orchestrator;
 use dray_lib::distributed::distributed_common::run_server;
-use dray_lib::distributed::config::{NUM_OBJ_SERVERS, NUM_RAY_SERVERS, TCP_END_PORT, TCP_START_PORT};
+use dray_lib::distributed::config::{ClusterArgs, ClusterConfig};
+use clap::Parser;
 use std::thread::sleep;
 use std::time::Duration;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use clap::ValueEnum;
 use tokio::net::TcpListener;
 
-async fn find_available_port_in_range(start_port: u16, end_port: u16) -> Option<u16> {
+async fn find_available_port_in_range(addr: IpAddr, start_port: u16, end_port: u16) -> Option<u16> {
     for port in start_port..=end_port {
         // Attempt to bind to the port.
-        if let Ok(_listener) = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await {
+        if let Ok(_listener) = TcpListener::bind((addr, port)).await {
             // If the bind is successful, the port is available.
             // We can immediately drop the listener to free the port for our main server.
             println!("✅ Found an available port: {}", port);
@@ -20,29 +22,68 @@ async fn find_available_port_in_range(start_port: u16, end_port: u16) -> Option<
     None
 }
 
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Role {
+    /// The servers and the orchestrator, for a cluster on a single host
+    All,
+    /// Only object and ray servers, announcing themselves to an orchestrator on another host
+    Servers,
+    /// Only the orchestrator, which clients connect to
+    Orchestrator,
+}
+
+/// Runs the object servers, the ray servers and the orchestrator of a render cluster.
+#[derive(Parser)]
+struct Args {
+    /// Which part of the cluster to run on this host
+    #[arg(long, value_enum, default_value_t = Role::All)]
+    role: Role,
+    #[command(flatten)]
+    cluster: ClusterArgs,
+}
+
+async fn run_server_or_report(port: u16, is_object_server: bool, config: ClusterConfig) {
+    if let Err(e) = run_server(port, is_object_server, config).await {
+        eprintln!("Server on port {} failed: {}", port, e);
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    let config = match args.cluster.load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
     let mut handles = vec![];
+    let num_servers = |n| if args.role == Role::Orchestrator { 0 } else { n };
 
-    for _i in 0..NUM_OBJ_SERVERS {
-        let handle = tokio::spawn(run_server(
-            find_available_port_in_range(TCP_START_PORT, TCP_END_PORT).await.expect("No available port found"),
-            true
+    for _i in 0..num_servers(config.num_obj_servers) {
+        let handle = tokio::spawn(run_server_or_report(
+            find_available_port_in_range(config.bind_addr, config.tcp_start_port, config.tcp_end_port).await.expect("No available port found"),
+            true,
+            config.clone()
         ));
         sleep(Duration::from_secs_f32(0.1));
         handles.push(handle);
     }
 
-    for _i in 0..NUM_RAY_SERVERS {
-        let handle = tokio::spawn(run_server(
-            find_available_port_in_range(TCP_START_PORT, TCP_END_PORT).await.expect("No available port found"),
-            false
+    for _i in 0..num_servers(config.num_ray_servers) {
+        let handle = tokio::spawn(run_server_or_report(
+            find_available_port_in_range(config.bind_addr, config.tcp_start_port, config.tcp_end_port).await.expect("No available port found"),
+            false,
+            config.clone()
         ));
         sleep(Duration::from_secs_f32(0.1));
         handles.push(handle);
     }
 
-    run_orchestrator().await;
+    if args.role != Role::Servers {
+        run_orchestrator(config).await;
+    }
 
     // Wait for all server threads to finish
     for handle in handles {
@@ -0,0 +1,194 @@
+//! Encoding of the messages between nodes, shared by requests and responses.
+//!
+//! A frame is its length (u32, little endian, counting what follows), a request id (u64), a kind
+//! byte and a payload. The payload of a message frame is the bincode encoding of the message; the
+//! payload of an error frame is the text of an error the peer hit handling the request with that
+//! id, e.g. a message it couldn't decode.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, ErrorKind};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Largest frame either side sends or accepts, which leaves room for large meshes and
+// environment maps
+pub const MAX_FRAME_SIZE: usize = 64 << 20;
+// Bytes of a frame after the length and before the payload
+const HEADER_SIZE: usize = 9;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    // a frame, or the length it claims, past MAX_FRAME_SIZE
+    TooLarge(usize),
+    // the connection closed partway through a frame
+    Truncated,
+    // a frame too short for its header, or of an unknown kind
+    Malformed(String),
+    Encode(bincode::error::EncodeError),
+    Decode(bincode::error::DecodeError),
+    // a payload with bytes left over after its message
+    TrailingBytes(usize),
+    // the error the peer sent back for a request
+    Remote(String),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "{}", e),
+            CodecError::TooLarge(len) => write!(f, "frame of {} bytes is over the {} byte limit", len, MAX_FRAME_SIZE),
+            CodecError::Truncated => write!(f, "connection closed partway through a frame"),
+            CodecError::Malformed(msg) => write!(f, "malformed frame: {}", msg),
+            CodecError::Encode(e) => write!(f, "can't encode message: {}", e),
+            CodecError::Decode(e) => write!(f, "can't decode message: {}", e),
+            CodecError::TrailingBytes(n) => write!(f, "{} bytes left over after the message", n),
+            CodecError::Remote(msg) => write!(f, "peer failed to handle the request: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == ErrorKind::UnexpectedEof { CodecError::Truncated } else { CodecError::Io(e) }
+    }
+}
+
+// Keeps the CodecError inside, for callers to downcast to.
+impl From<CodecError> for io::Error {
+    fn from(e: CodecError) -> Self {
+        match e {
+            CodecError::Io(e) => e,
+            CodecError::Truncated => io::Error::new(ErrorKind::UnexpectedEof, e),
+            CodecError::TooLarge(_) | CodecError::Encode(_) => io::Error::new(ErrorKind::InvalidInput, e),
+            _ => io::Error::new(ErrorKind::InvalidData, e)
+        }
+    }
+}
+
+pub fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>, CodecError> {
+    bincode::serde::encode_to_vec(message, bincode::config::standard()).map_err(CodecError::Encode)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    let (message, num_bytes_decoded) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+        .map_err(CodecError::Decode)?;
+    if num_bytes_decoded != bytes.len() {
+        return Err(CodecError::TrailingBytes(bytes.len() - num_bytes_decoded));
+    }
+    Ok(message)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FrameKind {
+    Message = 0,
+    Error = 1
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub id: u64,
+    pub kind: FrameKind,
+    pub payload: Vec<u8>
+}
+
+impl Frame {
+    pub fn message(id: u64, payload: Vec<u8>) -> Self {
+        Frame { id, kind: FrameKind::Message, payload }
+    }
+
+    pub fn error(id: u64, error: &dyn Display) -> Self {
+        Frame { id, kind: FrameKind::Error, payload: error.to_string().into_bytes() }
+    }
+
+    // The payload of a message frame, or the error of an error frame.
+    pub fn into_result(self) -> Result<Vec<u8>, CodecError> {
+        match self.kind {
+            FrameKind::Message => Ok(self.payload),
+            FrameKind::Error => Err(CodecError::Remote(String::from_utf8_lossy(&self.payload).into_owned()))
+        }
+    }
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> Result<(), CodecError> {
+    let len = HEADER_SIZE + frame.payload.len();
+    if len > MAX_FRAME_SIZE {
+        return Err(CodecError::TooLarge(len));
+    }
+    writer.write_all(&(len as u32).to_le_bytes()).await?;
+    writer.write_all(&frame.id.to_le_bytes()).await?;
+    writer.write_all(&[frame.kind as u8]).await?;
+    writer.write_all(&frame.payload).await?;
+    Ok(())
+}
+
+// The next frame, or None if the peer closed the connection between frames. Checks the length
+// before reading the rest, so a bad length can't make it allocate more than MAX_FRAME_SIZE.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>, CodecError> {
+    let mut len_bytes = [0; 4];
+    let mut filled = 0;
+    while filled < len_bytes.len() {
+        match reader.read(&mut len_bytes[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(CodecError::Truncated),
+            n => filled += n
+        }
+    }
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(CodecError::TooLarge(len));
+    }
+    if len < HEADER_SIZE {
+        return Err(CodecError::Malformed(format!("{} bytes is too short for the header", len)));
+    }
+    let mut header = [0; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    let id = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let kind = match header[8] {
+        0 => FrameKind::Message,
+        1 => FrameKind::Error,
+        kind => return Err(CodecError::Malformed(format!("unknown frame kind {}", kind)))
+    };
+    let mut payload = vec![0; len - HEADER_SIZE];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(Frame { id, kind, payload }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, read_frame, write_frame, CodecError, Frame, FrameKind, MAX_FRAME_SIZE};
+
+    #[tokio::test]
+    async fn test_frames() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &Frame::message(7, encode(&(1u32, "ray".to_string())).unwrap())).await.unwrap();
+        write_frame(&mut bytes, &Frame::error(8, &"no camera")).await.unwrap();
+
+        // a reader that returns a few bytes at a time still gets whole frames
+        let (mut write, mut read) = tokio::io::duplex(3);
+        let written = bytes.clone();
+        tokio::spawn(async move { tokio::io::AsyncWriteExt::write_all(&mut write, &written).await });
+        let frame = read_frame(&mut read).await.unwrap().unwrap();
+        assert_eq!((frame.id, frame.kind), (7, FrameKind::Message));
+        assert_eq!(decode::<(u32, String)>(&frame.into_result().unwrap()).unwrap(), (1, "ray".to_string()));
+        let frame = read_frame(&mut read).await.unwrap().unwrap();
+        assert!(matches!(frame.into_result(), Err(CodecError::Remote(msg)) if msg == "no camera"));
+        assert!(read_frame(&mut read).await.unwrap().is_none());
+
+        // cut short
+        let mut cut = &bytes[..bytes.len() - 1];
+        read_frame(&mut cut).await.unwrap();
+        assert!(matches!(read_frame(&mut cut).await, Err(CodecError::Truncated)));
+
+        // claiming more than the limit, which must fail before allocating it
+        let mut huge = &((MAX_FRAME_SIZE + 1) as u32).to_le_bytes()[..];
+        assert!(matches!(read_frame(&mut huge).await, Err(CodecError::TooLarge(_))));
+
+        // payloads that aren't the expected message
+        assert!(matches!(decode::<String>(&[200]), Err(CodecError::Decode(_))));
+        assert!(matches!(decode::<u8>(&encode(&(1u8, 2u8)).unwrap()), Err(CodecError::TrailingBytes(1))));
+    }
+}
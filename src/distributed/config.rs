@@ -1,14 +1,470 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+//! Settings of the distributed renderer.
+//!
+//! Every binary starts from the defaults below, then applies a JSON config file (from
+//! `--cluster-config` or DRAY_CLUSTER_CONFIG), then environment variables named after the fields
+//! (e.g. DRAY_NUM_RAY_SERVERS=8), then command line flags (e.g. `--num-ray-servers 8`), and checks
+//! the result before starting anything. A config file can leave out any field, e.g.
+//!
+//! ```json
+//! { "bind_addr": "0.0.0.0", "num_obj_servers": 8, "orchestrator_server_socket": "192.168.1.10:27302" }
+//! ```
 
-pub const TCP_START_PORT: u16 = 8000;
-pub const TCP_END_PORT: u16 = 9000;
+use std::fmt::Display;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use crate::distributed::discovery::ANNOUNCE_INTERVAL;
 
-pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 0);
-pub const MULTICAST_PORT: u16 = 7784;
+// How the orchestrator finds the servers, see distributed::discovery.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    /// Servers multicast their address to multicast_addr
+    #[default]
+    Multicast,
+    /// The servers are static_object_servers and static_ray_servers
+    Static,
+    /// Servers register with the orchestrator at registration_socket
+    Registration,
+}
 
-pub const ORCHESTRATOR_CLIENT_CONNECTION_SOCKET: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 27301);
-pub const ORCHESTRATOR_SERVER_CONNECTION_SOCKET: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 27302);
+impl FromStr for DiscoveryMode {
+    type Err = String;
 
-pub const NUM_OBJ_SERVERS: i32 = 50;
-pub const NUM_REPEAT_OBJECT: i32 = 10;
-pub const NUM_RAY_SERVERS: i32 = 5;
\ No newline at end of file
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    // interface the object and ray servers listen on, e.g. 0.0.0.0 or :: for all of them
+    pub bind_addr: IpAddr,
+    // address the servers announce to the orchestrator, which has to reach them at it. Defaults
+    // to bind_addr, or the address of the interface announcements go out on when that's unspecified
+    pub advertise_addr: Option<IpAddr>,
+    // ports the object and ray servers listen on are picked from this range
+    pub tcp_start_port: u16,
+    pub tcp_end_port: u16,
+
+    pub discovery: DiscoveryMode,
+    // how long the orchestrator looks for more servers after finding the last one
+    pub discovery_timeout_secs: f64,
+    // group the servers announce themselves to with multicast discovery
+    pub multicast_addr: IpAddr,
+    pub multicast_port: u16,
+    // the servers with static discovery
+    pub static_object_servers: Vec<SocketAddr>,
+    pub static_ray_servers: Vec<SocketAddr>,
+    // address the orchestrator accepts servers on with registration discovery
+    pub registration_socket: SocketAddr,
+
+    pub orchestrator_client_socket: SocketAddr,
+    pub orchestrator_server_socket: SocketAddr,
+
+    pub num_obj_servers: i32,
+    // how many times the grid of scene regions is repeated, each region going to another object
+    // server, so a region still has servers left when one fails
+    pub num_repeat_object: i32,
+    pub num_ray_servers: i32,
+
+    // how long the orchestrator waits for the next sample before giving up on the rest of a pass
+    pub sample_timeout_secs: f64
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            bind_addr: Ipv4Addr::LOCALHOST.into(),
+            advertise_addr: None,
+            tcp_start_port: 8000,
+            tcp_end_port: 9000,
+            discovery: DiscoveryMode::Multicast,
+            discovery_timeout_secs: 5.,
+            multicast_addr: Ipv4Addr::new(224, 0, 0, 0).into(),
+            multicast_port: 7784,
+            static_object_servers: Vec::new(),
+            static_ray_servers: Vec::new(),
+            registration_socket: (Ipv4Addr::LOCALHOST, 27303).into(),
+            orchestrator_client_socket: (Ipv4Addr::LOCALHOST, 27301).into(),
+            orchestrator_server_socket: (Ipv4Addr::LOCALHOST, 27302).into(),
+            num_obj_servers: 50,
+            num_repeat_object: 10,
+            num_ray_servers: 5,
+            sample_timeout_secs: 30.
+        }
+    }
+}
+
+fn config_error(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("invalid cluster configuration: {}", msg))
+}
+
+// Looks up environment variables, see ClusterArgs::load_with_env.
+type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+fn process_env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+// Parses the environment variable `name` into `value`, if it's set.
+fn env_override<T: FromStr>(env: Env, name: &str, value: &mut T) -> Result<()>
+where
+    T::Err: Display
+{
+    if let Some(text) = env(name) {
+        *value = text.parse().map_err(|e| config_error(format!("{}={}: {}", name, text, e)))?;
+    }
+    Ok(())
+}
+
+// Same for comma separated lists.
+fn env_list_override<T: FromStr>(env: Env, name: &str, values: &mut Vec<T>) -> Result<()>
+where
+    T::Err: Display
+{
+    if let Some(text) = env(name) {
+        *values = text.split(',').map(|item| item.trim().parse()).collect::<std::result::Result<_, _>>()
+            .map_err(|e| config_error(format!("{}={}: {}", name, text, e)))?;
+    }
+    Ok(())
+}
+
+impl ClusterConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        serde_json::from_str(&contents).map_err(|e| config_error(format!("{}: {}", path.display(), e)))
+    }
+
+    // Overrides the fields set in DRAY_<FIELD NAME> environment variables.
+    pub fn apply_env(&mut self) -> Result<()> {
+        self.apply_env_from(&process_env)
+    }
+
+    // Same, looking the variables up with `env` instead of in the environment of the process.
+    pub fn apply_env_from(&mut self, env: Env) -> Result<()> {
+        env_override(env, "DRAY_BIND_ADDR", &mut self.bind_addr)?;
+        if let Some(text) = env("DRAY_ADVERTISE_ADDR") {
+            self.advertise_addr = Some(text.parse().map_err(|e| config_error(format!("DRAY_ADVERTISE_ADDR={}: {}", text, e)))?);
+        }
+        env_override(env, "DRAY_TCP_START_PORT", &mut self.tcp_start_port)?;
+        env_override(env, "DRAY_TCP_END_PORT", &mut self.tcp_end_port)?;
+        env_override(env, "DRAY_DISCOVERY", &mut self.discovery)?;
+        env_override(env, "DRAY_DISCOVERY_TIMEOUT_SECS", &mut self.discovery_timeout_secs)?;
+        env_override(env, "DRAY_MULTICAST_ADDR", &mut self.multicast_addr)?;
+        env_override(env, "DRAY_MULTICAST_PORT", &mut self.multicast_port)?;
+        env_list_override(env, "DRAY_STATIC_OBJECT_SERVERS", &mut self.static_object_servers)?;
+        env_list_override(env, "DRAY_STATIC_RAY_SERVERS", &mut self.static_ray_servers)?;
+        env_override(env, "DRAY_REGISTRATION_SOCKET", &mut self.registration_socket)?;
+        env_override(env, "DRAY_ORCHESTRATOR_CLIENT_SOCKET", &mut self.orchestrator_client_socket)?;
+        env_override(env, "DRAY_ORCHESTRATOR_SERVER_SOCKET", &mut self.orchestrator_server_socket)?;
+        env_override(env, "DRAY_NUM_OBJ_SERVERS", &mut self.num_obj_servers)?;
+        env_override(env, "DRAY_NUM_REPEAT_OBJECT", &mut self.num_repeat_object)?;
+        env_override(env, "DRAY_NUM_RAY_SERVERS", &mut self.num_ray_servers)?;
+        env_override(env, "DRAY_SAMPLE_TIMEOUT_SECS", &mut self.sample_timeout_secs)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.num_obj_servers < 1 || self.num_ray_servers < 1 || self.num_repeat_object < 1 {
+            return Err(config_error(format!(
+                "num_obj_servers ({}), num_ray_servers ({}) and num_repeat_object ({}) must be at least 1",
+                self.num_obj_servers, self.num_ray_servers, self.num_repeat_object)));
+        }
+        if self.tcp_start_port > self.tcp_end_port {
+            return Err(config_error(format!(
+                "tcp_start_port ({}) is after tcp_end_port ({})", self.tcp_start_port, self.tcp_end_port)));
+        }
+        let num_ports = (self.tcp_end_port - self.tcp_start_port) as i64 + 1;
+        let num_servers = self.num_obj_servers as i64 + self.num_ray_servers as i64;
+        if num_ports < num_servers {
+            return Err(config_error(format!(
+                "ports {}-{} can't fit {} servers", self.tcp_start_port, self.tcp_end_port, num_servers)));
+        }
+        if !self.multicast_addr.is_multicast() {
+            return Err(config_error(format!("multicast_addr {} is not a multicast address", self.multicast_addr)));
+        }
+        if let Some(addr) = self.advertise_addr
+            && (addr.is_unspecified() || addr.is_multicast())
+        {
+            return Err(config_error(format!("advertise_addr {} can't be reached at", addr)));
+        }
+        if self.orchestrator_client_socket == self.orchestrator_server_socket {
+            return Err(config_error(format!(
+                "orchestrator_client_socket and orchestrator_server_socket are both {}", self.orchestrator_client_socket)));
+        }
+        match self.discovery {
+            DiscoveryMode::Multicast | DiscoveryMode::Registration => {
+                // or servers that were found would be taken for the last ones while waiting for
+                // the next announcement of the others
+                if !(self.discovery_timeout_secs > ANNOUNCE_INTERVAL.as_secs_f64() && self.discovery_timeout_secs.is_finite()) {
+                    return Err(config_error(format!(
+                        "discovery_timeout_secs ({}) must be longer than the {} s between announcements",
+                        self.discovery_timeout_secs, ANNOUNCE_INTERVAL.as_secs_f64())));
+                }
+            }
+            DiscoveryMode::Static => {
+                if self.static_object_servers.is_empty() || self.static_ray_servers.is_empty() {
+                    return Err(config_error("static discovery needs static_object_servers and static_ray_servers".to_string()));
+                }
+            }
+        }
+        if self.discovery == DiscoveryMode::Registration
+            && [self.orchestrator_client_socket, self.orchestrator_server_socket].contains(&self.registration_socket)
+        {
+            return Err(config_error(format!("registration_socket {} is taken by the orchestrator", self.registration_socket)));
+        }
+        if !(self.sample_timeout_secs > 0. && self.sample_timeout_secs.is_finite()) {
+            return Err(config_error(format!("sample_timeout_secs ({}) must be positive", self.sample_timeout_secs)));
+        }
+        Ok(())
+    }
+
+    pub fn sample_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.sample_timeout_secs)
+    }
+
+    pub fn discovery_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.discovery_timeout_secs)
+    }
+
+    pub fn multicast_group(&self) -> SocketAddr {
+        SocketAddr::new(self.multicast_addr, self.multicast_port)
+    }
+
+    // Interface to send and receive IPv4 multicasts on: the one of bind_addr, or the system's
+    // choice when that's unspecified or loopback, which can't multicast.
+    pub fn multicast_interface_v4(&self) -> Ipv4Addr {
+        match self.bind_addr {
+            IpAddr::V4(addr) if !addr.is_loopback() => addr,
+            _ => Ipv4Addr::UNSPECIFIED
+        }
+    }
+
+    // Address the servers announce, see advertise_addr.
+    pub fn advertised_addr(&self) -> Result<IpAddr> {
+        if let Some(addr) = self.advertise_addr {
+            return Ok(addr);
+        }
+        if !self.bind_addr.is_unspecified() {
+            return Ok(self.bind_addr);
+        }
+        // connecting a UDP socket sends nothing, but picks the interface packets to the
+        // orchestrator would leave from
+        let orchestrator = match self.discovery {
+            DiscoveryMode::Registration => self.registration_socket,
+            _ => self.multicast_group()
+        };
+        let any: IpAddr = match orchestrator {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into()
+        };
+        let socket = UdpSocket::bind((any, 0))?;
+        socket.connect(orchestrator)?;
+        let addr = socket.local_addr()?.ip();
+        if addr.is_unspecified() {
+            return Err(config_error(format!("no interface to reach {} from, set advertise_addr", orchestrator)));
+        }
+        Ok(addr)
+    }
+}
+
+// Command line flags overriding the cluster configuration, shared by the binaries.
+#[derive(Args, Default)]
+pub struct ClusterArgs {
+    /// JSON file with cluster settings (defaults to $DRAY_CLUSTER_CONFIG, if set)
+    #[arg(long)]
+    pub cluster_config: Option<PathBuf>,
+    /// Interface object and ray servers listen on, e.g. 0.0.0.0 or :: for all of them
+    #[arg(long)]
+    pub bind_addr: Option<IpAddr>,
+    /// Address servers announce to the orchestrator (defaults to --bind-addr, or the address
+    /// multicasts go out from when that's unspecified)
+    #[arg(long)]
+    pub advertise_addr: Option<IpAddr>,
+    /// First port object and ray servers may listen on
+    #[arg(long)]
+    pub tcp_start_port: Option<u16>,
+    /// Last port object and ray servers may listen on
+    #[arg(long)]
+    pub tcp_end_port: Option<u16>,
+    /// How the orchestrator finds the servers
+    #[arg(long, value_enum)]
+    pub discovery: Option<DiscoveryMode>,
+    /// Seconds the orchestrator looks for more servers after finding the last one
+    #[arg(long)]
+    pub discovery_timeout_secs: Option<f64>,
+    /// Multicast group servers announce themselves to with multicast discovery
+    #[arg(long)]
+    pub multicast_addr: Option<IpAddr>,
+    #[arg(long)]
+    pub multicast_port: Option<u16>,
+    /// Object servers for static discovery, comma separated
+    #[arg(long, value_delimiter = ',')]
+    pub static_object_servers: Option<Vec<SocketAddr>>,
+    /// Ray servers for static discovery, comma separated
+    #[arg(long, value_delimiter = ',')]
+    pub static_ray_servers: Option<Vec<SocketAddr>>,
+    /// Address the orchestrator accepts servers on with registration discovery
+    #[arg(long)]
+    pub registration_socket: Option<SocketAddr>,
+    /// Address the orchestrator accepts clients on
+    #[arg(long)]
+    pub orchestrator_client_socket: Option<SocketAddr>,
+    /// Address the orchestrator accepts samples from ray servers on
+    #[arg(long)]
+    pub orchestrator_server_socket: Option<SocketAddr>,
+    #[arg(long)]
+    pub num_obj_servers: Option<i32>,
+    /// How many object servers host each region of the scene
+    #[arg(long)]
+    pub num_repeat_object: Option<i32>,
+    #[arg(long)]
+    pub num_ray_servers: Option<i32>,
+    /// Seconds to wait for the next sample before giving up on the rest of a pass
+    #[arg(long)]
+    pub sample_timeout_secs: Option<f64>,
+}
+
+impl ClusterArgs {
+    // The configuration from the config file, the environment and these flags, checked.
+    pub fn load(&self) -> Result<ClusterConfig> {
+        self.load_with_env(&process_env)
+    }
+
+    // Same, with the environment variables looked up with `env`.
+    pub fn load_with_env(&self, env: Env) -> Result<ClusterConfig> {
+        let path = self.cluster_config.clone().or_else(|| env("DRAY_CLUSTER_CONFIG").map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => ClusterConfig::from_file(path)?,
+            None => ClusterConfig::default()
+        };
+        config.apply_env_from(env)?;
+        self.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply(&self, config: &mut ClusterConfig) {
+        fn set<T: Copy>(flag: Option<T>, value: &mut T) {
+            if let Some(flag) = flag {
+                *value = flag;
+            }
+        }
+        set(self.bind_addr, &mut config.bind_addr);
+        if self.advertise_addr.is_some() {
+            config.advertise_addr = self.advertise_addr;
+        }
+        set(self.tcp_start_port, &mut config.tcp_start_port);
+        set(self.tcp_end_port, &mut config.tcp_end_port);
+        set(self.discovery, &mut config.discovery);
+        set(self.discovery_timeout_secs, &mut config.discovery_timeout_secs);
+        set(self.multicast_addr, &mut config.multicast_addr);
+        set(self.multicast_port, &mut config.multicast_port);
+        if let Some(servers) = &self.static_object_servers {
+            config.static_object_servers = servers.clone();
+        }
+        if let Some(servers) = &self.static_ray_servers {
+            config.static_ray_servers = servers.clone();
+        }
+        set(self.registration_socket, &mut config.registration_socket);
+        set(self.orchestrator_client_socket, &mut config.orchestrator_client_socket);
+        set(self.orchestrator_server_socket, &mut config.orchestrator_server_socket);
+        set(self.num_obj_servers, &mut config.num_obj_servers);
+        set(self.num_repeat_object, &mut config.num_repeat_object);
+        set(self.num_ray_servers, &mut config.num_ray_servers);
+        set(self.sample_timeout_secs, &mut config.sample_timeout_secs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use super::{ClusterArgs, ClusterConfig, DiscoveryMode};
+    use crate::distributed::distributed_common::{run_server, send_tcp_message, serve_listener};
+    use crate::distributed::messages::{RayServerMessage, RayServerMessageType, ServerDiscoveryMessage, ServerType};
+
+    #[test]
+    fn test_config_layers() {
+        let path = std::env::temp_dir().join(format!("dray_cluster_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{ "num_obj_servers": 4, "num_ray_servers": 2, "num_repeat_object": 2 }"#).unwrap();
+        let vars = HashMap::from([
+            ("DRAY_CLUSTER_CONFIG", path.to_str().unwrap()),
+            ("DRAY_NUM_REPEAT_OBJECT", "3"),
+            ("DRAY_NUM_RAY_SERVERS", "5")
+        ]);
+        let env = |name: &str| vars.get(name).map(|value| value.to_string());
+        let args = ClusterArgs { num_ray_servers: Some(3), ..Default::default() };
+        let config = args.load_with_env(&env).unwrap();
+        // from the file, the environment, the flags and the defaults
+        assert_eq!(config.num_obj_servers, 4);
+        assert_eq!(config.num_repeat_object, 3);
+        assert_eq!(config.num_ray_servers, 3);
+        assert_eq!(config.tcp_start_port, ClusterConfig::default().tcp_start_port);
+
+        // typos and bad values are reported instead of ignored
+        std::fs::write(&path, r#"{ "num_ray_server": 2 }"#).unwrap();
+        assert!(args.load_with_env(&env).is_err());
+        std::fs::remove_file(&path).unwrap();
+        let error = args.load_with_env(&|name| (name == "DRAY_TCP_END_PORT").then(|| "oops".to_string())).unwrap_err();
+        assert!(error.to_string().contains("DRAY_TCP_END_PORT=oops"), "{}", error);
+        let args = ClusterArgs { tcp_start_port: Some(9000), tcp_end_port: Some(9010), ..Default::default() };
+        let error = args.load_with_env(&|_| None).unwrap_err().to_string();
+        assert!(error.contains("can't fit 55 servers"), "{}", error);
+    }
+
+    #[test]
+    fn test_advertised_addr() {
+        let mut config = ClusterConfig { bind_addr: "127.0.0.2".parse().unwrap(), ..Default::default() };
+        assert_eq!(config.advertised_addr().unwrap(), config.bind_addr);
+        config.advertise_addr = Some("::1".parse().unwrap());
+        assert_eq!(config.advertised_addr().unwrap(), config.advertise_addr.unwrap());
+
+        config.advertise_addr = Some("::".parse().unwrap());
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_addr() {
+        // the orchestrator end of registration discovery, on the usual loopback address
+        let registration = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ClusterConfig {
+            bind_addr: "127.0.0.2".parse().unwrap(),
+            discovery: DiscoveryMode::Registration,
+            registration_socket: registration.local_addr().unwrap(),
+            ..Default::default()
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(serve_listener(registration, move |msg: &ServerDiscoveryMessage| {
+            let (tx, msg) = (tx.clone(), *msg);
+            async move {
+                let _ = tx.send(msg).await;
+                Ok(msg)
+            }
+        }));
+
+        let port = std::net::TcpListener::bind((config.bind_addr, 0)).unwrap().local_addr().unwrap().port();
+        tokio::spawn(run_server(port, false, config.clone()));
+        let announced = tokio::time::timeout(Duration::from_secs(10), rx.recv()).await.unwrap().unwrap();
+        assert!(announced.server_type == ServerType::Ray);
+        assert_eq!(announced.socket_addr, SocketAddr::new(config.bind_addr, port));
+
+        // the server is announced before it listens, so give it a moment
+        let registration = RayServerMessage::new_no_data(RayServerMessageType::Registration);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while send_tcp_message(&announced.socket_addr, &registration).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+}
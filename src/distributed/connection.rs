@@ -0,0 +1,220 @@
+//! Long-lived connections between the nodes of the cluster.
+//!
+//! Every message travels in a frame (see distributed::codec) with a request id, and a response
+//! carries the id of its request. Requests on a connection don't wait for each other, so a
+//! connection can have any number of them in flight, and responses come back in whatever order
+//! they're ready. Each node keeps a few connections open to every peer it talks to, see
+//! ConnectionPool.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use crate::distributed::codec::{read_frame, write_frame, Frame};
+
+// Connections opened to each peer, which requests take turns on
+pub const CONNECTIONS_PER_PEER: usize = 4;
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// Long enough for a response to wait behind thousands of others
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Writes the frames from `frames` until all of their senders are gone, flushing whenever there
+// are no more waiting, so frames sent together go out together.
+pub async fn write_frames<W: AsyncWrite + Unpin>(writer: W, mut frames: mpsc::Receiver<Frame>) -> Result<()> {
+    let mut writer = BufWriter::new(writer);
+    while let Some(frame) = frames.recv().await {
+        write_frame(&mut writer, &frame).await?;
+        while let Ok(frame) = frames.try_recv() {
+            write_frame(&mut writer, &frame).await?;
+        }
+        writer.flush().await?;
+    }
+    writer.shutdown().await
+}
+
+struct Pending {
+    // set once the connection is gone, after which no more requests are taken
+    closed: bool,
+    responses: HashMap<u64, oneshot::Sender<Frame>>
+}
+
+// A connection to a peer carrying any number of requests at a time.
+pub struct Connection {
+    frames: mpsc::Sender<Frame>,
+    pending: Arc<Mutex<Pending>>,
+    next_id: AtomicU64
+}
+
+impl Connection {
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await??;
+        stream.set_nodelay(true)?;
+        let (mut read, write) = stream.into_split();
+
+        let (frames, rx) = mpsc::channel(1024);
+        tokio::spawn(write_frames(write, rx));
+
+        let pending = Arc::new(Mutex::new(Pending { closed: false, responses: HashMap::new() }));
+        let reader_pending = Arc::clone(&pending);
+        tokio::spawn(async move {
+            loop {
+                match read_frame(&mut read).await {
+                    Ok(Some(frame)) => {
+                        let response = reader_pending.lock().unwrap().responses.remove(&frame.id);
+                        if let Some(response) = response {
+                            let _ = response.send(frame);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("Connection to {} failed: {}", addr, e);
+                        break;
+                    }
+                }
+            }
+            // fails the requests still waiting, by dropping their senders
+            let mut pending = reader_pending.lock().unwrap();
+            pending.closed = true;
+            pending.responses.clear();
+        });
+
+        Ok(Connection { frames, pending, next_id: AtomicU64::new(0) })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.frames.is_closed() || self.pending.lock().unwrap().closed
+    }
+
+    // Sends an encoded message and waits for the encoded response.
+    pub async fn request(&self, payload: Vec<u8>) -> Result<Vec<u8>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "connection closed"));
+            }
+            pending.responses.insert(id, tx);
+        }
+        if self.frames.send(Frame::message(id, payload)).await.is_err() {
+            self.pending.lock().unwrap().responses.remove(&id);
+            return Err(Error::new(ErrorKind::ConnectionAborted, "connection closed"));
+        }
+        match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(Ok(response)) => Ok(response.into_result()?),
+            Ok(Err(_)) => Err(Error::new(ErrorKind::ConnectionAborted, "connection closed before the response")),
+            Err(_) => {
+                self.pending.lock().unwrap().responses.remove(&id);
+                Err(Error::new(ErrorKind::TimedOut, "no response in time"))
+            }
+        }
+    }
+}
+
+// The open connections to a peer
+type PeerConnections = Arc<tokio::sync::Mutex<Vec<Arc<Connection>>>>;
+
+// Up to `per_peer` connections to every peer, opened as requests need them and replaced once
+// they close.
+pub struct ConnectionPool {
+    per_peer: usize,
+    peers: Mutex<HashMap<SocketAddr, PeerConnections>>,
+    next: AtomicUsize
+}
+
+impl ConnectionPool {
+    pub fn new(per_peer: usize) -> Self {
+        ConnectionPool { per_peer: per_peer.max(1), peers: Mutex::new(HashMap::new()), next: AtomicUsize::new(0) }
+    }
+
+    pub async fn connection(&self, addr: &SocketAddr) -> Result<Arc<Connection>> {
+        let peer = Arc::clone(self.peers.lock().unwrap().entry(*addr).or_default());
+        let mut connections = peer.lock().await;
+        connections.retain(|connection| !connection.is_closed());
+        if connections.len() < self.per_peer {
+            let connection = Arc::new(Connection::connect(*addr).await?);
+            connections.push(Arc::clone(&connection));
+            return Ok(connection);
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % connections.len();
+        Ok(Arc::clone(&connections[index]))
+    }
+
+    pub async fn request(&self, addr: &SocketAddr, payload: Vec<u8>) -> Result<Vec<u8>> {
+        self.connection(addr).await?.request(payload).await
+    }
+}
+
+// The pool of this process, which all of its servers share.
+pub fn pool() -> &'static ConnectionPool {
+    static POOL: OnceLock<ConnectionPool> = OnceLock::new();
+    POOL.get_or_init(|| ConnectionPool::new(CONNECTIONS_PER_PEER))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+    use super::ConnectionPool;
+    use crate::distributed::codec::{decode, encode, CodecError};
+    use crate::distributed::distributed_common::serve_listener;
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // request 0 is held until the test releases it
+        let (started, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        tokio::spawn(serve_listener(listener, {
+            let (started, release) = (Arc::clone(&started), Arc::clone(&release));
+            move |n: &u64| {
+                let n = *n;
+                let (started, release) = (Arc::clone(&started), Arc::clone(&release));
+                async move {
+                    if n == 0 {
+                        started.notify_one();
+                        release.notified().await;
+                    }
+                    if n == u64::MAX {
+                        return Err(std::io::Error::other("too large to double"));
+                    }
+                    Ok(n * 2)
+                }
+            }
+        }));
+
+        // one connection, so every request shares it
+        let pool = Arc::new(ConnectionPool::new(1));
+        let request = |n: u64| {
+            let pool = Arc::clone(&pool);
+            tokio::spawn(async move {
+                let response = pool.request(&addr, encode(&n).unwrap()).await.unwrap();
+                decode::<u64>(&response).unwrap()
+            })
+        };
+        let held = request(0);
+        started.notified().await;
+        // the requests after it are answered while it's still waiting
+        let requests: Vec<_> = (1..100u64).map(request).collect();
+        for (n, request) in (1..100u64).zip(requests) {
+            assert_eq!(request.await.unwrap(), n * 2);
+        }
+        assert!(!held.is_finished());
+        release.notify_one();
+        assert_eq!(held.await.unwrap(), 0);
+        assert_eq!(pool.peers.lock().unwrap()[&addr].try_lock().unwrap().len(), 1);
+
+        // a request the server can't decode or fails to handle fails with the server's error, and
+        // the connection carries on
+        let error = pool.request(&addr, vec![0xff; 3]).await.unwrap_err();
+        assert!(matches!(error.get_ref().and_then(|e| e.downcast_ref::<CodecError>()), Some(CodecError::Remote(_))), "{}", error);
+        let error = pool.request(&addr, encode(&u64::MAX).unwrap()).await.unwrap_err();
+        assert!(matches!(error.get_ref().and_then(|e| e.downcast_ref::<CodecError>()), Some(CodecError::Remote(msg)) if msg == "too large to double"), "{}", error);
+        assert_eq!(decode::<u64>(&pool.request(&addr, encode(&1u64).unwrap()).await.unwrap()).unwrap(), 2);
+    }
+}
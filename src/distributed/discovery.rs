@@ -0,0 +1,262 @@
+//! How the orchestrator finds the object and ray servers.
+//!
+//! Servers announce themselves until an orchestrator claims them (by deregistering them), and the
+//! orchestrator collects the servers it hears of until no new one has turned up for the discovery
+//! timeout. Which way they meet is picked by the `discovery` setting of the cluster configuration:
+//! multicast on the local network, a fixed list of servers, or servers registering with the
+//! orchestrator over TCP where multicast doesn't get through (e.g. container networks).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::io::{Error, Result};
+use std::time::Duration;
+use futures_util::future::BoxFuture;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::time::Instant;
+use crate::distributed::codec::{decode, encode};
+use crate::distributed::config::{ClusterConfig, DiscoveryMode};
+use crate::distributed::distributed_common::{send_tcp_message, serve_listener};
+use crate::distributed::messages::{ServerDiscoveryMessage, ServerType};
+
+// How often unclaimed servers announce themselves
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(3);
+
+pub trait Discovery: Send + Sync {
+    // Announces a server until it's claimed (`should_stop`), then waits to be released again.
+    // Runs for as long as the server does.
+    fn announce(&self, server: ServerDiscoveryMessage, should_stop: Arc<AtomicBool>) -> BoxFuture<'_, Result<()>>;
+
+    // Collects the servers announcing themselves, without duplicates.
+    fn discover(&self) -> BoxFuture<'_, Result<Vec<ServerDiscoveryMessage>>>;
+}
+
+pub fn new_discovery(config: &ClusterConfig) -> Box<dyn Discovery> {
+    match config.discovery {
+        DiscoveryMode::Multicast => Box::new(MulticastDiscovery {
+            group: config.multicast_group(),
+            interface_v4: config.multicast_interface_v4(),
+            timeout: config.discovery_timeout()
+        }),
+        DiscoveryMode::Static => Box::new(StaticDiscovery::new(&config.static_object_servers, &config.static_ray_servers)),
+        DiscoveryMode::Registration => Box::new(RegistrationDiscovery {
+            socket: config.registration_socket,
+            timeout: config.discovery_timeout()
+        })
+    }
+}
+
+// Calls `announce` every ANNOUNCE_INTERVAL while the server is unclaimed.
+async fn announce_periodically<F, U>(should_stop: Arc<AtomicBool>, announce: F) -> Result<()>
+where
+    F: Fn() -> U,
+    U: Future<Output = Result<()>>,
+{
+    loop {
+        if !should_stop.load(Ordering::SeqCst) {
+            announce().await?;
+        }
+        tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+    }
+}
+
+// Adds `server` to `servers` unless it's there already, returning whether it's new.
+fn add_server(servers: &mut Vec<ServerDiscoveryMessage>, server: ServerDiscoveryMessage) -> bool {
+    let known = servers.iter().any(|s| s.server_type == server.server_type && s.socket_addr == server.socket_addr);
+    if !known {
+        servers.push(server);
+    }
+    !known
+}
+
+// Servers multicast their address to a group the orchestrator listens to.
+pub struct MulticastDiscovery {
+    pub group: SocketAddr,
+    // interface to multicast on for IPv4 groups, see ClusterConfig::multicast_interface_v4
+    pub interface_v4: Ipv4Addr,
+    pub timeout: Duration
+}
+
+impl Discovery for MulticastDiscovery {
+    fn announce(&self, server: ServerDiscoveryMessage, should_stop: Arc<AtomicBool>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            // Sending from the address of an interface sends the multicasts out of that interface
+            let socket = match self.group {
+                SocketAddr::V4(_) => {
+                    let socket = UdpSocket::bind((self.interface_v4, 0)).await?;
+                    socket.set_multicast_loop_v4(true)?; // Since server and client may be on the same host
+                    socket
+                }
+                SocketAddr::V6(_) => {
+                    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?;
+                    socket.set_multicast_loop_v6(true)?;
+                    socket
+                }
+            };
+
+            println!("Multicasting {} to {}", server.socket_addr, self.group);
+
+            let message_bytes: Vec<u8> = encode(&server)?;
+            let socket = &socket;
+            let message_bytes = &message_bytes;
+            announce_periodically(should_stop, || async move {
+                socket.send_to(message_bytes, self.group).await.map(|_| ())
+            }).await
+        })
+    }
+
+    fn discover(&self) -> BoxFuture<'_, Result<Vec<ServerDiscoveryMessage>>> {
+        Box::pin(async move {
+            // Bind to the socket that will receive the multicast packets
+            let socket = UdpSocket::bind(self.group).await?;
+
+            // Join the multicast group on the interface of the servers
+            match self.group.ip() {
+                IpAddr::V4(group) => socket.join_multicast_v4(group, self.interface_v4)?,
+                IpAddr::V6(group) => socket.join_multicast_v6(&group, 0)?
+            }
+
+            println!("Joined multicast group and listening for messages...");
+
+            let mut servers = Vec::new();
+            let mut buf = [0; 256];
+            let mut deadline = Instant::now() + self.timeout;
+            while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                let (num_bytes, src_addr) = received?;
+                match decode(&buf[..num_bytes]) {
+                    Ok(server) => if add_server(&mut servers, server) {
+                        deadline = Instant::now() + self.timeout;
+                    }
+                    Err(e) => eprintln!("Ignoring bad announcement from {}: {}", src_addr, e)
+                }
+            }
+            Ok(servers)
+        })
+    }
+}
+
+// A fixed list of servers, e.g. from the config file. Servers don't announce anything.
+pub struct StaticDiscovery {
+    pub servers: Vec<ServerDiscoveryMessage>
+}
+
+impl StaticDiscovery {
+    pub fn new(object_servers: &[SocketAddr], ray_servers: &[SocketAddr]) -> Self {
+        let server = |server_type| move |socket_addr: &SocketAddr| ServerDiscoveryMessage { server_type, socket_addr: *socket_addr };
+        let mut servers = Vec::new();
+        for s in object_servers.iter().map(server(ServerType::Object)).chain(ray_servers.iter().map(server(ServerType::Ray))) {
+            add_server(&mut servers, s);
+        }
+        StaticDiscovery { servers }
+    }
+}
+
+impl Discovery for StaticDiscovery {
+    fn announce(&self, _server: ServerDiscoveryMessage, _should_stop: Arc<AtomicBool>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(std::future::pending())
+    }
+
+    fn discover(&self) -> BoxFuture<'_, Result<Vec<ServerDiscoveryMessage>>> {
+        Box::pin(async move { Ok(self.servers.clone()) })
+    }
+}
+
+// Servers send their address to a TCP endpoint of the orchestrator.
+pub struct RegistrationDiscovery {
+    pub socket: SocketAddr,
+    pub timeout: Duration
+}
+
+impl Discovery for RegistrationDiscovery {
+    fn announce(&self, server: ServerDiscoveryMessage, should_stop: Arc<AtomicBool>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            println!("Registering {} with {}", server.socket_addr, self.socket);
+            announce_periodically(should_stop, || async move {
+                // the orchestrator only listens while it looks for servers
+                let _ = send_tcp_message(&self.socket, &server).await;
+                Ok(())
+            }).await
+        })
+    }
+
+    fn discover(&self) -> BoxFuture<'_, Result<Vec<ServerDiscoveryMessage>>> {
+        Box::pin(async move {
+            let listener = TcpListener::bind(self.socket).await?;
+            self.discover_on(listener).await
+        })
+    }
+}
+
+impl RegistrationDiscovery {
+    // Same as `discover`, with the endpoint on a listener that's already bound.
+    async fn discover_on(&self, listener: TcpListener) -> Result<Vec<ServerDiscoveryMessage>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<ServerDiscoveryMessage>(128);
+        let endpoint = tokio::spawn(serve_listener(
+            listener,
+            move |msg: &ServerDiscoveryMessage| {
+                let tx_clone = tx.clone();
+                let msg = *msg;
+                async move {
+                    let _ = tx_clone.send(msg).await;
+                    Ok(msg)
+                }
+            }
+        ));
+
+        println!("Listening for registrations on {}...", self.socket);
+
+        let mut servers = Vec::new();
+        let mut deadline = Instant::now() + self.timeout;
+        while let Ok(received) = tokio::time::timeout_at(deadline, rx.recv()).await {
+            match received {
+                Some(server) => if add_server(&mut servers, server) {
+                    deadline = Instant::now() + self.timeout;
+                }
+                // the endpoint stopped
+                None => {
+                    return Err(match endpoint.await {
+                        Ok(Err(e)) => e,
+                        _ => Error::other("registration endpoint stopped")
+                    });
+                }
+            }
+        }
+        endpoint.abort();
+        Ok(servers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use super::{Discovery, RegistrationDiscovery};
+    use crate::distributed::messages::{ServerDiscoveryMessage, ServerType};
+
+    #[tokio::test]
+    async fn test_registration() {
+        // bound before the servers announce themselves, so none of them find it closed
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = listener.local_addr().unwrap();
+        let discovery = Arc::new(RegistrationDiscovery { socket, timeout: Duration::from_secs(2) });
+        let discovering = tokio::spawn({
+            let discovery = Arc::clone(&discovery);
+            async move { discovery.discover_on(listener).await }
+        });
+
+        for (server_type, port) in [(ServerType::Object, 8000), (ServerType::Ray, 8001), (ServerType::Object, 8000)] {
+            let discovery = Arc::clone(&discovery);
+            let server = ServerDiscoveryMessage { server_type, socket_addr: ([127, 0, 0, 1], port).into() };
+            tokio::spawn(async move { discovery.announce(server, Arc::new(AtomicBool::new(false))).await });
+        }
+
+        let servers = discovering.await.unwrap().unwrap();
+        let mut found: Vec<(bool, u16)> = servers.iter()
+            .map(|s| (s.server_type == ServerType::Object, s.socket_addr.port()))
+            .collect();
+        found.sort();
+        assert_eq!(found, vec![(false, 8001), (true, 8000)]);
+    }
+}
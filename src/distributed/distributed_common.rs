@@ -1,54 +1,108 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
-use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
-use std::time::Duration;
-use std::io::{Result};
+use std::net::SocketAddr;
+use std::sync::{atomic::AtomicBool, Arc};
+use std::io::Result;
 use futures_util::stream::SplitSink;
 use futures_util::sink::SinkExt;
 use serde::de::DeserializeOwned;
 use serde::{Serialize};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
+use tokio::task::JoinSet;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
-use crate::distributed::config::{MULTICAST_ADDR, MULTICAST_PORT};
-use crate::distributed::messages::{ObjectServerMessage, RayServerMessage, ServerDiscoveryMessage, ServerType};
+use crate::distributed::config::ClusterConfig;
+use crate::distributed::codec::{decode, encode, read_frame, Frame};
+use crate::distributed::connection::{pool, write_frames};
+use crate::distributed::discovery::new_discovery;
+use crate::distributed::messages::{ObjectServerMessage, ObjectServerMessageType, RayServerMessage, ServerDiscoveryMessage, ServerType};
 use crate::distributed::ray_server::RayServer;
 use crate::distributed::{object_server::ObjectServer};
 
-pub async fn run_async_server<M, F, U>(socket_addr: SocketAddrV4, handler: F) -> Result<()>
+// Serves the requests of every connection to `socket_addr` with `handler`, see
+// distributed::connection. Requests are handled concurrently, also those on one connection. A
+// request the handler fails gets the error back instead of a response.
+pub async fn run_async_server<M, F, U>(socket_addr: SocketAddr, handler: F) -> Result<()>
 where
-    M: Serialize + DeserializeOwned,
-    F: Fn(&M) -> U,
-    U: Future<Output = M>,
+    M: Serialize + DeserializeOwned + Send + 'static,
+    F: Fn(&M) -> U + Send + Sync + 'static,
+    U: Future<Output = Result<M>> + Send + 'static,
 {
     let listener  = TcpListener::bind(socket_addr).await?;
-    while let Ok((mut stream, _)) = listener.accept().await {
-        // Read the length
-        let mut len_bytes = [0; 4];
-        stream.read_exact(&mut len_bytes).await?;
-        let message_len = u32::from_le_bytes(len_bytes) as usize;
-        // Read the message
-        let mut buf = vec![0; message_len];
-        stream.read_exact(&mut buf).await?;
-        // Convert the bytes into a decoded server message
-        let (msg, _num_bytes_decoded): (M, usize) = bincode::serde::decode_from_slice(
-            &buf, bincode::config::standard()).unwrap();
-        let new_msg = handler(&msg).await;
-        // Writes new message (msg was modified by self.handle_msg)
-        let message_bytes: Vec<u8> = bincode::serde::encode_to_vec(&new_msg, 
-            bincode::config::standard()).unwrap();
-        stream.write_all(message_bytes.as_slice()).await?;
+    serve_listener(listener, handler).await
+}
+
+// Same, on a listener that's already bound.
+pub async fn serve_listener<M, F, U>(listener: TcpListener, handler: F) -> Result<()>
+where
+    M: Serialize + DeserializeOwned + Send + 'static,
+    F: Fn(&M) -> U + Send + Sync + 'static,
+    U: Future<Output = Result<M>> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    // dropped along with the server, closing the connections
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let Ok((stream, peer_addr)) = accepted else {
+                    break;
+                };
+                let handler = Arc::clone(&handler);
+                connections.spawn(async move {
+                    if let Err(e) = serve_connection(stream, handler).await {
+                        eprintln!("Connection from {} failed: {}", peer_addr, e);
+                    }
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
     }
     Ok(())
 }
 
+async fn serve_connection<M, F, U>(stream: TcpStream, handler: Arc<F>) -> Result<()>
+where
+    M: Serialize + DeserializeOwned + Send + 'static,
+    F: Fn(&M) -> U + Send + Sync + 'static,
+    U: Future<Output = Result<M>> + Send + 'static,
+{
+    stream.set_nodelay(true)?;
+    let (mut read, write) = stream.into_split();
+    let (responses, rx) = tokio::sync::mpsc::channel(1024);
+    let writer = tokio::spawn(write_frames(write, rx));
+
+    while let Some(frame) = read_frame(&mut read).await? {
+        // Convert the bytes into a decoded server message, or tell the sender why not
+        let id = frame.id;
+        let msg: M = match frame.into_result().and_then(|payload| decode(&payload)) {
+            Ok(msg) => msg,
+            Err(e) => {
+                let _ = responses.send(Frame::error(id, &e)).await;
+                continue;
+            }
+        };
+        let response = handler(&msg);
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            let frame = match response.await.and_then(|new_msg| Ok(encode(&new_msg)?)) {
+                Ok(message_bytes) => Frame::message(id, message_bytes),
+                Err(e) => Frame::error(id, &e)
+            };
+            let _ = responses.send(frame).await;
+        });
+    }
+    // the writer finishes once the last response is out
+    drop(responses);
+    writer.await?
+}
+
 pub async fn send_websocket_message<T: Serialize, S: AsyncRead + AsyncWrite + Unpin>(
     write: &mut SplitSink<WebSocketStream<S>, Message>,
     message: &T,
 ) -> Result<()> { 
     // Encode data
-    let message_bytes: Vec<u8> = bincode::serde::encode_to_vec(&message, bincode::config::standard()).unwrap();
+    let message_bytes: Vec<u8> = encode(message)?;
 
     // Write all bytes to the stream
     write
@@ -59,76 +113,30 @@ pub async fn send_websocket_message<T: Serialize, S: AsyncRead + AsyncWrite + Un
     Ok(())
 }
 
-pub async fn send_tcp_message<T: Serialize>(socket_addr: &SocketAddrV4, message: &T) -> Result<Vec<u8>> {    
-    // 1. Establish the connection
-    let timeout_duration = Duration::from_secs(1);
-    let mut stream = tokio::time::timeout(timeout_duration, TcpStream::connect(socket_addr)).await??;
-
-    // 2. Write data to the server
-    let message_bytes: Vec<u8> = bincode::serde::encode_to_vec(&message, 
-        bincode::config::standard()).unwrap();
-    
-    // Write all bytes to the stream
-    tokio::time::timeout(timeout_duration,stream.write_all(&(message_bytes.len() as u32).to_le_bytes())).await??;
-    tokio::time::timeout(timeout_duration,stream.write_all(message_bytes.as_slice())).await??;
-    
-    // 3. Read the server's response (e.g., an echo)
-    let mut buffer = [0; 128]; // Buffer to hold the response
-    
-    // Read the response into the buffer
-    let bytes_read = tokio::time::timeout(timeout_duration,stream.read(&mut buffer)).await??;
-    
-    // Convert the received bytes into a readable string
-    let response = buffer[..bytes_read].to_vec();
-
-    Ok(response)
+// Sends `message` over one of the pooled connections to `socket_addr` and returns the response,
+// which is a message of the same type. Errors carry a CodecError when the message or the response
+// couldn't be encoded or decoded, on either side.
+pub async fn send_tcp_message<T: Serialize + DeserializeOwned>(socket_addr: &SocketAddr, message: &T) -> Result<T> {
+    let response = pool().request(socket_addr, encode(message)?).await?;
+    Ok(decode(&response)?)
 }
 
-// This function multicasts the server's port number
-async fn multicast_port_announcer(
-    port_to_announce: u16,
-    server_type: ServerType, 
-    should_stop: Arc<AtomicBool>
-) -> Result<()> {
-    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
-    socket.set_multicast_loop_v4(true)?; // Since server and client are both on localhost
-    
-    let multicast_addr = SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT);
-
-    println!("Multicasting port {} to {}", port_to_announce, multicast_addr);
-
-    let message = ServerDiscoveryMessage{
-        server_type: server_type,
-        socket_addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port_to_announce)
-    };
-    
-    let message_bytes: Vec<u8> = bincode::serde::encode_to_vec(&message, 
-        bincode::config::standard()).unwrap();
-
-    loop {
-        if should_stop.load(Ordering::SeqCst) {
-            tokio::time::sleep(Duration::from_secs(3)).await; // Check every 3 seconds
-        } else {
-            socket.send_to(&message_bytes, multicast_addr).await?;
-            tokio::time::sleep(Duration::from_secs(3)).await; // Announce every 3 seconds
-        }
-    }
-}
-
-pub async fn run_server(port: u16, is_object_server: bool) {
+pub async fn run_server(port: u16, is_object_server: bool, config: ClusterConfig) -> Result<()> {
     let should_stop = Arc::new(AtomicBool::new(false));
-    let multicast_stop_flag = Arc::clone(&should_stop);
+    let announce_stop_flag = Arc::clone(&should_stop);
 
-    // Start the multicast announcer in a separate thread.
-    let multicast_handle = tokio::spawn( 
-        multicast_port_announcer(port, 
-            if is_object_server {ServerType::Object} else {ServerType::Ray},
-            multicast_stop_flag
-        )
-    );
+    // Start announcing the server in a separate thread.
+    let announcement = ServerDiscoveryMessage {
+        server_type: if is_object_server {ServerType::Object} else {ServerType::Ray},
+        socket_addr: SocketAddr::new(config.advertised_addr()?, port)
+    };
+    let discovery = new_discovery(&config);
+    let announce_handle = tokio::spawn(async move {
+        discovery.announce(announcement, announce_stop_flag).await
+    });
 
     // Start the TCP servers in a separate thread.
-    let socket_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+    let socket_addr = SocketAddr::new(config.bind_addr, port);
     if is_object_server {
         let server = Arc::new(Mutex::new(ObjectServer::new(Arc::clone(&should_stop))));
         tokio::spawn(
@@ -138,15 +146,22 @@ pub async fn run_server(port: u16, is_object_server: bool) {
                     let server_clone = server.clone();
                     let cloned_msg = msg.clone(); 
                     async move {
-                        let mut server_locked = server_clone.lock().await;
-                        let new_msg = server_locked.handle_msg(&cloned_msg).await;
-                        new_msg
+                        match cloned_msg.message_type {
+                            // Hit checks only read the scene, so they don't wait for each other.
+                            // They run on the blocking pool, since traversing the hierarchy and
+                            // scattering would hold up the connections on the runtime threads.
+                            ObjectServerMessageType::CheckHit | ObjectServerMessageType::CheckShadow => {
+                                let scene = server_clone.lock().await.scene();
+                                tokio::task::spawn_blocking(move || scene.check(&cloned_msg)).await?
+                            }
+                            _ => server_clone.lock().await.handle_msg(&cloned_msg).await
+                        }
                     }
                 }
             )
         );
     } else {
-        let server = Arc::new(Mutex::new(RayServer::new(Arc::clone(&should_stop))));
+        let server = Arc::new(Mutex::new(RayServer::new(Arc::clone(&should_stop), config.orchestrator_server_socket)));
         tokio::spawn(
             run_async_server(
                 socket_addr,
@@ -155,8 +170,7 @@ pub async fn run_server(port: u16, is_object_server: bool) {
                     let cloned_msg = msg.clone(); 
                     async move {
                         let mut server_locked = server_clone.lock().await;
-                        let new_msg = server_locked.handle_msg(&cloned_msg).await;
-                        new_msg
+                        server_locked.handle_msg(&cloned_msg).await
                     }
                 }
             )
@@ -164,5 +178,5 @@ pub async fn run_server(port: u16, is_object_server: bool) {
     }
 
     // Wait for both threads to finish (which they won't, as they run infinitely).
-    let _ = multicast_handle.await;
+    announce_handle.await?
 }
\ No newline at end of file
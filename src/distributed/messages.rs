@@ -1,12 +1,11 @@
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
-use std::net::{SocketAddrV4};
+use std::net::{SocketAddr};
 use std::fmt::{Display, Formatter, Result};
 use crate::raytracer::bounding_box::BoundingBox;
-use crate::raytracer::camera::{Camera, PixelIndexEntry, RayColorEntry, RayColorStatus};
+use crate::raytracer::camera::{Aov, Camera, PixelIndexEntry, RayColorEntry, RayColorStatus};
 use crate::raytracer::hittable::{Hittable};
 use crate::raytracer::{prelude::*};
-use crate::raytracer::sphere::Sphere;
 
 // since variant_count is only on nightly
 pub const NUM_SERVER_TYPES: usize = 2;
@@ -28,7 +27,7 @@ impl Display for ServerType {
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct ServerDiscoveryMessage {
     pub server_type: ServerType,
-    pub socket_addr: SocketAddrV4
+    pub socket_addr: SocketAddr
 }
 
 impl Display for ServerDiscoveryMessage {
@@ -38,21 +37,38 @@ impl Display for ServerDiscoveryMessage {
     }
 }
 
+// Field `name` of a message, or an error to send back if the sender left it out.
+pub fn required<'a, T>(field: &'a Option<T>, name: &str) -> std::io::Result<&'a T> {
+    field.as_ref().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("message without {}", name)))
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub enum ObjectServerMessageType {
     Deregistration,
     Registration,
     AddObject,
+    AddSharedObject,
     PrintObjects,
+    BuildAccelerator,
+    SetCamera,
     CheckHit,
+    CheckShadow,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ObjectServerMessage {
     pub message_type: ObjectServerMessageType,
     pub object_add: Option<Arc<dyn Hittable>>,
+    pub shared_id: Option<u64>,
     pub ray_entry: Option<RayColorEntry>,
+    // part of the ray to check for hits
+    pub ray_interval: Option<Interval>,
     pub ray_status: Option<RayColorStatus>,
+    // for the lights and background that light samples are taken from
+    pub camera: Option<Camera>,
+    pub shadow_ray: Option<Ray>,
+    // light emitted by the closest object the shadow ray hits, None if it hits nothing
+    pub shadow_color: Option<Color>,
 }
 
 impl ObjectServerMessage {
@@ -60,8 +76,13 @@ impl ObjectServerMessage {
         ObjectServerMessage {
             message_type: message_type,
             object_add: None,
+            shared_id: None,
             ray_entry: None,
-            ray_status: None
+            ray_interval: None,
+            ray_status: None,
+            camera: None,
+            shadow_ray: None,
+            shadow_color: None
         }
     }
 
@@ -69,17 +90,41 @@ impl ObjectServerMessage {
         ObjectServerMessage {
             message_type: ObjectServerMessageType::AddObject,
             object_add: Some(object),
+            shared_id: None,
             ray_entry: None,
-            ray_status: None
+            ray_interval: None,
+            ray_status: None,
+            camera: None,
+            shadow_ray: None,
+            shadow_color: None
         }
     }
 
-    pub fn new_ray_check(ray_entry: RayColorEntry) -> Self {
+    pub fn new_shared_object_add(id: u64, object: Arc<dyn Hittable>) -> Self {
+        ObjectServerMessage {
+            message_type: ObjectServerMessageType::AddSharedObject,
+            object_add: Some(object),
+            shared_id: Some(id),
+            ray_entry: None,
+            ray_interval: None,
+            ray_status: None,
+            camera: None,
+            shadow_ray: None,
+            shadow_color: None
+        }
+    }
+
+    pub fn new_ray_check(ray_entry: RayColorEntry, ray_interval: Interval) -> Self {
         ObjectServerMessage {
             message_type: ObjectServerMessageType::CheckHit,
             object_add: None,
+            shared_id: None,
             ray_entry: Some(ray_entry),
-            ray_status: None
+            ray_interval: Some(ray_interval),
+            ray_status: None,
+            camera: None,
+            shadow_ray: None,
+            shadow_color: None
         }
     }
 
@@ -87,8 +132,55 @@ impl ObjectServerMessage {
         ObjectServerMessage {
             message_type: ObjectServerMessageType::CheckHit,
             object_add: None,
+            shared_id: None,
             ray_entry: Some(ray_entry),
-            ray_status: Some(ray_status)
+            ray_interval: None,
+            ray_status: Some(ray_status),
+            camera: None,
+            shadow_ray: None,
+            shadow_color: None
+        }
+    }
+
+    pub fn new_camera(camera: &Camera) -> Self {
+        ObjectServerMessage {
+            message_type: ObjectServerMessageType::SetCamera,
+            object_add: None,
+            shared_id: None,
+            ray_entry: None,
+            ray_interval: None,
+            ray_status: None,
+            camera: Some(camera.clone()),
+            shadow_ray: None,
+            shadow_color: None
+        }
+    }
+
+    pub fn new_shadow_check(shadow_ray: Ray, ray_interval: Interval) -> Self {
+        ObjectServerMessage {
+            message_type: ObjectServerMessageType::CheckShadow,
+            object_add: None,
+            shared_id: None,
+            ray_entry: None,
+            ray_interval: Some(ray_interval),
+            ray_status: None,
+            camera: None,
+            shadow_ray: Some(shadow_ray),
+            shadow_color: None
+        }
+    }
+
+    pub fn new_shadow_check_response(shadow_color: Option<Color>) -> Self {
+        ObjectServerMessage {
+            message_type: ObjectServerMessageType::CheckShadow,
+            object_add: None,
+            shared_id: None,
+            ray_entry: None,
+            ray_interval: None,
+            ray_status: None,
+            camera: None,
+            shadow_ray: None,
+            shadow_color
         }
     }
 }
@@ -106,7 +198,7 @@ pub enum RayServerMessageType {
 pub struct RayServerMessage {
     pub message_type: RayServerMessageType,
     pub object_bbs: Option<Vec<Arc<BoundingBox>>>,
-    pub object_servers: Option<HashMap<usize, Vec<SocketAddrV4>>>,
+    pub object_servers: Option<HashMap<usize, Vec<SocketAddr>>>,
     pub camera: Option<Camera>,
     pub pixel_index: Option<PixelIndexEntry>,
     pub ray: Option<Ray>,
@@ -126,7 +218,7 @@ impl RayServerMessage {
 
     pub fn new_share_params(
         object_bbs: &Vec<Arc<BoundingBox>>,
-        server_directory: &HashMap<usize, Vec<SocketAddrV4>>,
+        server_directory: &HashMap<usize, Vec<SocketAddr>>,
         camera: &Camera
     ) -> Self {
         RayServerMessage {
@@ -141,7 +233,7 @@ impl RayServerMessage {
 
     pub fn new_share_ray(
         pixel_index: &PixelIndexEntry,
-        ray: &Ray,
+        ray: Option<&Ray>,
     ) -> Self {
         RayServerMessage {
             message_type: RayServerMessageType::SendPixel,
@@ -149,7 +241,7 @@ impl RayServerMessage {
             object_servers: None,
             camera: None,
             pixel_index: Some(pixel_index.clone()),
-            ray: Some(ray.clone()),
+            ray: ray.cloned(),
         }
     }
 }
@@ -157,17 +249,25 @@ impl RayServerMessage {
 #[derive(Serialize, Deserialize, Clone)]
 pub enum OrchestratorServerMessageType {
     SendObject,
+    SendSharedObject,
     BeginRaytracing,
     ReceivePixel,
+    // sent to the client once every pixel of a sample pass has come in
+    EndPass,
+    // sent to the client once no more pixels are coming
+    EndRaytracing,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct OrchestratorServerMessage {
     pub message_type: OrchestratorServerMessageType,
-    pub object: Option<Arc<Sphere>>,
+    pub object: Option<Arc<dyn Hittable>>,
+    pub shared_id: Option<u64>,
     pub camera: Option<Camera>,
     pub pixel_index: Option<PixelIndexEntry>,
-    pub pixel_color: Option<Color>
+    pub pixel_color: Option<Color>,
+    // None if the camera ray hit nothing
+    pub pixel_aov: Option<Aov>
 }
 
 impl OrchestratorServerMessage {
@@ -175,29 +275,71 @@ impl OrchestratorServerMessage {
         OrchestratorServerMessage {
             message_type: OrchestratorServerMessageType::BeginRaytracing,
             object: None,
+            shared_id: None,
             camera: Some(camera.clone()),
             pixel_index: None,
-            pixel_color: None
+            pixel_color: None,
+            pixel_aov: None
         }
     }
     
-    pub fn new_add_object(object: Arc<Sphere>) -> Self {
+    pub fn new_add_object(object: Arc<dyn Hittable>) -> Self {
         OrchestratorServerMessage {
             message_type: OrchestratorServerMessageType::SendObject,
             object: Some(object),
+            shared_id: None,
             camera: None,
             pixel_index: None,
-            pixel_color: None
+            pixel_color: None,
+            pixel_aov: None
         }
     }
 
-    pub fn new_pixel_response(pixel_index: PixelIndexEntry, pixel_color: Color) -> Self {
+    pub fn new_add_shared_object(id: u64, object: Arc<dyn Hittable>) -> Self {
         OrchestratorServerMessage {
-            message_type: OrchestratorServerMessageType::SendObject,
+            message_type: OrchestratorServerMessageType::SendSharedObject,
+            object: Some(object),
+            shared_id: Some(id),
+            camera: None,
+            pixel_index: None,
+            pixel_color: None,
+            pixel_aov: None
+        }
+    }
+
+    pub fn new_pixel_response(pixel_index: PixelIndexEntry, pixel_color: Color, pixel_aov: Option<Aov>) -> Self {
+        OrchestratorServerMessage {
+            message_type: OrchestratorServerMessageType::ReceivePixel,
             object: None,
+            shared_id: None,
             camera: None,
             pixel_index: Some(pixel_index),
-            pixel_color: Some(pixel_color)
+            pixel_color: Some(pixel_color),
+            pixel_aov
+        }
+    }
+
+    pub fn new_end_pass() -> Self {
+        OrchestratorServerMessage {
+            message_type: OrchestratorServerMessageType::EndPass,
+            object: None,
+            shared_id: None,
+            camera: None,
+            pixel_index: None,
+            pixel_color: None,
+            pixel_aov: None
+        }
+    }
+
+    pub fn new_end_raytracing() -> Self {
+        OrchestratorServerMessage {
+            message_type: OrchestratorServerMessageType::EndRaytracing,
+            object: None,
+            shared_id: None,
+            camera: None,
+            pixel_index: None,
+            pixel_color: None,
+            pixel_aov: None
         }
     }
 }
\ No newline at end of file
@@ -3,5 +3,8 @@ pub mod object_server;
 pub mod ray_server;
 pub mod orchestrator_server;
 pub mod client;
+pub mod codec;
 pub mod config;
+pub mod connection;
+pub mod discovery;
 pub mod messages;
\ No newline at end of file
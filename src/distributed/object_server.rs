@@ -1,45 +1,122 @@
+use std::io::{Error, ErrorKind, Result};
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use crate::distributed::messages::{
+    required,
     ObjectServerMessage, 
     ObjectServerMessageType, 
 };
-use crate::raytracer::camera::ray_color_iteration;
+use crate::raytracer::camera::{shadow_ray_color, Camera};
+use crate::raytracer::bvh::Bvh;
+use crate::raytracer::hittable::Hittable;
 use crate::raytracer::hittable_list::HittableList;
+use crate::raytracer::shared::SharedObjects;
 
 pub struct ObjectServer{
     objects: HittableList,
+    // geometry the instances among the objects reference, see raytracer::shared
+    shared: SharedObjects,
+    accelerator: Option<Arc<dyn Hittable>>,
+    // scene lights and background, for the light samples taken at hits
+    camera: Arc<Camera>,
     should_stop: Arc<AtomicBool>,
 }
 
+// What hit checks need from the server, so they can run without holding on to it.
+pub struct ObjectScene {
+    world: Arc<dyn Hittable>,
+    camera: Arc<Camera>
+}
+
+impl ObjectScene {
+    // The response to a CheckHit or CheckShadow message.
+    pub fn check(&self, msg: &ObjectServerMessage) -> Result<ObjectServerMessage> {
+        match msg.message_type {
+            ObjectServerMessageType::CheckHit => {
+                let mut entry = required(&msg.ray_entry, "ray entry")?.clone();
+                let ray_t = *required(&msg.ray_interval, "ray interval")?;
+                let status = self.camera.ray_color_iteration(&mut entry, self.world.as_ref(), ray_t);
+                Ok(ObjectServerMessage::new_ray_check_response(entry, status))
+            }
+            ObjectServerMessageType::CheckShadow => {
+                let shadow_color = shadow_ray_color(
+                    required(&msg.shadow_ray, "shadow ray")?,
+                    self.world.as_ref(),
+                    *required(&msg.ray_interval, "ray interval")?
+                );
+                Ok(ObjectServerMessage::new_shadow_check_response(shadow_color))
+            }
+            _ => Err(Error::new(ErrorKind::InvalidInput, "not a hit check"))
+        }
+    }
+}
+
 impl ObjectServer {
     pub fn new(should_stop: Arc<AtomicBool>) -> Self {
         ObjectServer {
             objects: HittableList::new(),
+            shared: SharedObjects::new(),
+            accelerator: None,
+            camera: Arc::new(Camera::new()),
             should_stop
         }
     }
 
-    pub async fn handle_msg(&mut self, msg: &ObjectServerMessage) -> ObjectServerMessage {
+    pub async fn handle_msg(&mut self, msg: &ObjectServerMessage) -> Result<ObjectServerMessage> {
         let mut new_msg = msg.clone();
         match msg.message_type {
             ObjectServerMessageType::Deregistration => {
+                // claimed by an orchestrator for a new render, so the scene of the last one goes
                 self.should_stop.store(true, Ordering::SeqCst);
+                self.objects = HittableList::new();
+                self.shared = SharedObjects::new();
+                self.accelerator = None;
+                self.camera = Arc::new(Camera::new());
             }
             ObjectServerMessageType::Registration => {
                 self.should_stop.store(false, Ordering::SeqCst);
             }
             ObjectServerMessageType::AddObject => {
-                self.objects.add(msg.object_add.clone().unwrap());
+                let object = required(&msg.object_add, "object")?;
+                object.resolve_shared(&self.shared)?;
+                self.objects.add(object.clone());
+                // the hierarchy is stale now, so it gets rebuilt before the next hit check
+                self.accelerator = None;
+                // objects can be large, so don't echo them back
+                new_msg = ObjectServerMessage::new_no_data(ObjectServerMessageType::AddObject);
             }
-            ObjectServerMessageType::CheckHit => {
-                let mut entry = msg.ray_entry.clone().unwrap();
-                new_msg.ray_status = Some(ray_color_iteration(&mut entry, &self.objects));
-                new_msg.ray_entry = Some(entry);
+            ObjectServerMessageType::AddSharedObject => {
+                // only referenced by the instances added afterwards, not part of the world itself
+                self.shared.insert(*required(&msg.shared_id, "shared id")?, required(&msg.object_add, "object")?.clone());
+                new_msg = ObjectServerMessage::new_no_data(ObjectServerMessageType::AddSharedObject);
+            }
+            ObjectServerMessageType::BuildAccelerator => {
+                self.build_accelerator();
+            }
+            ObjectServerMessageType::CheckHit | ObjectServerMessageType::CheckShadow => {
+                new_msg = self.scene().check(msg)?;
+            }
+            ObjectServerMessageType::SetCamera => {
+                let camera = required(&msg.camera, "camera")?;
+                if let Some(lights) = &camera.lights {
+                    lights.resolve_shared(&self.shared)?;
+                }
+                self.camera = Arc::new(camera.clone());
+                new_msg = ObjectServerMessage::new_no_data(ObjectServerMessageType::SetCamera);
             }
             ObjectServerMessageType::PrintObjects => {
                 println!("Num Objects: {}", self.objects.len())
             }
         }
-        new_msg
+        Ok(new_msg)
+    }
+
+    pub fn scene(&mut self) -> ObjectScene {
+        ObjectScene { world: self.build_accelerator(), camera: Arc::clone(&self.camera) }
+    }
+
+    fn build_accelerator(&mut self) -> Arc<dyn Hittable> {
+        self.accelerator
+            .get_or_insert_with(|| Arc::new(Bvh::new(&self.objects)))
+            .clone()
     }
 }
\ No newline at end of file
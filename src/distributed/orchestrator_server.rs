@@ -1,45 +1,54 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
-use std::io::{ErrorKind, Result};
-use bincode;
+use std::net::SocketAddr;
+use std::io::{Error, ErrorKind, Result};
 use futures_util::stream::SplitSink;
 use tokio_tungstenite::WebSocketStream;
+use crate::distributed::codec::{decode, MAX_FRAME_SIZE};
 use crate::distributed::messages::*;
 use crate::distributed::distributed_common::{run_async_server, send_tcp_message, send_websocket_message};
 use crate::raytracer::bounding_box::BoundingBox;
-use crate::raytracer::camera::{Camera};
+use crate::raytracer::camera::{Camera, PixelIndexEntry};
+use crate::raytracer::framebuffer::FrameBuffer;
 use std::collections::HashMap;
-use std::time::Duration;
-use crate::distributed::config::{MULTICAST_ADDR, MULTICAST_PORT, NUM_REPEAT_OBJECT, ORCHESTRATOR_CLIENT_CONNECTION_SOCKET, ORCHESTRATOR_SERVER_CONNECTION_SOCKET};
+use crate::distributed::config::ClusterConfig;
+use crate::distributed::discovery::new_discovery;
 use std::sync::Arc;
 use tokio;
+use tokio::sync::mpsc::Sender;
 use futures_util::{StreamExt};
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 
-pub async fn run_orchestrator() {
-    let try_socket = tokio::net::TcpListener::bind(&ORCHESTRATOR_CLIENT_CONNECTION_SOCKET).await;
+pub async fn run_orchestrator(config: ClusterConfig) {
+    let try_socket = tokio::net::TcpListener::bind(&config.orchestrator_client_socket).await;
     let listener = try_socket.expect("Failed to bind");
 
+    // The ray servers send the finished samples here, which go to the render in progress.
+    let current_render: Arc<std::sync::Mutex<Option<Sender<OrchestratorServerMessage>>>> = Arc::default();
+    let endpoint_render = Arc::clone(&current_render);
+    tokio::spawn(
+        run_async_server(
+            config.orchestrator_server_socket,
+            move |msg: &OrchestratorServerMessage| {
+                let tx = endpoint_render.lock().unwrap().clone();
+                let cloned_msg = msg.clone(); 
+                async move {
+                    let tx = tx.ok_or_else(|| Error::other("orchestrator isn't rendering"))?;
+                    tx.send(cloned_msg.clone()).await
+                        .map_err(|_| Error::other("orchestrator isn't rendering"))?;
+                    Ok(cloned_msg)
+                }
+            }
+        )
+    );
+
     // Accept new connections in a loop.
     while let Ok((stream, peer_addr)) = listener.accept().await {
         let (tx, rx) = tokio::sync::mpsc::channel::<OrchestratorServerMessage>(128);
-        tokio::spawn(
-            run_async_server(
-                ORCHESTRATOR_SERVER_CONNECTION_SOCKET,
-                move |msg: &OrchestratorServerMessage| {
-                    // Clone the Arc to create a new shared reference for this call
-                    let tx_clone = tx.clone();
-                    let cloned_msg = msg.clone(); 
-                    async move {
-                        let _ = tx_clone.send(cloned_msg.clone()).await.unwrap();
-                        cloned_msg
-                    }
-                }
-            )
-        );
+        *current_render.lock().unwrap() = Some(tx);
 
         // Spawn a new asynchronous task for each connection.
         // The `spawn` function returns a `JoinHandle` which we don't need to await here.
-        let mut orchestrator = OrchestratorServer::new(rx);
+        let mut orchestrator = OrchestratorServer::new(rx, config.clone());
         orchestrator.discover_servers().await.unwrap();
         orchestrator.create_bounding_volumes();
         tokio::spawn(async move {
@@ -50,39 +59,64 @@ pub async fn run_orchestrator() {
 
 pub struct OrchestratorServer{
     rx: tokio::sync::mpsc::Receiver<OrchestratorServerMessage>,
-    server_directory: [Vec<SocketAddrV4>; NUM_SERVER_TYPES],
+    server_directory: [Vec<SocketAddr>; NUM_SERVER_TYPES],
     boxes: Vec<Arc<BoundingBox>>,
-    box_map: HashMap<usize, Vec<SocketAddrV4>>,
-    camera: Camera
+    box_map: HashMap<usize, Vec<SocketAddr>>,
+    camera: Camera,
+    config: ClusterConfig
 }
 
-async fn distribute_rays(camera: Camera, server_directory: [Vec<SocketAddrV4>; NUM_SERVER_TYPES]) {
-    for (ray_index, ray) in camera.iterate_rays() {
-        let consolidated_idx = ray_index.pixel_i+ray_index.pixel_j+ray_index.pixel_sample_num;
-        let server_idx = (consolidated_idx as usize) % server_directory[ServerType::Ray as usize].len();
-        let _ = send_tcp_message(
-            &server_directory[ServerType::Ray as usize][server_idx], 
-            &RayServerMessage::new_share_ray(&ray_index, &ray)
-        ).await;
-    }
+// Rays the orchestrator hands out without waiting for the ray servers to take the previous ones
+const RAYS_IN_FLIGHT: usize = 256;
+
+async fn distribute_rays(
+    camera: Camera,
+    server_directory: [Vec<SocketAddr>; NUM_SERVER_TYPES],
+    pixels: Vec<PixelIndexEntry>,
+    passes: i32
+) {
+    let ray_servers = &server_directory[ServerType::Ray as usize];
+    let num_pixels = pixels.len();
+    // the rays of a pass all come before those of the next
+    futures_util::stream::iter(camera.iterate_pixel_rays(pixels, passes).enumerate())
+        .map(|(n, ray)| {
+            if n.is_multiple_of(num_pixels) {
+                println!("Sending pass {} / {} for {} pixels", n / num_pixels + 1, passes, num_pixels);
+            }
+            ray
+        })
+        .for_each_concurrent(RAYS_IN_FLIGHT, |(ray_index, ray)| async move {
+            let consolidated_idx = ray_index.pixel_i+ray_index.pixel_j+ray_index.pixel_sample_num;
+            let server_idx = (consolidated_idx as usize) % ray_servers.len();
+            let _ = send_tcp_message(
+                &ray_servers[server_idx], 
+                &RayServerMessage::new_share_ray(&ray_index, ray.as_ref())
+            ).await;
+        })
+        .await;
 }
 
 impl OrchestratorServer {
-    pub fn new(rx: tokio::sync::mpsc::Receiver<OrchestratorServerMessage>) -> Self {
+    pub fn new(rx: tokio::sync::mpsc::Receiver<OrchestratorServerMessage>, config: ClusterConfig) -> Self {
         OrchestratorServer {
             rx: rx,
             server_directory: std::array::from_fn(|_| Vec::new()),
             boxes: Vec::new(),
             box_map: HashMap::new(),
-            camera: Camera::default()
+            camera: Camera::default(),
+            config
         }
     }
 
     async fn handle_connection(&mut self, stream: tokio::net::TcpStream, peer_addr: SocketAddr) {
         println!("New WebSocket connection from: {}", peer_addr);
 
-        // The `accept_async` method performs the WebSocket handshake.
-        let ws_stream = tokio_tungstenite::accept_async(stream)
+        // The `accept_async` method performs the WebSocket handshake. Client messages can be as
+        // large as the frames the orchestrator sends on, e.g. a camera with an environment map.
+        let ws_config = WebSocketConfig::default()
+            .max_message_size(Some(MAX_FRAME_SIZE))
+            .max_frame_size(Some(MAX_FRAME_SIZE));
+        let ws_stream = tokio_tungstenite::accept_async_with_config(stream, Some(ws_config))
             .await
             .expect("Error during the websocket handshake");
 
@@ -94,9 +128,17 @@ impl OrchestratorServer {
             match msg {
                 Ok(Message::Text(_)) => {}
                 Ok(Message::Binary(binary)) => {
-                    let (mut msg, _num_bytes_decoded): (OrchestratorServerMessage, usize) = bincode::serde::decode_from_slice(
-                        &binary, bincode::config::standard()).unwrap();
-                    self.handle_msg(&mut write, &mut msg).await;
+                    let mut msg: OrchestratorServerMessage = match decode(&binary) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            eprintln!("Bad message from {}: {}", peer_addr, e);
+                            break;
+                        }
+                    };
+                    if let Err(e) = self.handle_msg(&mut write, &mut msg).await {
+                        eprintln!("Bad message from {}: {}", peer_addr, e);
+                        break;
+                    }
                 }
                 Ok(Message::Ping(_)) => {}
                 Ok(Message::Close(close_frame)) => {
@@ -119,7 +161,7 @@ impl OrchestratorServer {
     fn create_bounding_volumes(&mut self) {
         let n = self.server_directory[ServerType::Object as usize].len();
         let mut cur_i: usize = 0;
-        for _ in 0..NUM_REPEAT_OBJECT {
+        for _ in 0..self.config.num_repeat_object {
             for a in (-10..=10).step_by(4) {
                 for b in (-10..=10).step_by(4) {
                     let bv = BoundingBox::new_xyz(
@@ -146,68 +188,63 @@ impl OrchestratorServer {
         &mut self, 
         write: &mut SplitSink<WebSocketStream<tokio::net::TcpStream>, Message>,
         msg: &mut OrchestratorServerMessage
-    ) {
+    ) -> Result<()> {
         match msg.message_type {
             OrchestratorServerMessageType::SendObject => {
+                let new_object = required(&msg.object, "object")?.clone();
+                let object_box = new_object.bounding_box();
                 for (index, aabb) in self.boxes.iter().enumerate() {
-                    let new_sphere = msg.object.clone().unwrap();
-                    if aabb.intersect_sphere(&new_sphere) {
+                    if aabb.overlaps(&object_box) {
                         for address in self.box_map[&index].iter() {
-                            let _ = send_tcp_message(
+                            let added = send_tcp_message(
                                 address, 
-                                &ObjectServerMessage::new_object_add(new_sphere.clone())
+                                &ObjectServerMessage::new_object_add(new_object.clone())
                             ).await;
+                            // e.g. an instance of shared geometry that never arrived
+                            if let Err(e) = added {
+                                eprintln!("Object server {} didn't take an object: {}", address, e);
+                            }
                         }
                     }
                 }
             }
+            OrchestratorServerMessageType::SendSharedObject => {
+                // Any object server may host an instance, so they all get a copy.
+                let id = *required(&msg.shared_id, "shared id")?;
+                let shared_object = required(&msg.object, "object")?.clone();
+                for address in self.server_directory[ServerType::Object as usize].iter() {
+                    let _ = send_tcp_message(
+                        address,
+                        &ObjectServerMessage::new_shared_object_add(id, shared_object.clone())
+                    ).await;
+                }
+            }
             OrchestratorServerMessageType::BeginRaytracing => {
-                self.camera = msg.camera.clone().unwrap();
+                self.camera = required(&msg.camera, "camera")?.clone();
                 let _ = self.run_raytracer(write).await;
             }
-            OrchestratorServerMessageType::ReceivePixel => {
-                panic!("Orchestrator Server should not receive pixels from itself") 
+            OrchestratorServerMessageType::ReceivePixel
+            | OrchestratorServerMessageType::EndPass
+            | OrchestratorServerMessageType::EndRaytracing => {
+                return Err(Error::new(ErrorKind::InvalidInput, "the client sent a message meant for it"));
             }
         }
+        Ok(())
     }
 
     async fn discover_servers(&mut self) -> Result<()> {
-        // Bind to the socket that will receive the multicast packets
-        let socket = UdpSocket::bind(SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT))?;
-        
-        // Join the multicast group on the local interface (0.0.0.0)
-        socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
-        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
-        
-        println!("Joined multicast group and listening for messages...");                 
+        let servers = new_discovery(&self.config).discover().await?;
 
-        let mut buf = [0; 256];
-        loop {
-            // Receive data from the socket
-            let recv_result = socket.recv_from(&mut buf);
-
-            match recv_result {
-                Ok((num_bytes, _src_addr)) => {
-                    let (msg, _num_bytes_decoded): (ServerDiscoveryMessage, usize) = bincode::serde::decode_from_slice(
-                        &buf[..num_bytes], bincode::config::standard()).unwrap();
-                    if !self.server_directory[msg.server_type as usize].contains(&msg.socket_addr) {
-                        self.server_directory[msg.server_type as usize].push(msg.socket_addr);
-                        if msg.server_type == ServerType::Ray {
-                            send_tcp_message(&msg.socket_addr, &RayServerMessage::new_no_data(RayServerMessageType::Deregistration)).await?;
-                        } else {
-                            send_tcp_message(&msg.socket_addr, &ObjectServerMessage::new_no_data(ObjectServerMessageType::Deregistration)).await?;
-                        };
-                    }
-                }
-                // Error: Check if it's a timeout error
-                Err(ref e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {
-                    println!("\nTimeout reached.");
-                    break;
-                },
-                // Other error
-                Err(e) => {
-                    return Err(e.into());
-                }
+        // Claim the servers, which stops them from announcing themselves to other orchestrators
+        for msg in servers {
+            let claimed = if msg.server_type == ServerType::Ray {
+                send_tcp_message(&msg.socket_addr, &RayServerMessage::new_no_data(RayServerMessageType::Deregistration)).await.map(|_| ())
+            } else {
+                send_tcp_message(&msg.socket_addr, &ObjectServerMessage::new_no_data(ObjectServerMessageType::Deregistration)).await.map(|_| ())
+            };
+            match claimed {
+                Ok(_) => self.server_directory[msg.server_type as usize].push(msg.socket_addr),
+                Err(e) => eprintln!("Skipping unreachable {} server {}: {}", msg.server_type, msg.socket_addr, e)
             }
         }
 
@@ -223,6 +260,12 @@ impl OrchestratorServer {
     }
 
     async fn share_params(&self) {
+        // Object servers take light samples at hits, so they need the lights and background.
+        for addr in self.server_directory[ServerType::Object as usize].iter() {
+            if let Err(e) = send_tcp_message(addr, &ObjectServerMessage::new_camera(&self.camera)).await {
+                eprintln!("Object server {} didn't take the camera: {}", addr, e);
+            }
+        }
         for i in 0..self.server_directory[ServerType::Ray as usize].len() {
             let _ = send_tcp_message(
                 &self.server_directory[ServerType::Ray as usize][i], 
@@ -242,19 +285,81 @@ impl OrchestratorServer {
             ).await;
         }
 
+        println!("Building object server hierarchies...");
+        for addr in self.server_directory[ServerType::Object as usize].iter() {
+            let _result = send_tcp_message(
+                addr,
+                &ObjectServerMessage::new_no_data(ObjectServerMessageType::BuildAccelerator)
+            ).await;
+        }
+
         println!("Sharing parameters...");
         self.share_params().await;
 
-        println!("Distributing rays...");
-        let thread_camera = self.camera.clone();
-        let thread_server_directory = self.server_directory.clone();
-        let _ = tokio::spawn(distribute_rays(thread_camera, thread_server_directory));
-        
-        println!("Waiting for ray responses...");
-        while let Some(msg) = self.rx.recv().await {
-            let _ = send_websocket_message(write, &msg).await;
+        // The samples passing through are added up here too, for the errors adaptive sampling
+        // goes by. Every pixel gets the same number of passes up front, and with adaptive
+        // sampling, more passes follow for the pixels that need them. The passes of a batch
+        // overlap, so the client is told when all the samples of one have come in.
+        let (width, height) = (self.camera.image_width as usize, self.camera.image_height() as usize);
+        let mut frame = FrameBuffer::new(width, height);
+        let mut samples_sent = vec![0; width * height];
+        let mut pixels: Vec<(usize, usize)> = (0..height).flat_map(|j| (0..width).map(move |i| (i, j))).collect();
+        let mut passes = match &self.camera.adaptive {
+            Some(adaptive) => adaptive.min_samples,
+            None => self.camera.samples_per_pixel
+        };
+        loop {
+            println!("Distributing rays for {} pixels...", pixels.len());
+            let first_sample = samples_sent.clone();
+            let entries: Vec<PixelIndexEntry> = pixels.iter().map(|&(i, j)| PixelIndexEntry {
+                pixel_i: i as i32,
+                pixel_j: j as i32,
+                pixel_sample_num: samples_sent[j * width + i]
+            }).collect();
+            for &(i, j) in pixels.iter() {
+                samples_sent[j * width + i] += passes;
+            }
+            let thread_camera = self.camera.clone();
+            let thread_server_directory = self.server_directory.clone();
+            let distributing = tokio::spawn(distribute_rays(thread_camera, thread_server_directory, entries, passes));
+
+            println!("Waiting for ray responses...");
+            let num_samples = pixels.len() * passes.max(0) as usize;
+            let mut pass_samples = vec![0; passes.max(0) as usize];
+            for _ in 0..num_samples {
+                let Ok(Some(msg)) = tokio::time::timeout(self.config.sample_timeout(), self.rx.recv()).await else {
+                    println!("Timed out waiting for samples");
+                    break;
+                };
+                let mut pass_done = false;
+                if let (Some(pixel_idx), Some(color)) = (&msg.pixel_index, &msg.pixel_color) {
+                    let (i, j) = (pixel_idx.pixel_i as usize, pixel_idx.pixel_j as usize);
+                    frame.add_sample(i, j, color);
+                    if let Some(count) = pass_samples.get_mut((pixel_idx.pixel_sample_num - first_sample[j * width + i]) as usize) {
+                        *count += 1;
+                        pass_done = *count == pixels.len();
+                    }
+                }
+                let _ = send_websocket_message(write, &msg).await;
+                if pass_done {
+                    let _ = send_websocket_message(write, &OrchestratorServerMessage::new_end_pass()).await;
+                }
+            }
+            // Every ray is out unless the samples timed out, and then the rest of the batch
+            // mustn't overlap the next one.
+            distributing.abort();
+
+            let Some(adaptive) = &self.camera.adaptive else {
+                break;
+            };
+            pixels = adaptive.active_pixels(&frame);
+            passes = 1;
+            if pixels.is_empty() {
+                break;
+            }
         }
 
+        let _ = send_websocket_message(write, &OrchestratorServerMessage::new_end_raytracing()).await;
         Ok(())
     }
 }
\ No newline at end of file
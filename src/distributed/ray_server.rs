@@ -1,125 +1,201 @@
 use core::time;
-use std::net::{SocketAddrV4};
+use std::io::Result;
+use std::net::{SocketAddr};
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use tokio::sync::mpsc;
-use bincode;
+use tokio::task::{JoinHandle, JoinSet};
 use tokio::time::sleep;
-use crate::distributed::config::ORCHESTRATOR_SERVER_CONNECTION_SOCKET;
+use crate::distributed::codec::CodecError;
 use crate::distributed::messages::{
-    ObjectServerMessage, OrchestratorServerMessage, RayServerMessage, RayServerMessageType
+    required, ObjectServerMessage, OrchestratorServerMessage, RayServerMessage, RayServerMessageType
 };
 use crate::distributed::distributed_common::send_tcp_message;
 use crate::raytracer::camera::{Camera, PixelIndexEntry, RayColorEntry, RayColorStatus};
 use crate::raytracer::bounding_box::BoundingBox;
-use crate::raytracer::hittable::{HitRecord, Hittable};
-use crate::raytracer::hittable_list::HittableList;
 use crate::raytracer::prelude::*;
 use std::collections::HashMap;
 
+// Splits the part of ray_t inside the boxes into disjoint segments, in the order the ray passes
+// through them, along with the box each segment is checked against. Where boxes overlap, the box
+// the ray entered first covers the overlap. Checking each object server only over its segment
+// makes the first reported hit the closest one, and keeps volumes from being sampled twice.
+fn ray_segments(boxes: &[Arc<BoundingBox>], r: &Ray, ray_t: Interval) -> Vec<(usize, Interval)> {
+    let mut hits: Vec<(usize, Interval)> = boxes
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, aabb)| aabb.hit_interval(r, ray_t).map(|interval| (idx, interval)))
+        .collect();
+    hits.sort_by(|a, b| a.1.min.total_cmp(&b.1.min));
+
+    let mut covered_until = ray_t.min;
+    let mut segments = Vec::new();
+    for (idx, interval) in hits {
+        if interval.max <= covered_until {
+            continue;
+        }
+        segments.push((idx, Interval::new_min_max(f64::max(interval.min, covered_until), interval.max)));
+        covered_until = interval.max;
+    }
+    segments
+}
+
+// Samples a ray server traces at a time, each waiting on one object server at a time
+const SAMPLES_IN_FLIGHT: usize = 4096;
+
 struct RayProcessor {
-    ray_entries: HashMap<PixelIndexEntry, RayColorEntry>,
-    bounding_boxes: HittableList,
-    object_servers: HashMap<usize, Vec<SocketAddrV4>>,
+    bounding_boxes: Vec<Arc<BoundingBox>>,
+    object_servers: HashMap<usize, Vec<SocketAddr>>,
     camera: Camera,
-    rx: mpsc::Receiver<(PixelIndexEntry, Ray)>
+    // where finished samples go
+    orchestrator: SocketAddr
 }
 
 impl RayProcessor {
     pub fn new(
         bounding_boxes: Vec<Arc<BoundingBox>>,
-        object_servers: HashMap<usize, Vec<SocketAddrV4>>,
+        object_servers: HashMap<usize, Vec<SocketAddr>>,
         camera: Camera,
-        rx: mpsc::Receiver<(PixelIndexEntry, Ray)>
+        orchestrator: SocketAddr
     ) -> Self {
         RayProcessor {
-            ray_entries: HashMap::new(),
-            bounding_boxes: HittableList::new_w_objs(bounding_boxes
-                .into_iter()
-                .map(|obj_arc| -> Arc<dyn Hittable> { obj_arc })
-                .collect()),
+            bounding_boxes: bounding_boxes,
             object_servers: object_servers,
             camera: camera,
-            rx: rx
+            orchestrator
         }
     }
 
-    pub async fn run(&mut self) {
-        while let Some((pixel_idx, ray)) = self.rx.recv().await {
-            if !self.ray_entries.contains_key(&pixel_idx) {
-                self.ray_entries.insert(pixel_idx.clone(), 
-                RayColorEntry::new(ray.clone(), self.camera.max_depth));
-            }
-            loop {
-                let mut finished: bool = true;
-                let mut status: RayColorStatus = RayColorStatus::default();
-                let mut first_hit: RayColorEntry = self.ray_entries[&pixel_idx].clone();
-                for (aabb_idx, _distance) in self.bounding_boxes.hits_vec(
-                    &self.ray_entries[&pixel_idx].ray, 
-                    Interval::new_min_max(0.001, INFINITY), 
-                    &mut HitRecord::default()).iter() 
-                {
-                    let mut server_idx: usize = 0;
-                    loop {
-                        let response = send_tcp_message(
-                            &self.object_servers[&aabb_idx][server_idx], 
-                            &ObjectServerMessage::new_ray_check(self.ray_entries[&pixel_idx].clone()
-                        )).await;
-                        match response {
-                            Ok(response_bytes) => {
-                                let (msg, _num_bytes_decoded): (ObjectServerMessage, usize) = bincode::serde::decode_from_slice(
-                                    &response_bytes, bincode::config::standard()).unwrap();
-                                first_hit = msg.ray_entry.unwrap();
-                                status = msg.ray_status.unwrap();
-                                break;
-                            }
-                            Err(_) => {
-                                if server_idx == self.object_servers[&aabb_idx].len()-1 {
-                                    sleep(time::Duration::from_secs(5)).await;
-                                    server_idx = 0;
-                                } else {
-                                    // timeout or some other error, so skip to other server that hosts object
-                                    server_idx += 1;
-                                }
-                                
-                            }
-                        }
+    // Sends `msg` to one of the object servers hosting box `aabb_idx`, moving on to the next one
+    // on errors, and waiting for them to come back once all have failed. A server rejecting the
+    // message fails the query, since the others would too.
+    async fn query_object_server(&self, aabb_idx: usize, msg: &ObjectServerMessage) -> Result<ObjectServerMessage> {
+        let mut server_idx: usize = 0;
+        loop {
+            let response = send_tcp_message(&self.object_servers[&aabb_idx][server_idx], msg).await;
+            match response {
+                Ok(msg) => {
+                    return Ok(msg);
+                }
+                Err(e) if matches!(e.get_ref().and_then(|e| e.downcast_ref::<CodecError>()), Some(CodecError::Remote(_))) => {
+                    return Err(e);
+                }
+                Err(_) => {
+                    if server_idx == self.object_servers[&aabb_idx].len()-1 {
+                        sleep(time::Duration::from_secs(5)).await;
+                        server_idx = 0;
+                    } else {
+                        // timeout or some other error, so skip to other server that hosts object
+                        server_idx += 1;
                     }
+                }
+            }
+        }
+    }
 
-                    finished = status.finished & finished;
-                    if status.hit_object_or_stop {
-                        break;
-                    }
+    // Light found along a shadow ray, from the first object server reporting a hit in its
+    // segment, see shadow_ray_color.
+    async fn trace_shadow_ray(&self, shadow_ray: &Ray) -> Result<Option<Color>> {
+        for (aabb_idx, segment) in ray_segments(&self.bounding_boxes, shadow_ray, Interval::new_min_max(0.001, f64::INFINITY)) {
+            let msg = self.query_object_server(aabb_idx, &ObjectServerMessage::new_shadow_check(shadow_ray.clone(), segment)).await?;
+            if msg.shadow_color.is_some() {
+                return Ok(msg.shadow_color);
+            }
+        }
+        Ok(None)
+    }
+
+    // Traces the samples from `rx`, many at once.
+    pub async fn run(self, mut rx: mpsc::Receiver<(PixelIndexEntry, Option<Ray>)>) {
+        let processor = Arc::new(self);
+        let mut in_flight = JoinSet::new();
+        while let Some((pixel_idx, ray)) = rx.recv().await {
+            while in_flight.len() >= SAMPLES_IN_FLIGHT {
+                in_flight.join_next().await;
+            }
+            let processor = Arc::clone(&processor);
+            in_flight.spawn(async move {
+                let (i, j) = (pixel_idx.pixel_i, pixel_idx.pixel_j);
+                if let Err(e) = processor.trace_sample(pixel_idx, ray).await {
+                    eprintln!("Dropping a sample of pixel ({}, {}): {}", i, j, e);
                 }
-                self.ray_entries.insert(pixel_idx.clone(), first_hit);
-                if finished {
-                    let _ = send_tcp_message(
-                        &ORCHESTRATOR_SERVER_CONNECTION_SOCKET, 
-                        &OrchestratorServerMessage::new_pixel_response(
-                            pixel_idx.clone(), 
-                            self.ray_entries[&pixel_idx].color
-                        )
-                    ).await;
-                    break; 
+            });
+            while in_flight.try_join_next().is_some() {}
+        }
+    }
+
+    async fn trace_sample(&self, pixel_idx: PixelIndexEntry, ray: Option<Ray>) -> Result<()> {
+        let Some(ray) = ray else {
+            // the projection has no ray for this sample, which stays black
+            let _ = send_tcp_message(
+                &self.orchestrator,
+                &OrchestratorServerMessage::new_pixel_response(pixel_idx, Color::default(), None)
+            ).await;
+            return Ok(());
+        };
+        let mut entry = RayColorEntry::new(ray, self.camera.max_depth, self.camera.sample_id(
+            pixel_idx.pixel_i, pixel_idx.pixel_j, pixel_idx.pixel_sample_num));
+        loop {
+            let mut finished: bool = true;
+            let mut hit_object_or_stop: bool = false;
+            let mut first_hit: RayColorEntry = entry.clone();
+            for (aabb_idx, segment) in ray_segments(
+                &self.bounding_boxes,
+                &entry.ray,
+                Interval::new_min_max(0.001, f64::INFINITY)).iter()
+            {
+                let msg = self.query_object_server(
+                    *aabb_idx,
+                    &ObjectServerMessage::new_ray_check(entry.clone(), *segment)
+                ).await?;
+                first_hit = required(&msg.ray_entry, "ray entry")?.clone();
+                let status: RayColorStatus = required(&msg.ray_status, "ray status")?.clone();
+
+                finished &= status.finished;
+                if status.hit_object_or_stop {
+                    hit_object_or_stop = true;
+                    break;
                 }
             }
+            if let Some(shadow) = &first_hit.shadow {
+                let light = self.trace_shadow_ray(&shadow.ray).await?;
+                self.camera.apply_shadow_ray(&mut first_hit, light);
+            }
+            if !hit_object_or_stop {
+                // the ray escaped every object server, so it picks up the background
+                self.camera.apply_background(&mut first_hit);
+                finished = true;
+            }
+            entry = first_hit;
+            if finished {
+                let _ = send_tcp_message(
+                    &self.orchestrator,
+                    &OrchestratorServerMessage::new_pixel_response(pixel_idx, entry.color, entry.aov)
+                ).await;
+                return Ok(());
+            }
         }
     }
 }
 
 pub struct RayServer{
-    tx: mpsc::Sender<(PixelIndexEntry, Ray)>,
+    tx: mpsc::Sender<(PixelIndexEntry, Option<Ray>)>,
+    // tracing the samples of the current render
+    processor: Option<JoinHandle<()>>,
     should_stop: Arc<AtomicBool>,
+    orchestrator: SocketAddr
 }
 
 impl RayServer {
-    pub fn new(should_stop: Arc<AtomicBool>) -> Self {
+    pub fn new(should_stop: Arc<AtomicBool>, orchestrator: SocketAddr) -> Self {
         RayServer {
-            tx: mpsc::channel::<(PixelIndexEntry, Ray)>(128).0,
-            should_stop: should_stop
+            tx: mpsc::channel::<(PixelIndexEntry, Option<Ray>)>(128).0,
+            processor: None,
+            should_stop: should_stop,
+            orchestrator
         }
     }
 
-    pub async fn handle_msg(&mut self, msg: &RayServerMessage) -> RayServerMessage {
+    pub async fn handle_msg(&mut self, msg: &RayServerMessage) -> Result<RayServerMessage> {
         match msg.message_type {
             RayServerMessageType::Deregistration => {
                 self.should_stop.store(true, Ordering::SeqCst);
@@ -128,25 +204,174 @@ impl RayServer {
                 self.should_stop.store(false, Ordering::SeqCst);
             }
             RayServerMessageType::SendObjectServerDirectory => {
-                let (tx, rx) = mpsc::channel::<(PixelIndexEntry, Ray)>(128);
+                let (tx, rx) = mpsc::channel::<(PixelIndexEntry, Option<Ray>)>(128);
                 
-                let thread_msg = msg.clone();
-                let _ = tokio::spawn(async move {
-                    let mut ray_processor = RayProcessor::new(
-                        thread_msg.object_bbs.clone().unwrap(),
-                        thread_msg.object_servers.clone().unwrap(),
-                        thread_msg.camera.clone().unwrap(),
-                        rx
-                    );
-                    ray_processor.run().await;
-                });
+                let ray_processor = RayProcessor::new(
+                    required(&msg.object_bbs, "bounding boxes")?.clone(),
+                    required(&msg.object_servers, "object servers")?.clone(),
+                    required(&msg.camera, "camera")?.clone(),
+                    self.orchestrator
+                );
+                // the samples of the last render are stale now
+                if let Some(processor) = self.processor.replace(tokio::spawn(ray_processor.run(rx))) {
+                    processor.abort();
+                }
                 self.tx = tx;
+                // The camera can carry a whole environment map, so don't echo it back.
+                return Ok(RayServerMessage::new_no_data(RayServerMessageType::SendObjectServerDirectory));
             }
             RayServerMessageType::SendPixel => {
-                let _ = self.tx.send((msg.clone().pixel_index.unwrap(), msg.clone().ray.unwrap())).await;
+                // no ray means a black sample, see Camera::get_ray
+                let pixel = (required(&msg.pixel_index, "pixel index")?.clone(), msg.ray.clone());
+                let _ = self.tx.send(pixel).await;
             }
             RayServerMessageType::CheckHit => {}
         }
-        msg.clone()
+        Ok(msg.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ray_segments, RayServer};
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, Mutex};
+    use crate::distributed::distributed_common::{send_tcp_message, serve_listener};
+    use crate::distributed::messages::{ObjectServerMessage, OrchestratorServerMessage, RayServerMessage};
+    use crate::distributed::object_server::ObjectServer;
+    use crate::raytracer::background::EnvironmentMap;
+    use crate::raytracer::bounding_box::BoundingBox;
+    use crate::raytracer::camera::{Camera, PixelIndexEntry};
+    use crate::raytracer::constant_medium::ConstantMedium;
+    use crate::raytracer::hittable::Hittable;
+    use crate::raytracer::hittable_list::HittableList;
+    use crate::raytracer::material::{Dialectric, DiffuseLight, Isotropic, Lambertian};
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::quad::Quad;
+    use crate::raytracer::shared::{SharedHittable, SharedObjects};
+    use crate::raytracer::sphere::Sphere;
+
+    #[test]
+    fn test_ray_segments() {
+        // two overlapping boxes along x, and one the ray misses
+        let boxes = vec![
+            Arc::new(BoundingBox::new_xyz(4., 10., -1., 1., -1., 1.)),
+            Arc::new(BoundingBox::new_xyz(0., 6., -1., 1., -1., 1.)),
+            Arc::new(BoundingBox::new_xyz(0., 6., 5., 6., -1., 1.)),
+        ];
+        let r = Ray::new(Point3::new_xyz(1., 0., 0.), Vec3::new_xyz(1., 0., 0.));
+        let segments = ray_segments(&boxes, &r, Interval::new_min_max(0.001, f64::INFINITY));
+
+        assert_eq!(segments.len(), 2);
+        let (idx, segment) = segments[0];
+        assert_eq!((idx, segment.min, segment.max), (1, 0.001, 5.));
+        let (idx, segment) = segments[1];
+        assert_eq!((idx, segment.min, segment.max), (0, 5., 9.));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_distributed_samples_match_local() {
+        // A glass ball instance on the left and fog on the right, each on its own object
+        // server, with the floor and the light on both.
+        let mut shared = SharedObjects::new();
+        shared.insert(0, Arc::new(Sphere::new(&Point3::new_xyz(-1.5, 0., -2.), 0.8, Arc::new(Dialectric::new(1.5)))));
+        let ball: Arc<dyn Hittable> = Arc::new(SharedHittable::new(0, shared[&0].clone()));
+        let floor: Arc<dyn Hittable> = Arc::new(Quad::new(
+            &Point3::new_xyz(-50., -1., -50.), &Vec3::new_xyz(100., 0., 0.), &Vec3::new_xyz(0., 0., 100.),
+            Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])))));
+        let fog: Arc<dyn Hittable> = Arc::new(ConstantMedium::new(
+            Arc::new(Sphere::new(&Point3::new_xyz(2., 0., -2.), 1.5, Arc::new(Dialectric::new(1.)))),
+            0.5, Arc::new(Isotropic::new(&Color::new([0.9, 0.9, 0.9])))));
+        let light: Arc<dyn Hittable> = Arc::new(Quad::new(
+            &Point3::new_xyz(-1., 3., -3.), &Vec3::new_xyz(2., 0., 0.), &Vec3::new_xyz(0., 0., 2.),
+            Arc::new(DiffuseLight::new(&Color::new([4., 4., 4.])))));
+        let objects = vec![floor, ball, fog, light.clone()];
+        let world = HittableList::new_w_objs(objects.clone());
+        world.resolve_shared(&shared).unwrap();
+
+        let mut camera = Camera::new();
+        camera.image_width = 12;
+        camera.samples_per_pixel = 2;
+        camera.lights = Some(light);
+        let sky = (0..32).map(|n| [n as f32 / 32., 0.5, 1.]).collect();
+        camera.background = Some(Arc::new(EnvironmentMap::new(8, 4, sky, 1., 30.)));
+        camera.initialize();
+
+        // Each object server gets the objects overlapping its box, like from the orchestrator.
+        let boxes = vec![
+            Arc::new(BoundingBox::new_xyz(-100., 0., -100., 100., -100., 100.)),
+            Arc::new(BoundingBox::new_xyz(0., 100., -100., 100., -100., 100.)),
+        ];
+        let mut object_servers = HashMap::new();
+        for (index, aabb) in boxes.iter().enumerate() {
+            let addr = start_object_server(ObjectServer::new(Arc::default())).await;
+            for (id, object) in shared.iter() {
+                send_tcp_message(&addr, &ObjectServerMessage::new_shared_object_add(*id, object.clone())).await.unwrap();
+            }
+            for object in objects.iter().filter(|object| aabb.overlaps(&object.bounding_box())) {
+                send_tcp_message(&addr, &ObjectServerMessage::new_object_add(object.clone())).await.unwrap();
+            }
+            send_tcp_message(&addr, &ObjectServerMessage::new_camera(&camera)).await.unwrap();
+            object_servers.insert(index, vec![addr]);
+        }
+
+        // stands in for the orchestrator, collecting the finished samples
+        let orchestrator = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let orchestrator_addr = orchestrator.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::channel(128);
+        tokio::spawn(serve_listener(orchestrator, move |msg: &OrchestratorServerMessage| {
+            let (tx, msg) = (tx.clone(), msg.clone());
+            async move {
+                let _ = tx.send(msg.clone()).await;
+                Ok(msg)
+            }
+        }));
+
+        let ray_server = start_ray_server(RayServer::new(Arc::default(), orchestrator_addr)).await;
+        send_tcp_message(&ray_server, &RayServerMessage::new_share_params(&boxes, &object_servers, &camera)).await.unwrap();
+        let mut expected = HashMap::new();
+        for (i, j, sample) in (0..12).flat_map(|j| (0..12).flat_map(move |i| (0..2).map(move |s| (i, j, s)))) {
+            let ray = camera.get_ray(i, j, sample);
+            let pixel_index = PixelIndexEntry { pixel_i: i, pixel_j: j, pixel_sample_num: sample };
+            send_tcp_message(&ray_server, &RayServerMessage::new_share_ray(&pixel_index, ray.as_ref())).await.unwrap();
+            let color = camera.trace_pixel(i, j, sample, &world).color;
+            expected.insert((i, j, sample), color);
+        }
+
+        // The same random numbers come up for each sample however the ray servers interleave
+        // them, so each comes out exactly as in a local render.
+        let bits = |c: &Color| (0..3).map(|n| c[n].to_bits()).collect::<Vec<u64>>();
+        for _ in 0..expected.len() {
+            let msg = tokio::time::timeout(Duration::from_secs(30), rx.recv()).await.unwrap().unwrap();
+            let index = msg.pixel_index.unwrap();
+            let color = expected.remove(&(index.pixel_i, index.pixel_j, index.pixel_sample_num)).unwrap();
+            assert_eq!(bits(&msg.pixel_color.unwrap()), bits(&color));
+        }
+    }
+
+    // Serves messages for `server` on a new local port, returning its address.
+    async fn start_object_server(server: ObjectServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Mutex::new(server));
+        tokio::spawn(serve_listener(listener, move |msg: &ObjectServerMessage| {
+            let (server, msg) = (Arc::clone(&server), msg.clone());
+            async move { server.lock().await.handle_msg(&msg).await }
+        }));
+        addr
+    }
+
+    async fn start_ray_server(server: RayServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Mutex::new(server));
+        tokio::spawn(serve_listener(listener, move |msg: &RayServerMessage| {
+            let (server, msg) = (Arc::clone(&server), msg.clone());
+            async move { server.lock().await.handle_msg(&msg).await }
+        }));
+        addr
+    }
+}
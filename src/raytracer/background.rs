@@ -0,0 +1,264 @@
+//! Light arriving along rays that escape the scene.
+
+use std::path::Path;
+use std::sync::OnceLock;
+use crate::raytracer::prelude::*;
+
+#[typetag::serde(tag = "type")]
+pub trait Background: Send + Sync {
+    // radiance seen along the direction of `r`
+    fn color(&self, r: &Ray) -> Color;
+
+    // Probability density (per unit solid angle) of `random` returning `direction`. Backgrounds
+    // without a better strategy are sampled uniformly over the sphere.
+    fn pdf_value(&self, _direction: &Vec3) -> f64 {
+        1. / (4. * PI)
+    }
+
+    // Random direction, distributed with pdf_value, for sampling light from the background.
+    fn random(&self) -> Vec3 {
+        random_unit_vector()
+    }
+
+    // Whether light samples should go towards the background as well as the scene lights, which
+    // only pays off for backgrounds with a better strategy than uniform sampling.
+    fn sampled_as_light(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Constant {
+    color: Color
+}
+
+impl Constant {
+    pub fn new(color: &Color) -> Self {
+        Constant { color: *color }
+    }
+}
+
+#[typetag::serde]
+impl Background for Constant {
+    fn color(&self, _r: &Ray) -> Color {
+        self.color
+    }
+}
+
+// Vertical blend between two colors, from `bottom` looking straight down to `top` looking
+// straight up.
+#[derive(Serialize, Deserialize)]
+pub struct Gradient {
+    bottom: Color,
+    top: Color
+}
+
+impl Gradient {
+    pub fn new(bottom: &Color, top: &Color) -> Self {
+        Gradient { bottom: *bottom, top: *top }
+    }
+
+    // The white to blue sky from "Ray Tracing in One Weekend".
+    pub fn sky() -> Self {
+        Gradient::new(&Color::new([1.0, 1.0, 1.0]), &Color::new([0.5, 0.7, 1.0]))
+    }
+}
+
+#[typetag::serde]
+impl Background for Gradient {
+    fn color(&self, r: &Ray) -> Color {
+        let unit_direction: Vec3 = unit_vector(r.direction());
+        let a = 0.5*(unit_direction.y() + 1.0);
+        (1.0-a)*self.bottom + a*self.top
+    }
+}
+
+// Tables for picking texels of an environment map in proportion to their brightness, first a row
+// from the marginal distribution, then a column from the conditional distribution of that row.
+struct EnvironmentDistribution {
+    row_cdf: Vec<f64>,
+    column_cdfs: Vec<Vec<f64>>,
+    // texel weight, divided by the total weight of the map
+    texel_pdf: Vec<f64>
+}
+
+// Returns the index of the bucket of a cdf (ending at 1) that `x` falls into.
+fn sample_cdf(cdf: &[f64], x: f64) -> usize {
+    cdf.partition_point(|&c| c <= x).min(cdf.len() - 1)
+}
+
+fn normalized_cdf(weights: &[f64]) -> Vec<f64> {
+    let total: f64 = weights.iter().sum();
+    let mut sum = 0.;
+    weights.iter().map(|w| {
+        sum += if total > 0. { w / total } else { 1. / weights.len() as f64 };
+        sum
+    }).collect()
+}
+
+// Equirectangular (latitude-longitude) environment map, usually loaded from a Radiance .hdr
+// file. The pixels travel with the camera to every server, while the sampling tables are
+// rebuilt on first use.
+#[derive(Serialize, Deserialize)]
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<[f32; 3]>,
+    intensity: f64,
+    // rotation around the vertical axis, in degrees
+    rotation: f64,
+    #[serde(skip)]
+    distribution: OnceLock<EnvironmentDistribution>
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, pixels: Vec<[f32; 3]>, intensity: f64, rotation: f64) -> Self {
+        assert_eq!(pixels.len(), width * height, "environment map needs width * height pixels");
+        EnvironmentMap { width, height, pixels, intensity, rotation, distribution: OnceLock::new() }
+    }
+
+    pub fn load(path: impl AsRef<Path>, intensity: f64, rotation: f64) -> Result<Self> {
+        let path = path.as_ref();
+        let img = image::open(path)
+            .map_err(|e| std::io::Error::other(format!("{}: {}", path.display(), e)))?
+            .into_rgb32f();
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        let pixels = img.pixels().map(|p| p.0).collect();
+        Ok(EnvironmentMap::new(width, height, pixels, intensity, rotation))
+    }
+
+    fn texel(&self, i: usize, j: usize) -> Color {
+        let [r, g, b] = self.pixels[j * self.width + i];
+        Color::new([r as f64, g as f64, b as f64])
+    }
+
+    // Maps a unit direction to (u, v) in [0, 1]^2, with v = 0 looking straight up and the
+    // rotation applied to u.
+    fn direction_to_uv(&self, direction: &Vec3) -> (f64, f64) {
+        let theta = f64::acos(direction.y().clamp(-1., 1.));
+        let phi = f64::atan2(-direction.z(), direction.x()) + PI;
+        let u = (phi / (2. * PI) - self.rotation / 360.).rem_euclid(1.);
+        (u, theta / PI)
+    }
+
+    fn uv_to_direction(&self, u: f64, v: f64) -> Vec3 {
+        let theta = v * PI;
+        let phi = (u + self.rotation / 360.) * 2. * PI - PI;
+        Vec3::new_xyz(f64::sin(theta) * f64::cos(phi), f64::cos(theta), -f64::sin(theta) * f64::sin(phi))
+    }
+
+    fn uv_to_texel(&self, u: f64, v: f64) -> (usize, usize) {
+        let i = ((u * self.width as f64) as usize).min(self.width - 1);
+        let j = ((v * self.height as f64) as usize).min(self.height - 1);
+        (i, j)
+    }
+
+    fn distribution(&self) -> &EnvironmentDistribution {
+        self.distribution.get_or_init(|| {
+            // Rows near the poles cover less solid angle, so their texels are weighted down.
+            let weights: Vec<f64> = (0..self.width * self.height).map(|index| {
+                let (i, j) = (index % self.width, index / self.width);
+                let sin_theta = f64::sin(PI * (j as f64 + 0.5) / self.height as f64);
+                let c = self.texel(i, j);
+                (0.2126*c.x() + 0.7152*c.y() + 0.0722*c.z()) * sin_theta
+            }).collect();
+            let total: f64 = weights.iter().sum();
+
+            let rows: Vec<&[f64]> = weights.chunks(self.width).collect();
+            let row_weights: Vec<f64> = rows.iter().map(|row| row.iter().sum()).collect();
+            let texel_pdf = if total > 0. {
+                weights.iter().map(|w| w / total).collect()
+            } else {
+                vec![1. / weights.len() as f64; weights.len()]
+            };
+
+            EnvironmentDistribution {
+                row_cdf: normalized_cdf(&row_weights),
+                column_cdfs: rows.iter().map(|row| normalized_cdf(row)).collect(),
+                texel_pdf
+            }
+        })
+    }
+}
+
+#[typetag::serde]
+impl Background for EnvironmentMap {
+    fn color(&self, r: &Ray) -> Color {
+        let (u, v) = self.direction_to_uv(&unit_vector(r.direction()));
+        let (i, j) = self.uv_to_texel(u, v);
+        self.intensity * self.texel(i, j)
+    }
+
+    fn pdf_value(&self, direction: &Vec3) -> f64 {
+        let (u, v) = self.direction_to_uv(&unit_vector(direction));
+        let sin_theta = f64::sin(v * PI);
+        if sin_theta <= 0. {
+            return 0.;
+        }
+        let (i, j) = self.uv_to_texel(u, v);
+        // density over the unit (u, v) square, converted to solid angle
+        let uv_pdf = self.distribution().texel_pdf[j * self.width + i] * (self.width * self.height) as f64;
+        uv_pdf / (2. * PI * PI * sin_theta)
+    }
+
+    fn random(&self) -> Vec3 {
+        let distribution = self.distribution();
+        let j = sample_cdf(&distribution.row_cdf, random_f64());
+        let i = sample_cdf(&distribution.column_cdfs[j], random_f64());
+        let u = (i as f64 + random_f64()) / self.width as f64;
+        let v = (j as f64 + random_f64()) / self.height as f64;
+        self.uv_to_direction(u, v)
+    }
+
+    fn sampled_as_light(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::rng;
+    use super::{Background, EnvironmentMap};
+    use crate::raytracer::prelude::*;
+
+    // 8x4 map that is dark apart from one bright texel.
+    fn test_map() -> EnvironmentMap {
+        let mut pixels = vec![[0.1f32, 0.1, 0.1]; 8 * 4];
+        pixels[8 + 5] = [50., 40., 30.];
+        EnvironmentMap::new(8, 4, pixels, 1., 30.)
+    }
+
+    #[test]
+    fn test_uv_round_trip() {
+        let map = test_map();
+        let direction = unit_vector(&Vec3::new_xyz(0.3, -0.5, 0.8));
+        let (u, v) = map.direction_to_uv(&direction);
+        assert!((map.uv_to_direction(u, v) - direction).length() < 1e-9);
+    }
+
+    #[test]
+    fn test_pdf_integrates_to_one() {
+        rng::seed_thread(1);
+        // Monte Carlo estimate of the integral of pdf_value over the sphere.
+        let map = test_map();
+        let n = 200000;
+        let total: f64 = (0..n).map(|_| map.pdf_value(&random_unit_vector())).sum();
+        let integral = total / n as f64 * 4. * PI;
+        assert!((integral - 1.).abs() < 0.05, "pdf integrates to {}", integral);
+    }
+
+    #[test]
+    fn test_samples_follow_pdf() {
+        rng::seed_thread(2);
+        // The bright texel holds most of the weight, so most samples should land there.
+        let map = test_map();
+        let n = 20000;
+        let bright = (0..n).filter(|_| {
+            let direction = map.random();
+            let r = Ray::new(Point3::default(), direction);
+            map.color(&r).x() > 1.
+        }).count();
+        let expected = map.distribution().texel_pdf[8 + 5];
+        assert!((bright as f64 / n as f64 - expected).abs() < 0.02);
+    }
+}
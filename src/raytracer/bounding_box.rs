@@ -3,7 +3,7 @@ use crate::raytracer::prelude::*;
 use crate::raytracer::hittable::{Hittable, HitRecord};
 use crate::raytracer::sphere::Sphere;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct BoundingBox {
     axes: [Interval; 3]
 }
@@ -14,11 +14,13 @@ impl BoundingBox {
     }
 
     pub fn new(axes: [Interval; 3]) -> Self {
-        BoundingBox { axes }
+        let mut bbox = BoundingBox { axes };
+        bbox.pad_to_minimums();
+        bbox
     }
 
     pub fn new_xyz(x_min: f64, x_max: f64, y_min: f64, y_max: f64, z_min: f64, z_max: f64) -> Self {
-        BoundingBox { 
+        BoundingBox {
             axes: [
                 Interval::new_min_max(x_min, x_max),
                 Interval::new_min_max(y_min, y_max),
@@ -27,7 +29,93 @@ impl BoundingBox {
         }
     }
 
-    // Adapted from https://developer.mozilla.org/en-US/docs/Games/Techniques/3D_collision_detection 
+    pub fn new_points(a: &Point3, b: &Point3) -> Self {
+        // Treat the two points a and b as extrema for the bounding box, so we don't require a
+        // particular minimum/maximum coordinate order.
+        let lo = min_vec(a, b);
+        let hi = max_vec(a, b);
+        BoundingBox::new([
+            Interval::new_min_max(lo.x(), hi.x()),
+            Interval::new_min_max(lo.y(), hi.y()),
+            Interval::new_min_max(lo.z(), hi.z()),
+        ])
+    }
+
+    pub fn new_enclosing(box0: &BoundingBox, box1: &BoundingBox) -> Self {
+        BoundingBox {
+            axes: std::array::from_fn(|n| Interval::new_enclosing(&box0.axes[n], &box1.axes[n]))
+        }
+    }
+
+    pub fn axis_interval(&self, n: usize) -> &Interval {
+        &self.axes[n]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.axes.iter().any(|axis| axis.min > axis.max)
+    }
+
+    pub fn overlaps(&self, other: &BoundingBox) -> bool {
+        (0..3).all(|n| self.axes[n].min <= other.axes[n].max && other.axes[n].min <= self.axes[n].max)
+    }
+
+    pub fn longest_axis(&self) -> usize {
+        // Returns the index of the longest axis of the bounding box.
+        let mut longest = 0;
+        for n in 1..3 {
+            if self.axes[n].size() > self.axes[longest].size() {
+                longest = n;
+            }
+        }
+        longest
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        Point3::new(std::array::from_fn(|n| 0.5 * (self.axes[n].min + self.axes[n].max)))
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        if self.is_empty() {
+            return 0.;
+        }
+        let (x, y, z) = (self.axes[0].size(), self.axes[1].size(), self.axes[2].size());
+        2. * (x*y + y*z + z*x)
+    }
+
+    // Slab test, adapted from https://raytracing.github.io/books/RayTracingTheNextWeek.html
+    pub fn hit_aabb(&self, r: &Ray, ray_t: Interval) -> bool {
+        self.hit_interval(r, ray_t).is_some()
+    }
+
+    // Returns the part of ray_t during which the ray is inside the box, if any.
+    pub fn hit_interval(&self, r: &Ray, ray_t: Interval) -> Option<Interval> {
+        let mut ray_t = ray_t;
+        for a in 0..3 {
+            let adinv = 1.0 / r.direction()[a];
+            let t0 = (self.axes[a].min - r.origin()[a]) * adinv;
+            let t1 = (self.axes[a].max - r.origin()[a]) * adinv;
+
+            ray_t.min = f64::max(ray_t.min, f64::min(t0, t1));
+            ray_t.max = f64::min(ray_t.max, f64::max(t0, t1));
+
+            if ray_t.max <= ray_t.min {
+                return None;
+            }
+        }
+        Some(ray_t)
+    }
+
+    fn pad_to_minimums(&mut self) {
+        // Adjust the box so that no side is narrower than some delta, padding if necessary.
+        let delta = 0.0001;
+        for axis in self.axes.iter_mut() {
+            if axis.size() < delta {
+                *axis = axis.expand(delta);
+            }
+        }
+    }
+
+    // Adapted from https://developer.mozilla.org/en-US/docs/Games/Techniques/3D_collision_detection
     pub fn intersect_sphere(&self, sphere: &Sphere) -> bool {
         // get box closest point to sphere center by clamping
         let x = f64::max(self.axes[0].min, f64::min(sphere.center()[0], self.axes[0].max));
@@ -50,10 +138,10 @@ impl Hittable for BoundingBox {
     fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
         let mut t_min: f64 = -INFINITY;
         let mut t_max: f64 = INFINITY;
-        for a in 0..2 {
+        for a in 0..3 {
             if r.direction()[a] != 0.0 {
-                let t0: f64 = self.axes[a].min - r.origin()[a] / r.direction()[a];
-                let t1: f64 = self.axes[a].max - r.origin()[a] / r.direction()[a];
+                let t0: f64 = (self.axes[a].min - r.origin()[a]) / r.direction()[a];
+                let t1: f64 = (self.axes[a].max - r.origin()[a]) / r.direction()[a];
                 t_min = f64::max(t_min, f64::min(t0, t1));
                 t_max = f64::min(t_max, f64::max(t0, t1));
             }
@@ -69,9 +157,13 @@ impl Hittable for BoundingBox {
                 rec.t = t_max;
                 rec.p = r.at(t_max);
                 rec.mat = Arc::new(Transparent{});
-                return true;    
+                return true;
             }
         }
         false
     }
-}
\ No newline at end of file
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.clone()
+    }
+}
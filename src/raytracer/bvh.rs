@@ -0,0 +1,225 @@
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::shared::SharedObjects;
+use crate::raytracer::hittable_list::HittableList;
+
+// Relative cost of traversing a node compared to intersecting a primitive, used by the SAH.
+const TRAVERSAL_COST: f64 = 0.125;
+
+#[derive(Serialize, Deserialize)]
+pub struct Bvh {
+    left: Arc<dyn Hittable>,
+    right: Arc<dyn Hittable>,
+    bbox: BoundingBox
+}
+
+impl Bvh {
+    pub fn new(list: &HittableList) -> Self {
+        if list.len() == 0 {
+            // An empty hierarchy never gets hit, so both children are just empty lists.
+            let empty: Arc<dyn Hittable> = Arc::new(HittableList::new());
+            return Bvh { left: empty.clone(), right: empty, bbox: BoundingBox::default() };
+        }
+        // Unbounded objects (e.g. planes) have no useful centroid or area to split on, so they
+        // are kept in a plain list next to the hierarchy of the bounded ones.
+        let (mut items, unbounded): (Vec<_>, Vec<_>) = list
+            .iter()
+            .map(|object| (object.clone(), object.bounding_box()))
+            .partition(|(_, bbox)| bbox.surface_area().is_finite());
+        if unbounded.is_empty() {
+            return Bvh::build(&mut items);
+        }
+
+        let bbox = unbounded.iter().fold(BoundingBox::default(), |acc, (_, b)| BoundingBox::new_enclosing(&acc, b));
+        let unbounded: Arc<dyn Hittable> = Arc::new(HittableList::new_w_objs(
+            unbounded.into_iter().map(|(object, _)| object).collect()
+        ));
+        if items.is_empty() {
+            return Bvh { left: unbounded.clone(), right: unbounded, bbox };
+        }
+        let bounded = Bvh::build_child(&mut items);
+        let bbox = BoundingBox::new_enclosing(&bbox, &bounded.bounding_box());
+        Bvh { left: bounded, right: unbounded, bbox }
+    }
+
+    fn build(items: &mut [(Arc<dyn Hittable>, BoundingBox)]) -> Self {
+        // Build the bounding box of the span of source objects.
+        let bbox = items.iter().fold(BoundingBox::default(), |acc, (_, b)| BoundingBox::new_enclosing(&acc, b));
+
+        match items.len() {
+            1 => return Bvh { left: items[0].0.clone(), right: items[0].0.clone(), bbox },
+            2 => return Bvh { left: items[0].0.clone(), right: items[1].0.clone(), bbox },
+            _ => {}
+        }
+
+        // Sort along the axis where the centroids are spread the most.
+        let centroid_bounds = items.iter().fold(BoundingBox::default(), |acc, (_, b)| {
+            let c = b.centroid();
+            BoundingBox::new_enclosing(&acc, &BoundingBox::new_points(&c, &c))
+        });
+        let axis = centroid_bounds.longest_axis();
+        items.sort_by(|a, b| a.1.centroid()[axis].total_cmp(&b.1.centroid()[axis]));
+
+        let mid = Bvh::sah_split(items, &bbox);
+        let (left_items, right_items) = items.split_at_mut(mid);
+
+        Bvh {
+            left: Bvh::build_child(left_items),
+            right: Bvh::build_child(right_items),
+            bbox
+        }
+    }
+
+    fn build_child(items: &mut [(Arc<dyn Hittable>, BoundingBox)]) -> Arc<dyn Hittable> {
+        if items.len() == 1 {
+            items[0].0.clone()
+        } else {
+            Arc::new(Bvh::build(items))
+        }
+    }
+
+    fn sah_split(sorted: &[(Arc<dyn Hittable>, BoundingBox)], parent: &BoundingBox) -> usize {
+        // Returns the number of objects to put in the left child, choosing the split along the
+        // sorted objects that minimizes the surface area heuristic. Falls back to a median split
+        // when the boxes are degenerate.
+        let n = sorted.len();
+        let parent_area = parent.surface_area();
+        if !parent_area.is_finite() || parent_area <= 0. {
+            return n / 2;
+        }
+
+        // right_areas[i] is the area of the box enclosing objects i..n
+        let mut right_areas = vec![0.; n];
+        let mut acc = BoundingBox::default();
+        for i in (1..n).rev() {
+            acc = BoundingBox::new_enclosing(&acc, &sorted[i].1);
+            right_areas[i] = acc.surface_area();
+        }
+
+        let mut best_split = n / 2;
+        let mut best_cost = f64::INFINITY;
+        let mut acc = BoundingBox::default();
+        for i in 1..n {
+            acc = BoundingBox::new_enclosing(&acc, &sorted[i-1].1);
+            let cost = TRAVERSAL_COST
+                + (acc.surface_area() * i as f64 + right_areas[i] * (n - i) as f64) / parent_area;
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = i;
+            }
+        }
+        best_split
+    }
+}
+
+#[typetag::serde]
+impl Hittable for Bvh {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        if !self.bbox.hit_aabb(r, ray_t) {
+            return false;
+        }
+
+        let hit_left = self.left.hit(r, ray_t, rec);
+        let hit_right = self.right.hit(
+            r,
+            Interval::new_min_max(ray_t.min, if hit_left { rec.t } else { ray_t.max }),
+            rec
+        );
+
+        hit_left || hit_right
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.bbox.clone()
+    }
+
+    fn resolve_shared(&self, shared: &SharedObjects) -> Result<()> {
+        self.left.resolve_shared(shared)?;
+        self.right.resolve_shared(shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::rng;
+    use super::Bvh;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::hittable::{Hittable, HitRecord};
+    use crate::raytracer::hittable_list::HittableList;
+    use crate::raytracer::material::Lambertian;
+    use crate::raytracer::plane::Plane;
+    use crate::raytracer::quad::make_box;
+    use crate::raytracer::sphere::Sphere;
+
+    fn random_spheres(n: usize) -> HittableList {
+        let mut world = HittableList::new();
+        let mat = Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])));
+        for _ in 0..n {
+            let center = Point3::random_range(-10., 10.);
+            world.add(Arc::new(Sphere::new(&center, random_f64_range(0.1, 1.), mat.clone())));
+        }
+        world
+    }
+
+    #[test]
+    fn test_empty() {
+        let bvh = Bvh::new(&HittableList::new());
+        let r = Ray::new(Point3::new_xyz(0., 0., 0.), Vec3::new_xyz(0., 0., -1.));
+        assert!(!bvh.hit(&r, Interval::new_min_max(0.001, INFINITY), &mut HitRecord::default()));
+    }
+
+    fn assert_matches_linear_list(world: &HittableList) {
+        let bvh = Bvh::new(world);
+
+        for _ in 0..1000 {
+            let r = Ray::new(Point3::random_range(-15., 15.), random_unit_vector());
+            let ray_t = Interval::new_min_max(0.001, INFINITY);
+
+            let mut list_rec = HitRecord::default();
+            let mut bvh_rec = HitRecord::default();
+            let list_hit = world.hit(&r, ray_t, &mut list_rec);
+            let bvh_hit = bvh.hit(&r, ray_t, &mut bvh_rec);
+
+            assert_eq!(list_hit, bvh_hit);
+            if list_hit {
+                assert_eq!(list_rec.t, bvh_rec.t);
+                assert_eq!(list_rec.p, bvh_rec.p);
+            }
+        }
+    }
+
+    #[test]
+    fn test_matches_linear_list() {
+        rng::seed_thread(2);
+        assert_matches_linear_list(&random_spheres(200));
+    }
+
+    #[test]
+    fn test_unbounded_objects() {
+        rng::seed_thread(3);
+        let mut world = random_spheres(100);
+        let mat = Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])));
+        world.add(Arc::new(Plane::new(&Point3::new_xyz(0., -12., 0.), &Vec3::new_xyz(0., 1., 0.), mat.clone())));
+        world.add(Arc::new(Plane::new(&Point3::new_xyz(0., 0., 14.), &Vec3::new_xyz(1., 1., -1.), mat.clone())));
+        for object in make_box(&Point3::new_xyz(-3., -3., -3.), &Point3::new_xyz(2., 1., 4.), mat).iter() {
+            world.add(object.clone());
+        }
+        assert_matches_linear_list(&world);
+    }
+
+    #[test]
+    fn test_bounding_box_encloses_objects() {
+        rng::seed_thread(1);
+        let world = random_spheres(50);
+        let bvh = Bvh::new(&world);
+        let bbox = bvh.bounding_box();
+        for object in world.iter() {
+            let object_box = object.bounding_box();
+            for n in 0..3 {
+                assert!(bbox.axis_interval(n).min <= object_box.axis_interval(n).min);
+                assert!(bbox.axis_interval(n).max >= object_box.axis_interval(n).max);
+            }
+        }
+    }
+}
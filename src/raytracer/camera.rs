@@ -1,9 +1,10 @@
-use minifb::Window;
-use rand::seq::SliceRandom;
-use rand::RngCore;
-
 use crate::raytracer::prelude::*;
+use crate::raytracer::rng::{self, Rng};
+use crate::raytracer::sampler::{SampleId, Sampler, DIMS_PER_STREAM, LIGHT_DIM};
 use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::background::{Background, Gradient};
+use crate::raytracer::framebuffer::{FrameBuffer, ImageSink};
+use crate::raytracer::pdf::{power_heuristic, BackgroundPdf, HittablePdf, MixturePdf, Pdf};
 
 #[derive(Hash, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct PixelIndexEntry {
@@ -17,18 +18,58 @@ pub struct RayColorEntry {
     pub attenuation: Color,
     pub ray: Ray,
     pub depth: i32,
-    pub color: Color
+    pub color: Color,
+    // densities of the material and the lights for the direction of `ray`, to weigh the light it
+    // finds against the light samples taken at its origin
+    pub scatter_pdf: f64,
+    pub light_pdf: f64,
+    // light sample taken at the last hit, still to be traced through the scene
+    pub shadow: Option<ShadowRay>,
+    // what the camera ray hit first, None until it hits something
+    pub aov: Option<Aov>,
+    // see Camera::sample_id
+    pub sample: SampleId
 } 
 
 impl RayColorEntry {
-    pub fn new(ray: Ray, depth: i32) -> Self {
+    pub fn new(ray: Ray, depth: i32, sample: SampleId) -> Self {
         RayColorEntry {
             attenuation: Color::new([1., 1., 1.]),
             ray: ray,
             depth: depth,
-            color: Color::default()
+            color: Color::default(),
+            scatter_pdf: 0.,
+            light_pdf: 0.,
+            shadow: None,
+            aov: None,
+            sample
         }
     }
+
+    // Multiple importance sampling weight of light found along `ray`. Rays from the camera or
+    // from mirror-like materials don't compete with light samples, so they count fully.
+    fn emission_weight(&self) -> f64 {
+        if self.scatter_pdf == 0. { 1. } else { power_heuristic(self.scatter_pdf, self.light_pdf) }
+    }
+}
+
+// Ray towards a light, contributing `weight` times the light it reaches first.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ShadowRay {
+    pub ray: Ray,
+    pub weight: Color
+}
+
+// Auxiliary outputs (AOVs) describing the first surface a camera ray hits.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct Aov {
+    // distance from the camera
+    pub depth: f64,
+    // shading normal, facing the camera
+    pub normal: Vec3,
+    pub albedo: Color,
+    // see Tagged
+    pub object_id: u32
 }
 
 #[derive(Serialize, Deserialize, Clone, Default)]
@@ -38,12 +79,15 @@ pub struct RayColorStatus {
 }
 
 #[derive(Default, Serialize, Deserialize, Clone)]
+#[serde(default = "Camera::new")]
 pub struct Camera {
     pub aspect_ratio: f64,
     pub image_width: i32,
     pub samples_per_pixel: i32,
     pub max_depth: i32,
 
+    // how directions map to the image, vfov and the defocus settings only apply to Perspective
+    pub projection: Projection,
     pub vfov: f64,
     pub lookfrom: Point3,
     pub lookat: Point3,
@@ -52,6 +96,23 @@ pub struct Camera {
     pub defocus_angle: f64,
     pub focus_dist: f64,
 
+    // Rays are sent at random times between the shutter opening and closing, blurring objects
+    // that move in the meantime.
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+
+    // None keeps the sky gradient from Gradient::sky
+    pub background: Option<Arc<dyn Background>>,
+    // objects to sample explicitly as lights, usually the ones with a DiffuseLight material
+    pub lights: Option<Arc<dyn Hittable>>,
+    // None takes samples_per_pixel samples of every pixel
+    pub adaptive: Option<AdaptiveSampling>,
+    // Renders with the same seed come out the same, however they are split among threads and
+    // servers. The random scene generators use it too.
+    pub seed: u64,
+    // pattern of the random numbers of the samples of each pixel, None for plain random numbers
+    pub sampler: Option<Arc<dyn Sampler>>,
+
     image_height: i32,
     pub pixel_samples_scale: f64,
     center: Point3,
@@ -65,82 +126,129 @@ pub struct Camera {
     defocus_disk_v: Vec3,
 }
 
+// Projections relative to the view from lookfrom to lookat, with vup up.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum Projection {
+    // thin lens camera, see vfov, defocus_angle and focus_dist
+    #[default]
+    Perspective,
+    // parallel rays along the view direction, from a view `height` world units high
+    Orthographic { height: f64 },
+    // 360° panorama with longitude across and latitude down the image, which is made 2:1
+    Equirectangular,
+    // equidistant fisheye: the angle from the view direction grows linearly with the distance
+    // from the center of the image, up to fov / 2 degrees at the top and bottom edges
+    Fisheye { fov: f64 },
+    // six 90° views, with left, front and right in the top row and back, up and down in the
+    // bottom row, in a 3:2 image
+    CubeMap
+}
+
+impl Projection {
+    // Aspect ratio the projection requires, overriding Camera::aspect_ratio.
+    fn aspect_ratio(&self) -> Option<f64> {
+        match self {
+            Projection::Equirectangular => Some(2.),
+            Projection::CubeMap => Some(1.5),
+            _ => None
+        }
+    }
+}
+
+// Instead of taking samples_per_pixel samples of every pixel, keep sampling the pixels whose
+// error (see FrameBuffer::pixel_error) is still above `threshold`, one pass at a time, once they
+// have `min_samples`. No pixel gets more than `max_samples`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AdaptiveSampling {
+    pub threshold: f64,
+    pub min_samples: i32,
+    pub max_samples: i32
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        AdaptiveSampling { threshold: 0.01, min_samples: 8, max_samples: 256 }
+    }
+}
+
+impl AdaptiveSampling {
+    pub fn needs_samples(&self, frame: &FrameBuffer, i: usize, j: usize) -> bool {
+        let count = frame.sample_count(i, j);
+        count < self.max_samples && (count < self.min_samples || frame.pixel_error(i, j) > self.threshold)
+    }
+
+    // Pixels that need more samples, as (i, j).
+    pub fn active_pixels(&self, frame: &FrameBuffer) -> Vec<(usize, usize)> {
+        (0..frame.height())
+            .flat_map(|j| (0..frame.width()).map(move |i| (i, j)))
+            .filter(|&(i, j)| self.needs_samples(frame, i, j))
+            .collect()
+    }
+}
+
+// Rays for `passes` samples of each of `pixels`, in a new order every pass (the same for the
+// same seed). The sample numbers of a pixel start from its pixel_sample_num, and samples the
+// projection has no ray for come with None, see Camera::get_ray.
 pub struct CameraRayIterator<'a> {
     camera: &'a Camera,
-    i: i32,
-    n: i32,
-    shuffled_seq: Vec<i32>
+    pixels: Vec<PixelIndexEntry>,
+    passes: i32,
+    i: usize,
+    shuffled_seq: Vec<usize>
 }
 
 impl<'a> CameraRayIterator<'a> {
-    fn new(camera: &'a Camera) -> Self {
-        let n = camera.image_width * camera.image_height;
-        let shuffled = (0..n).collect::<Vec<i32>>();
+    fn new(camera: &'a Camera, pixels: Vec<PixelIndexEntry>, passes: i32) -> Self {
+        let shuffled = (0..pixels.len()).collect::<Vec<usize>>();
 
         CameraRayIterator {
             camera: camera,
+            pixels,
+            passes,
             i: 0,
-            n: n,
             shuffled_seq: shuffled
         }
     }
 }
 
 impl<'a> Iterator for CameraRayIterator<'a> {
-    type Item = (PixelIndexEntry, Ray);
+    type Item = (PixelIndexEntry, Option<Ray>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.i >= self.n {
+        let n = self.pixels.len();
+        if self.i >= n * self.passes.max(0) as usize {
             return None;
         }
-        if self.i % self.n == 0 {
-            self.shuffled_seq.shuffle(&mut rand::rng());
-        }
-        if self.i % self.camera.image_width == 0 {
-            println!("line {} / {} (sample {})", (self.i / self.camera.image_width) % self.camera.image_height, self.camera.image_height, self.i / self.n);
+        if self.i.is_multiple_of(n) {
+            let pass = (self.i / n) as u64;
+            rng::shuffle(&mut self.shuffled_seq, &mut Rng::new(rng::hash(&[self.camera.seed, pass, SHUFFLE_STREAM])));
         }
+        let pixel = &self.pixels[self.shuffled_seq[self.i % n]];
+        let pixel_sample_num = pixel.pixel_sample_num + (self.i / n) as i32;
+        let ray = self.camera.get_ray(pixel.pixel_i, pixel.pixel_j, pixel_sample_num);
 
-        let cur = self.shuffled_seq[(self.i % self.n) as usize];
-        let pixel_j = cur / self.camera.image_width;
-        let pixel_i = cur % self.camera.image_width;
-        let pixel_sample_num = self.i / self.n;
-        let ray = self.camera.get_ray(pixel_i, pixel_j);
-        
         self.i += 1;
         Some((PixelIndexEntry {
-            pixel_i: pixel_i,
-            pixel_j: pixel_j,
+            pixel_i: pixel.pixel_i,
+            pixel_j: pixel.pixel_j,
             pixel_sample_num: pixel_sample_num,
         }, ray))
     }
 }
 
-pub fn ray_color_iteration(r: &mut RayColorEntry, world: &dyn Hittable) -> RayColorStatus {
-    // performs a single iteration of ray_color
-    if r.depth <= 0 {
-        r.color = r.attenuation*Color::new([0.,0.,0.]);
-        return RayColorStatus{finished: true, hit_object_or_stop: true};
-    }
+// Stream id mixed into the keys of the order of the pixels.
+const SHUFFLE_STREAM: u64 = u64::MAX;
 
+// Light emitted by the closest object `r` hits within ray_t, or None if it doesn't hit `world`.
+// Object servers answer shadow ray queries with this.
+pub fn shadow_ray_color(r: &Ray, world: &dyn Hittable, ray_t: Interval) -> Option<Color> {
     let mut rec: HitRecord = HitRecord::default();
-    if world.hit(&r.ray, Interval::new_min_max(0.001, INFINITY), &mut rec) {
-        let mut scattered: Ray = Ray::default();
-        let mut attenuation: Color = Color::default();
-        if rec.mat.scatter(&r.ray, &rec, &mut attenuation, &mut scattered) {
-            r.attenuation = r.attenuation * attenuation;
-            r.ray = scattered;
-            r.depth -= 1;
-            return RayColorStatus{finished: false, hit_object_or_stop: true};
-        } else {
-            r.color = r.attenuation*Color::new([0.,0.,0.]);
-            return RayColorStatus{finished: true, hit_object_or_stop: true};
-        }
+    if world.hit(r, ray_t, &mut rec) {
+        Some(rec.mat.emitted(rec.u, rec.v, &rec.p))
+    } else {
+        None
     }
-
-    let unit_direction: Vec3 = unit_vector(r.ray.direction());
-    let a = 0.5*(unit_direction.y() + 1.0);
-    r.color = r.attenuation *((1.0-a)*Color::new([1.0, 1.0, 1.0]) + a*Color::new([0.5, 0.7, 1.0]));
-    return RayColorStatus{finished: true, hit_object_or_stop: false};
 }
 
 impl Camera {
@@ -159,16 +267,27 @@ impl Camera {
         camera.defocus_angle = 0.;
         camera.focus_dist = 10.;
 
+        camera.shutter_open = 0.;
+        camera.shutter_close = 0.;
+
         camera
     }
 
-    pub fn iterate_rays(&self) -> CameraRayIterator {
-        CameraRayIterator::new(&self)
+    pub fn iterate_rays(&self) -> CameraRayIterator<'_> {
+        let pixels = (0..self.image_height)
+            .flat_map(|pixel_j| (0..self.image_width).map(move |pixel_i| PixelIndexEntry { pixel_i, pixel_j, pixel_sample_num: 0 }))
+            .collect();
+        CameraRayIterator::new(self, pixels, self.samples_per_pixel)
+    }
+
+    pub fn iterate_pixel_rays(&self, pixels: Vec<PixelIndexEntry>, passes: i32) -> CameraRayIterator<'_> {
+        CameraRayIterator::new(self, pixels, passes)
     }
 
     pub fn initialize(&mut self) {
         // Calculate the image height, and ensure that it's at least 1.
-        self.image_height = (self.image_width as f64 / self.aspect_ratio) as i32;
+        let aspect_ratio = self.projection.aspect_ratio().unwrap_or(self.aspect_ratio);
+        self.image_height = (self.image_width as f64 / aspect_ratio) as i32;
         self.image_height = if self.image_height < 1 { 1 } else { self.image_height };
 
         self.pixel_samples_scale = 1.0 / self.samples_per_pixel as f64;
@@ -205,49 +324,128 @@ impl Camera {
         self.defocus_disk_v = self.v * defocus_radius;
     }
 
+    pub fn image_height(&self) -> i32 {
+        self.image_height
+    }
+
     pub fn render(
-        &mut self, world: &dyn Hittable, 
-        window: &mut Window, 
-        color_buffer: &mut Vec<u32>,
-        raw_buffer: &mut Vec<Vec3>,
-        count_buffer: &mut Vec<i32>
+        &mut self, world: &dyn Hittable,
+        frame: &mut FrameBuffer,
+        sinks: &mut [Box<dyn ImageSink>]
     ) -> Result<()> {
         self.initialize();
 
         for sample in 0..self.samples_per_pixel {
             for j in 0..self.image_height {
+                println!("line {} / {} (sample {})", j, self.image_height, sample);
                 for i in 0..self.image_width {
-                    if i == 0 {
-                        println!("line {} / {} (sample {})", j, self.image_height, sample);
+                    let sample = self.trace_pixel(i, j, sample, world);
+                    frame.add_sample(i as usize, j as usize, &sample.color);
+                    if let Some(aov) = &sample.aov {
+                        frame.add_aov(i as usize, j as usize, aov);
                     }
-                    let mut pixel_color: Color = Color::new_xyz(0.,0.,0.);
-                    let r: Ray = self.get_ray(i, j);
-                    pixel_color += self.ray_color(&r, self.max_depth, world);
-                    write_color(
-                        i, j, 
-                        self.image_width as usize, 
-                        self.image_height as usize, 
-                        &pixel_color, window, color_buffer, raw_buffer, count_buffer
-                    )?;
+                }
+                for sink in sinks.iter_mut() {
+                    sink.update(frame)?;
                 }
             }
+            frame.finish_pass();
+        }
+
+        frame.finish();
+        for sink in sinks.iter_mut() {
+            sink.finish(frame)?;
         }
         Ok(())
     }
 
-    fn get_ray(&self, i: i32, j: i32) -> Ray {
+    // Identifies the random numbers taken for sample number `sample` of pixel (i, j).
+    pub fn sample_id(&self, i: i32, j: i32, sample: i32) -> SampleId {
+        let count = match &self.adaptive {
+            Some(adaptive) => adaptive.max_samples,
+            None => self.samples_per_pixel
+        };
+        SampleId { pixel: rng::hash(&[self.seed, i as u64, j as u64]), index: sample as u32, count: count as u32 }
+    }
+
+    pub(crate) fn get_ray(&self, i: i32, j: i32, sample: i32) -> Option<Ray> {
         // Construct a camera ray originating from the defocus disk and directed at a randomly
-        // sampled point around the pixel location i, j.
+        // sampled point around the pixel location i, j. None where the projection doesn't cover
+        // the sampled point, like the corners of a fisheye image.
+        rng::with_sampler(self.sampler.as_ref(), self.sample_id(i, j, sample), 0, || self.sample_ray(i, j))
+    }
+
+    // Traces sample number `sample` of pixel (i, j), which is black if there's no ray for it.
+    pub(crate) fn trace_pixel(&self, i: i32, j: i32, sample: i32, world: &dyn Hittable) -> RayColorEntry {
+        let id = self.sample_id(i, j, sample);
+        match self.get_ray(i, j, sample) {
+            Some(r) => self.trace_sample(&r, self.max_depth, id, world),
+            None => RayColorEntry::new(Ray::default(), 0, id)
+        }
+    }
 
+    fn sample_ray(&self, i: i32, j: i32) -> Option<Ray> {
         let offset = self.sample_square();
-        let pixel_sample = self.pixel00_loc
-                          + ((i as f64 + offset.x()) * self.pixel_delta_u)
-                          + ((j as f64 + offset.y()) * self.pixel_delta_v);
+        // position on the image, from (0, 0) at the top left to (1, 1) at the bottom right
+        let x = (i as f64 + 0.5 + offset.x()) / self.image_width as f64;
+        let y = (j as f64 + 0.5 + offset.y()) / self.image_height as f64;
+        let (ray_origin, ray_direction) = self.project(x, y)?;
+        let ray_time = self.shutter_open + random_f64() * (self.shutter_close - self.shutter_open);
 
-        let ray_origin =  if self.defocus_angle <= 0. { self.center } else { self.defocus_disk_sample() };
-        let ray_direction = pixel_sample - ray_origin;
+        Some(Ray::new_time(ray_origin, ray_direction, ray_time))
+    }
 
-        Ray::new(ray_origin, ray_direction)
+    // Origin and direction of the ray through image position (x, y), see sample_ray.
+    fn project(&self, x: f64, y: f64) -> Option<(Point3, Vec3)> {
+        let aspect_ratio = self.image_width as f64 / self.image_height as f64;
+        // camera space to world space, with -z forward
+        let to_world = |v: Vec3| v.x() * self.u + v.y() * self.v + v.z() * self.w;
+        match &self.projection {
+            Projection::Perspective => {
+                let pixel_sample = self.pixel00_loc
+                                  + ((x * self.image_width as f64 - 0.5) * self.pixel_delta_u)
+                                  + ((y * self.image_height as f64 - 0.5) * self.pixel_delta_v);
+                let ray_origin =  if self.defocus_angle <= 0. { self.center } else { self.defocus_disk_sample() };
+                Some((ray_origin, pixel_sample - ray_origin))
+            }
+            Projection::Orthographic { height } => {
+                let offset = (x - 0.5) * height * aspect_ratio * self.u + (0.5 - y) * height * self.v;
+                Some((self.center + offset, -self.w))
+            }
+            Projection::Equirectangular => {
+                let longitude = (x - 0.5) * 2. * PI;
+                let latitude = (0.5 - y) * PI;
+                let direction = Vec3::new_xyz(
+                    latitude.cos() * longitude.sin(), latitude.sin(), -latitude.cos() * longitude.cos());
+                Some((self.center, to_world(direction)))
+            }
+            Projection::Fisheye { fov } => {
+                let (a, b) = ((2. * x - 1.) * aspect_ratio, 1. - 2. * y);
+                let theta = f64::sqrt(a * a + b * b) * degrees_to_radians(*fov) / 2.;
+                // the image is wider than the circle the fisheye sees
+                if theta > degrees_to_radians(*fov) / 2. {
+                    return None;
+                }
+                let phi = f64::atan2(b, a);
+                let direction = Vec3::new_xyz(theta.sin() * phi.cos(), theta.sin() * phi.sin(), -theta.cos());
+                Some((self.center, to_world(direction)))
+            }
+            Projection::CubeMap => {
+                let (face_x, face_y) = (f64::min((x * 3.).floor(), 2.), f64::min((y * 2.).floor(), 1.));
+                let (s, t) = (2. * (x * 3. - face_x) - 1., 1. - 2. * (y * 2. - face_y));
+                let (right, up, back) = (self.u, self.v, self.w);
+                // the direction each face looks in, and its right and up directions
+                let (forward, face_right, face_up) = match (face_x as i32, face_y as i32) {
+                    (0, 0) => (-right, -back, up),
+                    (1, 0) => (-back, right, up),
+                    (2, 0) => (right, back, up),
+                    (0, _) => (back, -right, up),
+                    (1, _) => (up, right, back),
+                    _ => (-up, right, -back)
+                };
+                Some((self.center, forward + s * face_right + t * face_up))
+            }
+        }
     }
 
     fn sample_square(&self) -> Vec3 {
@@ -261,23 +459,275 @@ impl Camera {
         return self.center + (p[0] * self.defocus_disk_u) + (p[1] * self.defocus_disk_v);
     }
 
-    fn ray_color(&self, r: &Ray, depth: i32, world: &dyn Hittable) -> Color {
-        if depth <= 0 {
-            return Color::new([0.,0.,0.]);
+    // Follows `r` through `world` until its color is known, returning the finished entry with
+    // the color and AOVs of the sample.
+    pub(crate) fn trace_sample(&self, r: &Ray, depth: i32, sample: SampleId, world: &dyn Hittable) -> RayColorEntry {
+        // The same steps the ray servers take, with the whole world at hand.
+        let ray_t = Interval::new_min_max(0.001, f64::INFINITY);
+        let mut entry = RayColorEntry::new(r.clone(), depth, sample);
+        loop {
+            let status = self.ray_color_iteration(&mut entry, world, ray_t);
+            if let Some(shadow) = &entry.shadow {
+                let light = shadow_ray_color(&shadow.ray, world, ray_t);
+                self.apply_shadow_ray(&mut entry, light);
+            }
+            if !status.hit_object_or_stop {
+                self.apply_background(&mut entry);
+            }
+            if status.finished {
+                return entry;
+            }
+        }
+    }
+
+    // Directions towards the scene lights, and the background if it's worth sampling, from
+    // `origin` at `time`. Empty if there's nothing to sample.
+    fn light_pdf(&self, origin: &Point3, time: f64) -> MixturePdf<'_> {
+        let mut pdfs: Vec<Box<dyn Pdf + '_>> = Vec::new();
+        if let Some(lights) = &self.lights {
+            pdfs.push(Box::new(HittablePdf::new(lights.as_ref(), origin, time)));
+        }
+        if let Some(background) = self.background.as_ref().filter(|background| background.sampled_as_light()) {
+            pdfs.push(Box::new(BackgroundPdf::new(background.as_ref())));
+        }
+        MixturePdf::new(pdfs)
+    }
+
+    // Performs a single iteration of trace_sample, accumulating emitted light into r.color.
+    // A ray that misses `world` is left untouched with hit_object_or_stop unset, since the
+    // caller may still have to check other objects before falling back on Camera::background.
+    //
+    // Hitting a diffuse material also samples a direction towards the lights, left in r.shadow
+    // for the caller to trace through the whole scene. Light reached both ways is weighed with
+    // multiple importance sampling, so neither counts twice.
+    //
+    // The random numbers come from the dimensions of the sample for this bounce, so the bounce
+    // scatters the same way on any server.
+    pub fn ray_color_iteration(&self, r: &mut RayColorEntry, world: &dyn Hittable, ray_t: Interval) -> RayColorStatus {
+        // the camera ray takes the first dimensions
+        let first_dim = DIMS_PER_STREAM * (1 + (self.max_depth - r.depth).max(0) as u32);
+        rng::with_sampler(self.sampler.as_ref(), r.sample, first_dim, || self.bounce(r, world, ray_t))
+    }
+
+    fn bounce(&self, r: &mut RayColorEntry, world: &dyn Hittable, ray_t: Interval) -> RayColorStatus {
+        if r.depth <= 0 {
+            return RayColorStatus{finished: true, hit_object_or_stop: true};
         }
 
         let mut rec: HitRecord = HitRecord::default();
-        if world.hit(r, Interval::new_min_max(0.001, INFINITY), &mut rec) {
-            let mut scattered: Ray = Ray::default();
-            let mut attenuation: Color = Color::default();
-            if rec.mat.scatter(r, &rec, &mut attenuation, &mut scattered) {
-                return attenuation * self.ray_color(&scattered, depth-1, world);
+        if !world.hit(&r.ray, ray_t, &mut rec) {
+            return RayColorStatus{finished: true, hit_object_or_stop: false};
+        }
+
+        if r.aov.is_none() {
+            // Nothing was hit before, so this is the first hit of the camera ray.
+            r.aov = Some(Aov {
+                depth: rec.t * r.ray.direction().length(),
+                normal: rec.normal,
+                albedo: rec.mat.albedo(&rec),
+                object_id: rec.object_id
+            });
+        }
+
+        r.color += r.attenuation * rec.mat.emitted(rec.u, rec.v, &rec.p) * r.emission_weight();
+
+        let mut scattered: Ray = Ray::default();
+        let mut attenuation: Color = Color::default();
+        if !rec.mat.scatter(&r.ray, &rec, &mut attenuation, &mut scattered) {
+            return RayColorStatus{finished: true, hit_object_or_stop: true};
+        }
+
+        let scatter_pdf = rec.mat.scattering_pdf(&r.ray, &rec, &scattered);
+        let light_pdf = self.light_pdf(&rec.p, r.ray.time());
+        rng::skip_to(LIGHT_DIM);
+        if scatter_pdf > 0. && !light_pdf.is_empty() {
+            let light_ray = Ray::new_time(rec.p, light_pdf.generate(), r.ray.time());
+            let pdf = light_pdf.value(light_ray.direction());
+            if pdf > 0. {
+                let weight = power_heuristic(pdf, rec.mat.scattering_pdf(&r.ray, &rec, &light_ray)) / pdf;
+                r.shadow = Some(ShadowRay {
+                    weight: r.attenuation * rec.mat.scattering_value(&r.ray, &rec, &light_ray) * weight,
+                    ray: light_ray
+                });
             }
-            return Color::new([0.,0.,0.]);
         }
 
-        let unit_direction: Vec3 = unit_vector(r.direction());
-        let a = 0.5*(unit_direction.y() + 1.0);
-        (1.0-a)*Color::new([1.0, 1.0, 1.0]) + a*Color::new([0.5, 0.7, 1.0])
+        r.scatter_pdf = scatter_pdf;
+        r.light_pdf = if scatter_pdf > 0. { light_pdf.value(scattered.direction()) } else { 0. };
+        r.attenuation = r.attenuation * attenuation;
+        r.ray = scattered;
+        r.depth -= 1;
+        RayColorStatus{finished: false, hit_object_or_stop: true}
+    }
+
+    pub fn background_color(&self, r: &Ray) -> Color {
+        // Light arriving along rays that escape the scene.
+        match &self.background {
+            Some(background) => background.color(r),
+            None => Gradient::sky().color(r)
+        }
+    }
+
+    // Adds the background seen by a ray that escaped the scene to its accumulated color, the
+    // final step of the ray_color_iteration loop.
+    pub fn apply_background(&self, r: &mut RayColorEntry) {
+        r.color += r.attenuation * self.background_color(&r.ray) * r.emission_weight();
+    }
+
+    // Adds the light reached by the shadow ray of `r`, given what shadow_ray_color found along
+    // it, with None meaning the shadow ray escaped to the background.
+    pub fn apply_shadow_ray(&self, r: &mut RayColorEntry, light: Option<Color>) {
+        if let Some(shadow) = r.shadow.take() {
+            let light = light.unwrap_or_else(|| self.background_color(&shadow.ray));
+            r.color += shadow.weight * light;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdaptiveSampling, Camera, Projection};
+    use crate::raytracer::sampler::SampleId;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::background::Constant;
+    use crate::raytracer::constant_medium::ConstantMedium;
+    use crate::raytracer::framebuffer::FrameBuffer;
+    use crate::raytracer::hittable::Hittable;
+    use crate::raytracer::hittable_list::HittableList;
+    use crate::raytracer::material::{Dialectric, DiffuseLight, Isotropic, Lambertian};
+    use crate::raytracer::quad::Quad;
+    use crate::raytracer::sphere::Sphere;
+
+    #[test]
+    fn test_light_sampling_is_unbiased() {
+        // A small light above a diffuse floor, seen straight down with a single bounce. Sampling
+        // the light explicitly has to converge to the same brightness as pure path tracing.
+        let floor = Arc::new(Quad::new(
+            &Point3::new_xyz(-50., 0., -50.), &Vec3::new_xyz(100., 0., 0.), &Vec3::new_xyz(0., 0., 100.),
+            Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])))));
+        let light: Arc<dyn Hittable> = Arc::new(Quad::new(
+            &Point3::new_xyz(-0.5, 2., -0.5), &Vec3::new_xyz(1., 0., 0.), &Vec3::new_xyz(0., 0., 1.),
+            Arc::new(DiffuseLight::new(&Color::new([4., 4., 4.])))));
+        let world = HittableList::new_w_objs(vec![floor, light.clone()]);
+
+        let mut camera = Camera::new();
+        camera.background = Some(Arc::new(Constant::new(&Color::default())));
+        let r = Ray::new(Point3::new_xyz(0.3, 1., 0.), Vec3::new_xyz(0., -1., 0.));
+        let mean = |camera: &Camera, n: usize| {
+            (0..n).map(|k| camera.trace_sample(&r, 2, SampleId { pixel: k as u64, index: 0, count: 1 }, &world).color.x()).sum::<f64>() / n as f64
+        };
+
+        let path_traced = mean(&camera, 200_000);
+        camera.lights = Some(Arc::new(HittableList::new_w_obj(light)));
+        let light_sampled = mean(&camera, 20_000);
+        assert!((path_traced - light_sampled).abs() < 0.01, "{} vs {}", path_traced, light_sampled);
+    }
+
+    #[test]
+    fn test_adaptive_sampling() {
+        // A softly lit floor in the lower half of the image and an empty black sky above it,
+        // which has no noise at all.
+        let floor = Arc::new(Quad::new(
+            &Point3::new_xyz(-50., -1., -50.), &Vec3::new_xyz(100., 0., 0.), &Vec3::new_xyz(0., 0., 100.),
+            Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])))));
+        let light: Arc<dyn Hittable> = Arc::new(Quad::new(
+            &Point3::new_xyz(-1., 3., -3.), &Vec3::new_xyz(2., 0., 0.), &Vec3::new_xyz(0., 0., 2.),
+            Arc::new(DiffuseLight::new(&Color::new([4., 4., 4.])))));
+        let world = HittableList::new_w_objs(vec![floor, light.clone()]);
+
+        let mut camera = Camera::new();
+        camera.image_width = 16;
+        camera.max_depth = 3;
+        camera.background = Some(Arc::new(Constant::new(&Color::default())));
+        camera.lights = Some(light);
+        camera.adaptive = Some(AdaptiveSampling { threshold: 0.005, min_samples: 4, max_samples: 64 });
+        let mut frame = FrameBuffer::new(16, 16);
+        camera.render_parallel(&world, &mut frame, &mut [], 2).unwrap();
+
+        // the sky is done after the minimum, while parts of the floor need more
+        assert!((0..16).all(|i| frame.sample_count(i, 0) == 4));
+        assert!((0..16).map(|i| frame.sample_count(i, 15)).sum::<i32>() > 16 * 4);
+        assert!((0..16).all(|j| (0..16).all(|i| frame.sample_count(i, j) <= 64)));
+    }
+
+    #[test]
+    fn test_projections() {
+        // Looking down -z from the origin, with y up.
+        let camera_with = |projection: Projection| {
+            let mut camera = Camera::new();
+            camera.image_width = 60;
+            camera.projection = projection;
+            camera.initialize();
+            camera
+        };
+        let direction = |camera: &Camera, x: f64, y: f64| unit_vector(&camera.project(x, y).unwrap().1);
+        let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-9;
+        let (right, up, forward) = (Vec3::new_xyz(1., 0., 0.), Vec3::new_xyz(0., 1., 0.), Vec3::new_xyz(0., 0., -1.));
+
+        let camera = camera_with(Projection::Orthographic { height: 4. });
+        let (origin, dir) = camera.project(1., 0.).unwrap();
+        assert!(close(dir, forward));
+        assert!(close(origin, Point3::new_xyz(2., 2., 0.)));
+
+        let camera = camera_with(Projection::Equirectangular);
+        assert_eq!(camera.image_height(), 30);
+        assert!(close(direction(&camera, 0.5, 0.5), forward));
+        assert!(close(direction(&camera, 0.75, 0.5), right));
+        assert!(close(direction(&camera, 0., 0.5), -forward));
+        assert!(close(direction(&camera, 0.3, 0.), up));
+
+        let camera = camera_with(Projection::Fisheye { fov: 180. });
+        assert!(close(direction(&camera, 0.5, 0.5), forward));
+        assert!(close(direction(&camera, 0.5, 0.), up));
+        assert!(close(direction(&camera, 0.5 + 0.5 / camera.aspect_ratio, 0.5), right));
+        // beyond the circle the fisheye sees, the samples are black
+        assert!(camera.project(0.1, 0.1).is_none());
+        assert!(camera.project(0., 0.).is_none());
+        let sample = camera.trace_pixel(0, 0, 0, &HittableList::new());
+        assert_eq!((sample.color.x(), sample.color.y(), sample.color.z()), (0., 0., 0.));
+
+        // face centers, and the edge between the front and right faces
+        let camera = camera_with(Projection::CubeMap);
+        assert_eq!(camera.image_height(), 40);
+        let faces = [(-right, 0, 0), (forward, 1, 0), (right, 2, 0), (-forward, 0, 1), (up, 1, 1), (-up, 2, 1)];
+        for (expected, face_x, face_y) in faces {
+            assert!(close(direction(&camera, (face_x as f64 + 0.5) / 3., (face_y as f64 + 0.5) / 2.), expected));
+        }
+        assert!(close(direction(&camera, 2. / 3., 0.25), unit_vector(&(right + forward))));
+    }
+
+    #[test]
+    fn test_renders_are_reproducible() {
+        // A glass ball in fog over a floor, so that every kind of random decision comes up.
+        let floor = Arc::new(Quad::new(
+            &Point3::new_xyz(-50., -1., -50.), &Vec3::new_xyz(100., 0., 0.), &Vec3::new_xyz(0., 0., 100.),
+            Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])))));
+        let ball = Arc::new(Sphere::new(&Point3::new_xyz(0., 0., -2.), 0.8, Arc::new(Dialectric::new(1.5))));
+        let fog = Arc::new(ConstantMedium::new(
+            Arc::new(Sphere::new(&Point3::new_xyz(0., 0., -2.), 3., Arc::new(Dialectric::new(1.)))),
+            0.2, Arc::new(Isotropic::new(&Color::new([0.9, 0.9, 0.9])))));
+        let light: Arc<dyn Hittable> = Arc::new(Quad::new(
+            &Point3::new_xyz(-1., 3., -3.), &Vec3::new_xyz(2., 0., 0.), &Vec3::new_xyz(0., 0., 2.),
+            Arc::new(DiffuseLight::new(&Color::new([4., 4., 4.])))));
+        let world = HittableList::new_w_objs(vec![floor, ball, fog, light.clone()]);
+
+        let render = |seed: u64, num_threads: usize| {
+            let mut camera = Camera::new();
+            camera.image_width = 40;
+            camera.samples_per_pixel = 3;
+            camera.lights = Some(light.clone());
+            camera.seed = seed;
+            let mut frame = FrameBuffer::new(40, 40);
+            camera.render_parallel(&world, &mut frame, &mut [], num_threads).unwrap();
+            (0..40).flat_map(|j| (0..40).map(move |i| (i, j)))
+                .map(|(i, j)| frame.pixel_color(i, j))
+                .collect::<Vec<Color>>()
+        };
+
+        // 40 pixels take two tiles, so the threads race for them
+        let bits = |colors: &[Color]| colors.iter().flat_map(|c| (0..3).map(|n| c[n].to_bits())).collect::<Vec<u64>>();
+        let image = bits(&render(1, 1));
+        assert_eq!(image, bits(&render(1, 4)));
+        assert_ne!(image, bits(&render(2, 4)));
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,3 @@
-use minifb::Window;
-
 use crate::raytracer::prelude::*;
 
 pub type Color = Vec3;
@@ -23,26 +21,9 @@ pub fn color_to_rgb(pixel_color: &Color) -> (u32, u32, u32) {
     (rbyte, gbyte, bbyte)
 }
 
-pub fn write_color(
-    i: i32,
-    j: i32,
-    width: usize,
-    height: usize,
-    pixel_color: &Color,
-    window: &mut Window, 
-    color_buffer: &mut Vec<u32>,
-    raw_buffer: &mut Vec<Vec3>,
-    count_buffer: &mut Vec<i32>
-) -> Result<()> {
-    let index = j as usize * width + i as usize;
-    raw_buffer[index] += *pixel_color;
-    count_buffer[index] += 1;
-    let denom = if count_buffer[index] != 0 {count_buffer[index] as f64} else {1.};
-    let (rbyte, gbyte, bbyte) = color_to_rgb(&(raw_buffer[index] / denom));
-    let color: u32 = (255 << 24) | (rbyte << 16) | (gbyte << 8) | bbyte;
-    color_buffer[index] = color;
-    window.update_with_buffer(&color_buffer, width, height);
-    Ok(())
+pub fn color_to_u32(pixel_color: &Color) -> u32 {
+    let (rbyte, gbyte, bbyte) = color_to_rgb(pixel_color);
+    (255 << 24) | (rbyte << 16) | (gbyte << 8) | bbyte
 }
 
 pub fn linear_to_gamma(linear_component: f64) -> f64
@@ -52,4 +33,19 @@ pub fn linear_to_gamma(linear_component: f64) -> f64
     }
 
     return 0.;
-}
\ No newline at end of file
+}
+
+// Bytes for a value in [0, 1] that isn't a color, such as a normal, so it skips the gamma
+// transform.
+pub fn value_to_rgb(value: &Vec3) -> (u32, u32, u32) {
+    let rbyte = (255.999 * INTENSITY.clamp(value.x())) as u32;
+    let gbyte = (255.999 * INTENSITY.clamp(value.y())) as u32;
+    let bbyte = (255.999 * INTENSITY.clamp(value.z())) as u32;
+
+    (rbyte, gbyte, bbyte)
+}
+
+// Perceived brightness of a linear color (Rec. 709 weights).
+pub fn luminance(color: &Color) -> f64 {
+    0.2126 * color.x() + 0.7152 * color.y() + 0.0722 * color.z()
+}
@@ -0,0 +1,122 @@
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::shared::SharedObjects;
+use crate::raytracer::material::Material;
+use crate::raytracer::rng::{self, Rng};
+
+// Volume of constant density filling the inside of `boundary`, which must be closed and convex.
+// Rays scatter at an exponentially distributed distance inside the volume, per
+// https://raytracing.github.io/books/RayTracingTheNextWeek.html
+//
+// The scattering distance is measured from where the ray enters the volume, with a random number
+// keyed by the ray itself, and the hit only counts if it falls within ray_t. So checking the
+// pieces of a ray separately (as object servers do for the segment of the ray inside their
+// region) finds the same scattering point as checking it in one go, and shadow rays find the
+// same as well.
+#[derive(Serialize, Deserialize)]
+pub struct ConstantMedium {
+    boundary: Arc<dyn Hittable>,
+    neg_inv_density: f64,
+    phase_function: Arc<dyn Material>
+}
+
+impl ConstantMedium {
+    pub fn new(boundary: Arc<dyn Hittable>, density: f64, phase_function: Arc<dyn Material>) -> Self {
+        ConstantMedium { boundary, neg_inv_density: -1. / density, phase_function }
+    }
+
+    // Uniform in [0, 1), always the same for the same ray.
+    fn ray_random(r: &Ray) -> f64 {
+        let (o, d) = (r.origin(), r.direction());
+        let key = rng::hash(&[
+            o.x().to_bits(), o.y().to_bits(), o.z().to_bits(),
+            d.x().to_bits(), d.y().to_bits(), d.z().to_bits(),
+            r.time().to_bits()
+        ]);
+        Rng::new(key).next_f64()
+    }
+}
+
+#[typetag::serde]
+impl Hittable for ConstantMedium {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        // Find where the ray enters and leaves the boundary, even outside of ray_t.
+        let mut rec1 = HitRecord::default();
+        let mut rec2 = HitRecord::default();
+        if !self.boundary.hit(r, Interval::UNIVERSE, &mut rec1) {
+            return false;
+        }
+        if !self.boundary.hit(r, Interval::new_min_max(rec1.t + 0.0001, f64::INFINITY), &mut rec2) {
+            return false;
+        }
+
+        let t_enter = f64::max(rec1.t, 0.);
+        if t_enter >= rec2.t || t_enter > ray_t.max || rec2.t < ray_t.min {
+            return false;
+        }
+
+        let ray_length = r.direction().length();
+        let hit_distance = self.neg_inv_density * Self::ray_random(r).ln();
+        let t = t_enter + hit_distance / ray_length;
+        if t >= rec2.t || !ray_t.contains(t) {
+            return false;
+        }
+
+        rec.t = t;
+        rec.p = r.at(rec.t);
+        // arbitrary, the phase function doesn't use them
+        rec.normal = Vec3::new_xyz(1., 0., 0.);
+        rec.front_face = true;
+        rec.u = 0.;
+        rec.v = 0.;
+        rec.mat = self.phase_function.clone();
+        true
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.boundary.bounding_box()
+    }
+
+    fn resolve_shared(&self, shared: &SharedObjects) -> Result<()> {
+        self.boundary.resolve_shared(shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::rng;
+    use super::ConstantMedium;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::hittable::{Hittable, HitRecord};
+    use crate::raytracer::material::{Isotropic, Lambertian};
+    use crate::raytracer::quad::make_box;
+
+    // Fraction of parallel rays through the unit thick slab x in [0, 1] that scatter, checking
+    // the slab either in one go or split into `pieces` consecutive segments.
+    fn scatter_fraction(pieces: usize) -> f64 {
+        let white = Arc::new(Lambertian::new(&Color::new([1., 1., 1.])));
+        let slab = Arc::new(make_box(&Point3::new_xyz(0., -10., -10.), &Point3::new_xyz(1., 10., 10.), white));
+        let medium = ConstantMedium::new(slab, 0.7, Arc::new(Isotropic::new(&Color::new([1., 1., 1.]))));
+
+        let n = 100000;
+        let scattered = (0..n).filter(|_| {
+            let origin = Point3::new_xyz(-1., random_f64_range(-5., 5.), random_f64_range(-5., 5.));
+            let r = Ray::new(origin, Vec3::new_xyz(2., 0., 0.));
+            (0..pieces).any(|i| {
+                let ray_t = Interval::new_min_max(i as f64 / pieces as f64 * 2., (i + 1) as f64 / pieces as f64 * 2.);
+                medium.hit(&r, ray_t, &mut HitRecord::default())
+            })
+        }).count();
+        scattered as f64 / n as f64
+    }
+
+    #[test]
+    fn test_split_segments() {
+        rng::seed_thread(1);
+        // 1 - e^(-density * thickness)
+        let expected = 1. - f64::exp(-0.7);
+        assert!((scatter_fraction(1) - expected).abs() < 0.01);
+        assert!((scatter_fraction(3) - expected).abs() < 0.01);
+    }
+}
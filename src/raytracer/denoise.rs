@@ -0,0 +1,145 @@
+//! Edge-avoiding à-trous wavelet filter, after Dammertz et al., "Edge-Avoiding À-Trous Wavelet
+//! Transform for fast Global Illumination Filtering" (2010).
+//!
+//! Each iteration blurs the image with a 5x5 B3 spline kernel whose taps are spread twice as far
+//! apart as in the iteration before, so a few iterations cover a wide footprint. Taps across edges
+//! in the color or in the AOVs (normal, albedo, object id) get little or no weight. Colors are
+//! divided by the albedo while filtering, so textures stay sharp.
+
+use crate::raytracer::prelude::*;
+use crate::raytracer::camera::Aov;
+use crate::raytracer::framebuffer::FrameBuffer;
+
+const KERNEL: [f64; 3] = [3. / 8., 1. / 4., 1. / 16.];
+
+#[derive(Clone)]
+pub struct Denoiser {
+    pub iterations: u32,
+    // How quickly the weights fall off with the difference to the center pixel. Color differences
+    // are relative to the brightness of the two pixels, so dark and bright areas blur alike.
+    pub sigma_color: f64,
+    pub sigma_normal: f64,
+    pub sigma_albedo: f64
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Denoiser { iterations: 5, sigma_color: 1., sigma_normal: 0.3, sigma_albedo: 0.2 }
+    }
+}
+
+impl Denoiser {
+    pub fn new(iterations: u32, sigma_color: f64, sigma_normal: f64, sigma_albedo: f64) -> Self {
+        Denoiser { iterations, sigma_color, sigma_normal, sigma_albedo }
+    }
+
+    // Denoised pixel colors of `frame`, in row order.
+    pub fn denoise(&self, frame: &FrameBuffer) -> Vec<Color> {
+        let (width, height) = (frame.width(), frame.height());
+        let aovs: Vec<Aov> = (0..height).flat_map(|j| (0..width).map(move |i| frame.pixel_aov(i, j))).collect();
+        let mut colors: Vec<Color> = (0..height)
+            .flat_map(|j| (0..width).map(move |i| frame.pixel_color(i, j)))
+            .zip(&aovs)
+            .map(|(color, aov)| demodulate(&color, &aov.albedo))
+            .collect();
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            colors = (0..width * height).map(|index| {
+                let (i, j) = ((index % width) as i64, (index / width) as i64);
+                let (color, aov) = (&colors[index], &aovs[index]);
+                let mut sum = Color::default();
+                let mut weight_sum = 0.;
+                for dj in -2i64..=2 {
+                    for di in -2i64..=2 {
+                        let (qi, qj) = (i + di * step, j + dj * step);
+                        if qi < 0 || qj < 0 || qi >= width as i64 || qj >= height as i64 {
+                            continue;
+                        }
+                        let q = qj as usize * width + qi as usize;
+                        let weight = KERNEL[di.unsigned_abs() as usize] * KERNEL[dj.unsigned_abs() as usize]
+                            * self.edge_weight(color, &colors[q], aov, &aovs[q]);
+                        sum += weight * colors[q];
+                        weight_sum += weight;
+                    }
+                }
+                // the center tap always has full edge weight, so weight_sum > 0
+                sum / weight_sum
+            }).collect();
+        }
+
+        colors.iter().zip(&aovs).map(|(color, aov)| remodulate(color, &aov.albedo)).collect()
+    }
+
+    // How much pixel q, with color `c_q` and AOVs `a_q`, counts towards the center pixel p.
+    fn edge_weight(&self, c_p: &Color, c_q: &Color, a_p: &Aov, a_q: &Aov) -> f64 {
+        if a_p.object_id != a_q.object_id {
+            return 0.;
+        }
+        let brightness = (c_p.length_squared() + c_q.length_squared()) / 2. + 1e-6;
+        let color_distance = (*c_p - *c_q).length_squared() / brightness;
+        let normal_distance = (a_p.normal - a_q.normal).length_squared();
+        let albedo_distance = (a_p.albedo - a_q.albedo).length_squared();
+        f64::exp(
+            -color_distance / (self.sigma_color * self.sigma_color)
+            - normal_distance / (self.sigma_normal * self.sigma_normal)
+            - albedo_distance / (self.sigma_albedo * self.sigma_albedo)
+        )
+    }
+}
+
+// Channels with (almost) no albedo are filtered as they are.
+const MIN_ALBEDO: f64 = 1e-3;
+
+fn demodulate(color: &Color, albedo: &Color) -> Color {
+    Color::new(std::array::from_fn(|c| if albedo[c] > MIN_ALBEDO { color[c] / albedo[c] } else { color[c] }))
+}
+
+fn remodulate(color: &Color, albedo: &Color) -> Color {
+    Color::new(std::array::from_fn(|c| if albedo[c] > MIN_ALBEDO { color[c] * albedo[c] } else { color[c] }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::rng;
+    use super::Denoiser;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::camera::Aov;
+    use crate::raytracer::framebuffer::FrameBuffer;
+
+    #[test]
+    fn test_denoise_keeps_edges() {
+        rng::seed_thread(1);
+        // Two flat objects side by side, the left one dark and the right one bright, each sampled
+        // with noise.
+        let (width, height) = (32, 16);
+        let mut frame = FrameBuffer::new(width, height);
+        let truth = |i: usize| if i < width / 2 { 0.2 } else { 0.8 };
+        for j in 0..height {
+            for i in 0..width {
+                let noisy = truth(i) * 2. * random_f64();
+                frame.add_sample(i, j, &Color::new([noisy; 3]));
+                let aov = Aov {
+                    depth: 1.,
+                    normal: Vec3::new_xyz(0., 0., 1.),
+                    albedo: Color::new([0.5; 3]),
+                    object_id: if i < width / 2 { 1 } else { 2 }
+                };
+                frame.add_aov(i, j, &aov);
+            }
+        }
+
+        let denoised = Denoiser::default().denoise(&frame);
+        let error = |color: &dyn Fn(usize, usize) -> f64| {
+            (0..height).flat_map(|j| (0..width).map(move |i| (i, j)))
+                .map(|(i, j)| (color(i, j) - truth(i)).powi(2))
+                .sum::<f64>() / (width * height) as f64
+        };
+        let noisy_error = error(&|i, j| frame.pixel_color(i, j).x());
+        let denoised_error = error(&|i, j| denoised[j * width + i].x());
+        assert!(denoised_error < 0.2 * noisy_error, "{} vs {}", denoised_error, noisy_error);
+        // nothing leaks across the edge between the objects
+        let (left, right) = (denoised[width / 2 - 1].x(), denoised[width / 2].x());
+        assert!(left < 0.5 && right > 0.5, "{} {}", left, right);
+    }
+}
@@ -0,0 +1,286 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use minifb::{Key, Window, WindowOptions};
+use crate::raytracer::prelude::*;
+use crate::raytracer::camera::Aov;
+use crate::raytracer::denoise::Denoiser;
+use crate::raytracer::colors::{color_to_u32, luminance};
+use crate::raytracer::image_writers::{aov_writers_for_path, image_writer_for_path, layer_path, layer_writer_for_path, Layer};
+
+// Accumulates the samples of every pixel, so the current estimate of the image can be shown or
+// saved at any point during the render. With a denoiser, the image is also denoised when the
+// render finishes, and for a preview whenever a sample pass completes, which the preview shows.
+//
+// The sums are kept in fixed point, so they don't depend on the order the samples come in, and
+// the same samples always make the same image.
+pub struct FrameBuffer {
+    width: usize,
+    height: usize,
+    raw_buffer: Vec<[ExactSum; 3]>,
+    count_buffer: Vec<i32>,
+    // sums of the squared luminance of the samples, for their variance
+    squared_buffer: Vec<ExactSum>,
+    color_buffer: Vec<u32>,
+    // sums of the AOVs of the samples that hit something, and how many did
+    aov_buffer: Vec<AovSum>,
+    aov_count_buffer: Vec<i32>,
+    denoiser: Option<Denoiser>,
+    // whether to denoise after every pass, rather than only at the end
+    denoise_passes: bool,
+    denoised_buffer: Option<Vec<Color>>,
+    // set when samples came in since the last denoise
+    denoise_stale: bool
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        FrameBuffer {
+            width,
+            height,
+            raw_buffer: vec![[ExactSum::default(); 3]; width * height],
+            count_buffer: vec![0; width * height],
+            squared_buffer: vec![ExactSum::default(); width * height],
+            color_buffer: vec![0; width * height],
+            aov_buffer: vec![AovSum::default(); width * height],
+            aov_count_buffer: vec![0; width * height],
+            denoiser: None,
+            denoise_passes: false,
+            denoised_buffer: None,
+            denoise_stale: false
+        }
+    }
+
+    // With `every_pass`, the denoiser also runs whenever a pass completes, which is only worth it
+    // for a preview.
+    pub fn new_w_denoiser(width: usize, height: usize, denoiser: Denoiser, every_pass: bool) -> Self {
+        let mut frame = FrameBuffer::new(width, height);
+        frame.denoiser = Some(denoiser);
+        frame.denoise_passes = every_pass;
+        frame
+    }
+
+    pub fn width(&self) -> usize { self.width }
+    pub fn height(&self) -> usize { self.height }
+
+    pub fn add_sample(&mut self, i: usize, j: usize, pixel_color: &Color) {
+        let index = j * self.width + i;
+        ExactSum::add_vec(&mut self.raw_buffer[index], pixel_color);
+        self.count_buffer[index] += 1;
+        self.squared_buffer[index].add(luminance(pixel_color).powi(2));
+        self.denoise_stale = true;
+        // the denoised preview only changes between passes
+        if self.denoised_buffer.is_none() {
+            self.color_buffer[index] = color_to_u32(&self.pixel_color(i, j));
+        }
+    }
+
+    // Returns the average of the samples taken so far for pixel (i, j).
+    pub fn pixel_color(&self, i: usize, j: usize) -> Color {
+        let index = j * self.width + i;
+        let denom = if self.count_buffer[index] != 0 { self.count_buffer[index] as f64 } else { 1. };
+        ExactSum::vec_value(&self.raw_buffer[index]) / denom
+    }
+
+    // Standard error of the gamma corrected luminance of pixel (i, j), estimated from the variance
+    // of its samples. Gamma correction makes errors in dark pixels count for more, as they do on
+    // screen, without demanding perfect blacks. Infinite with fewer than two samples.
+    pub fn pixel_error(&self, i: usize, j: usize) -> f64 {
+        let index = j * self.width + i;
+        let n = self.count_buffer[index] as f64;
+        if n < 2. {
+            return f64::INFINITY;
+        }
+        let mean = luminance(&ExactSum::vec_value(&self.raw_buffer[index])) / n;
+        let variance = f64::max(0., (self.squared_buffer[index].value() - n * mean * mean) / (n - 1.));
+        // first order approximation of the error after gamma correction, d(sqrt(x)) = dx / 2 sqrt(x)
+        (variance / n).sqrt() / (2. * f64::max(mean, 1e-4).sqrt())
+    }
+
+    pub fn add_aov(&mut self, i: usize, j: usize, aov: &Aov) {
+        let index = j * self.width + i;
+        let sum = &mut self.aov_buffer[index];
+        sum.depth.add(aov.depth);
+        ExactSum::add_vec(&mut sum.normal, &aov.normal);
+        ExactSum::add_vec(&mut sum.albedo, &aov.albedo);
+        // Ids can't be averaged, so the lowest one sticks, whatever the order of the samples.
+        if self.aov_count_buffer[index] == 0 || aov.object_id < sum.object_id {
+            sum.object_id = aov.object_id;
+        }
+        self.aov_count_buffer[index] += 1;
+    }
+
+    // Returns the average AOVs of the samples of pixel (i, j) that hit something, or all zeros
+    // (object id 0) if none did.
+    pub fn pixel_aov(&self, i: usize, j: usize) -> Aov {
+        let index = j * self.width + i;
+        let sum = &self.aov_buffer[index];
+        let denom = if self.aov_count_buffer[index] != 0 { self.aov_count_buffer[index] as f64 } else { 1. };
+        Aov {
+            depth: sum.depth.value() / denom,
+            normal: ExactSum::vec_value(&sum.normal) / denom,
+            albedo: ExactSum::vec_value(&sum.albedo) / denom,
+            object_id: sum.object_id
+        }
+    }
+
+    // Called by the renderers whenever a sample pass completes. Runs the denoiser, if there is one
+    // and it runs every pass.
+    pub fn finish_pass(&mut self) {
+        if self.denoise_passes {
+            self.denoise();
+        }
+    }
+
+    // Called by the renderers once the render is complete, before finishing the sinks. Runs the
+    // denoiser, if there is one, unless nothing changed since it last ran.
+    pub fn finish(&mut self) {
+        if self.denoise_stale {
+            self.denoise();
+        }
+    }
+
+    fn denoise(&mut self) {
+        if let Some(denoiser) = &self.denoiser {
+            let denoised = denoiser.denoise(self);
+            self.color_buffer = denoised.iter().map(color_to_u32).collect();
+            self.denoised_buffer = Some(denoised);
+        }
+        self.denoise_stale = false;
+    }
+
+    pub fn has_denoiser(&self) -> bool {
+        self.denoiser.is_some()
+    }
+
+    // Color of pixel (i, j) as of the last denoised pass, or pixel_color without a denoiser.
+    pub fn denoised_color(&self, i: usize, j: usize) -> Color {
+        match &self.denoised_buffer {
+            Some(denoised) => denoised[j * self.width + i],
+            None => self.pixel_color(i, j)
+        }
+    }
+
+    pub fn sample_count(&self, i: usize, j: usize) -> i32 {
+        self.count_buffer[j * self.width + i]
+    }
+
+    // Gamma corrected 0RGB pixels, in the format minifb expects.
+    pub fn color_buffer(&self) -> &[u32] {
+        &self.color_buffer
+    }
+}
+
+// Fixed point sum, exact up to the rounding of each term.
+#[derive(Clone, Copy, Default)]
+struct ExactSum(i128);
+
+impl ExactSum {
+    // steps of 2^-48 (about 4e-15), leaving room for sums up to about 2^78
+    const SCALE: f64 = (1u64 << 48) as f64;
+
+    fn add(&mut self, x: f64) {
+        // NaNs count as 0, and infinities saturate
+        self.0 = self.0.saturating_add((x * Self::SCALE).round() as i128);
+    }
+
+    fn value(&self) -> f64 {
+        self.0 as f64 / Self::SCALE
+    }
+
+    fn add_vec(sums: &mut [ExactSum; 3], v: &Vec3) {
+        for (c, sum) in sums.iter_mut().enumerate() {
+            sum.add(v[c]);
+        }
+    }
+
+    fn vec_value(sums: &[ExactSum; 3]) -> Vec3 {
+        Vec3::new(std::array::from_fn(|c| sums[c].value()))
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct AovSum {
+    depth: ExactSum,
+    normal: [ExactSum; 3],
+    albedo: [ExactSum; 3],
+    object_id: u32
+}
+
+// Destination for rendered frames. `update` is called as samples come in, so sinks can show
+// progress, and `finish` once the render is complete.
+pub trait ImageSink {
+    fn update(&mut self, frame: &FrameBuffer) -> Result<()>;
+
+    fn finish(&mut self, frame: &FrameBuffer) -> Result<()> {
+        self.update(frame)
+    }
+}
+
+pub struct WindowSink {
+    window: Window,
+    last_update: Option<Instant>
+}
+
+impl WindowSink {
+    const FRAME_TIME: Duration = Duration::from_millis(1000 / 60);
+
+    pub fn new(title: &str, width: usize, height: usize) -> Result<Self> {
+        let window = Window::new(title, width, height, WindowOptions::default())
+            .map_err(std::io::Error::other)?;
+        Ok(WindowSink { window, last_update: None })
+    }
+
+    fn present(&mut self, frame: &FrameBuffer) -> Result<()> {
+        self.window
+            .update_with_buffer(frame.color_buffer(), frame.width(), frame.height())
+            .map_err(std::io::Error::other)?;
+        self.last_update = Some(Instant::now());
+        Ok(())
+    }
+}
+
+impl ImageSink for WindowSink {
+    fn update(&mut self, frame: &FrameBuffer) -> Result<()> {
+        // Redrawing on every sample would throttle the render to the display rate.
+        if let Some(last_update) = self.last_update && last_update.elapsed() < Self::FRAME_TIME {
+            return Ok(());
+        }
+        self.present(frame)
+    }
+
+    fn finish(&mut self, frame: &FrameBuffer) -> Result<()> {
+        // Keep showing the final image until the window is closed.
+        while self.window.is_open() && !self.window.is_key_down(Key::Escape) {
+            self.present(frame)?;
+            std::thread::sleep(Self::FRAME_TIME);
+        }
+        Ok(())
+    }
+}
+
+// Creates a writer for each output file, along with writers for the denoised image and the AOVs
+// next to it if `denoised` and `aovs` are set, followed by a preview window unless `window_title`
+// is None. The window comes last since finishing it blocks until it gets closed.
+pub fn create_sinks(
+    outputs: &[PathBuf],
+    denoised: bool,
+    aovs: bool,
+    window_title: Option<&str>,
+    width: usize,
+    height: usize
+) -> Result<Vec<Box<dyn ImageSink>>> {
+    let mut sinks: Vec<Box<dyn ImageSink>> = Vec::new();
+    for output in outputs {
+        sinks.push(image_writer_for_path(output)?);
+        if denoised {
+            sinks.push(layer_writer_for_path(layer_path(output, Layer::Denoised), Layer::Denoised)?);
+        }
+        if aovs {
+            sinks.extend(aov_writers_for_path(output)?);
+        }
+    }
+    if let Some(title) = window_title {
+        sinks.push(Box::new(WindowSink::new(title, width, height)?));
+    }
+    Ok(sinks)
+}
@@ -2,6 +2,8 @@ use crate::raytracer::vec3::{Vec3, Point3, dot};
 use crate::raytracer::ray::Ray;
 use crate::raytracer::prelude::*;
 use crate::raytracer::material::{Material, DefaultMaterial};
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::shared::SharedObjects;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct HitRecord {
@@ -9,7 +11,11 @@ pub struct HitRecord {
     pub normal: Vec3,
     pub mat: Arc<dyn Material>,
     pub t: f64,
-    pub front_face: bool
+    pub u: f64,
+    pub v: f64,
+    pub front_face: bool,
+    // id of the scene object that was hit, set by Tagged (0 for untagged objects)
+    pub object_id: u32
 } 
 
 impl HitRecord {
@@ -25,6 +31,25 @@ impl HitRecord {
 #[typetag::serde(tag = "type")]
 pub trait Hittable: Send + Sync {
     fn hit(&self, r: &Ray, ray_t: Interval, hit_record: &mut HitRecord) -> bool;
+    // returns the axis-aligned box enclosing the object, used to build acceleration structures
+    fn bounding_box(&self) -> BoundingBox;
+
+    // Probability density (per unit solid angle) of `random` returning `direction` from `origin`,
+    // for a ray at `time`. Objects that can't be sampled as lights keep the default of 0.
+    fn pdf_value(&self, _origin: &Point3, _direction: &Vec3, _time: f64) -> f64 {
+        0.
+    }
+
+    // Random direction from `origin` towards the object, distributed with pdf_value.
+    fn random(&self, _origin: &Point3, _time: f64) -> Vec3 {
+        Vec3::new_xyz(1., 0., 0.)
+    }
+
+    // Links the instances inside a deserialized object to the shared geometry they reference,
+    // failing on ids missing from `shared`. Objects wrapping others pass it on.
+    fn resolve_shared(&self, _shared: &SharedObjects) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl Default for HitRecord {
@@ -36,7 +61,10 @@ impl Default for HitRecord {
             normal: Vec3::default(),
             mat: Arc::new(DefaultMaterial::default()),
             t: 0.0,
+            u: 0.0,
+            v: 0.0,
             front_face: false,
+            object_id: 0,
         }
     }
 }
\ No newline at end of file
@@ -2,6 +2,8 @@ use std::ops::{Index, IndexMut};
 use std::sync::Arc;
 use crate::raytracer::prelude::*;
 use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::shared::SharedObjects;
+use crate::raytracer::bounding_box::BoundingBox;
 
 #[derive(Serialize, Deserialize)]
 pub struct HittableList {
@@ -35,6 +37,10 @@ impl HittableList {
         self.objects.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
     pub fn iter(&self) -> std::slice::Iter<'_, Arc<dyn Hittable>> {
         self.objects.iter()
     }
@@ -72,6 +78,30 @@ impl Hittable for HittableList {
 
         hit_anything
     }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.objects.iter().fold(BoundingBox::default(), |bbox, object| {
+            BoundingBox::new_enclosing(&bbox, &object.bounding_box())
+        })
+    }
+
+    // Samples each object with equal probability.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3, time: f64) -> f64 {
+        if self.objects.is_empty() {
+            return 0.;
+        }
+        let weight = 1. / self.objects.len() as f64;
+        self.objects.iter().map(|object| weight * object.pdf_value(origin, direction, time)).sum()
+    }
+
+    fn random(&self, origin: &Point3, time: f64) -> Vec3 {
+        let idx = ((random_f64() * self.objects.len() as f64) as usize).min(self.objects.len() - 1);
+        self.objects[idx].random(origin, time)
+    }
+
+    fn resolve_shared(&self, shared: &SharedObjects) -> Result<()> {
+        self.objects.iter().try_for_each(|object| object.resolve_shared(shared))
+    }
 }
 
 impl Index<usize> for HittableList {
@@ -0,0 +1,283 @@
+//! Sinks that save the finished frame to an image file.
+//!
+//! Besides the rendered colors, a writer can save the denoised colors or one of the auxiliary
+//! outputs (AOVs) of the frame. Float images keep their raw values, while 8-bit images map them
+//! to visible colors.
+
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use crate::raytracer::prelude::*;
+use crate::raytracer::colors::{color_to_rgb, value_to_rgb};
+use crate::raytracer::framebuffer::{FrameBuffer, ImageSink};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layer {
+    Color,
+    Denoised,
+    Depth,
+    Normal,
+    Albedo,
+    ObjectId,
+}
+
+impl Layer {
+    pub const AOVS: [Layer; 4] = [Layer::Depth, Layer::Normal, Layer::Albedo, Layer::ObjectId];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layer::Color => "color",
+            Layer::Denoised => "denoised",
+            Layer::Depth => "depth",
+            Layer::Normal => "normal",
+            Layer::Albedo => "albedo",
+            Layer::ObjectId => "id",
+        }
+    }
+}
+
+// Raw value of `layer` at pixel (i, j), with depths and ids repeated in all three channels.
+fn layer_value(frame: &FrameBuffer, layer: Layer, i: usize, j: usize) -> Vec3 {
+    let aov = frame.pixel_aov(i, j);
+    match layer {
+        Layer::Color => frame.pixel_color(i, j),
+        Layer::Denoised => frame.denoised_color(i, j),
+        Layer::Depth => Vec3::new([aov.depth; 3]),
+        Layer::Normal => aov.normal,
+        Layer::Albedo => aov.albedo,
+        Layer::ObjectId => Vec3::new([aov.object_id as f64; 3]),
+    }
+}
+
+// 8-bit pixels of `layer` in row order. Depths are scaled by the largest one in the frame,
+// normals moved from [-1, 1] to [0, 1], and every object id gets its own arbitrary color.
+fn layer_rgb(frame: &FrameBuffer, layer: Layer) -> Vec<(u32, u32, u32)> {
+    let pixels = (0..frame.height()).flat_map(|j| (0..frame.width()).map(move |i| (i, j)));
+    let max_depth = if layer == Layer::Depth {
+        pixels.clone().map(|(i, j)| frame.pixel_aov(i, j).depth).fold(0., f64::max)
+    } else {
+        0.
+    };
+
+    pixels.map(|(i, j)| {
+        let value = layer_value(frame, layer, i, j);
+        match layer {
+            Layer::Color | Layer::Denoised | Layer::Albedo => color_to_rgb(&value),
+            Layer::Depth => value_to_rgb(&(value / if max_depth > 0. { max_depth } else { 1. })),
+            Layer::Normal => value_to_rgb(&(0.5 * (value + Vec3::new([1., 1., 1.])))),
+            Layer::ObjectId => {
+                let id = frame.pixel_aov(i, j).object_id;
+                let hash = id.wrapping_mul(0x9e37_79b1);
+                if id == 0 { (0, 0, 0) } else { (hash >> 24, (hash >> 16) & 0xff, (hash >> 8) & 0xff) }
+            }
+        }
+    }).collect()
+}
+
+// Plain text PPM, as written in "Ray Tracing in One Weekend".
+pub struct PpmWriter {
+    path: PathBuf,
+    layer: Layer
+}
+
+impl PpmWriter {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        PpmWriter::new_layer(path, Layer::Color)
+    }
+
+    pub fn new_layer(path: impl AsRef<Path>, layer: Layer) -> Self {
+        PpmWriter { path: path.as_ref().to_path_buf(), layer }
+    }
+}
+
+impl ImageSink for PpmWriter {
+    fn update(&mut self, _frame: &FrameBuffer) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self, frame: &FrameBuffer) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        writeln!(writer, "P3\n{} {}\n255", frame.width(), frame.height())?;
+        for (rbyte, gbyte, bbyte) in layer_rgb(frame, self.layer) {
+            writeln!(writer, "{} {} {}", rbyte, gbyte, bbyte)?;
+        }
+        writer.flush()
+    }
+}
+
+// 8-bit gamma corrected PNG.
+pub struct PngWriter {
+    path: PathBuf,
+    layer: Layer
+}
+
+impl PngWriter {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        PngWriter::new_layer(path, Layer::Color)
+    }
+
+    pub fn new_layer(path: impl AsRef<Path>, layer: Layer) -> Self {
+        PngWriter { path: path.as_ref().to_path_buf(), layer }
+    }
+}
+
+impl ImageSink for PngWriter {
+    fn update(&mut self, _frame: &FrameBuffer) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self, frame: &FrameBuffer) -> Result<()> {
+        let pixels = layer_rgb(frame, self.layer);
+        let img = image::RgbImage::from_fn(frame.width() as u32, frame.height() as u32, |i, j| {
+            let (rbyte, gbyte, bbyte) = pixels[j as usize * frame.width() + i as usize];
+            image::Rgb([rbyte as u8, gbyte as u8, bbyte as u8])
+        });
+        img.save_with_format(&self.path, image::ImageFormat::Png)
+            .map_err(Error::other)
+    }
+}
+
+// Portable float map, keeping the linear (not gamma corrected) radiance of every pixel.
+pub struct PfmWriter {
+    path: PathBuf,
+    layer: Layer
+}
+
+impl PfmWriter {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        PfmWriter::new_layer(path, Layer::Color)
+    }
+
+    pub fn new_layer(path: impl AsRef<Path>, layer: Layer) -> Self {
+        PfmWriter { path: path.as_ref().to_path_buf(), layer }
+    }
+}
+
+impl ImageSink for PfmWriter {
+    fn update(&mut self, _frame: &FrameBuffer) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self, frame: &FrameBuffer) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        // A negative scale marks the data as little endian.
+        write!(writer, "PF\n{} {}\n-1.0\n", frame.width(), frame.height())?;
+        // Rows are stored bottom to top.
+        for j in (0..frame.height()).rev() {
+            for i in 0..frame.width() {
+                let value = layer_value(frame, self.layer, i, j);
+                for c in 0..3 {
+                    writer.write_all(&(value[c] as f32).to_le_bytes())?;
+                }
+            }
+        }
+        writer.flush()
+    }
+}
+
+// Picks the writer matching the extension of `path`.
+pub fn image_writer_for_path(path: impl AsRef<Path>) -> Result<Box<dyn ImageSink>> {
+    layer_writer_for_path(path, Layer::Color)
+}
+
+// Like image_writer_for_path, for a writer saving `layer`.
+pub fn layer_writer_for_path(path: impl AsRef<Path>, layer: Layer) -> Result<Box<dyn ImageSink>> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "ppm" => Ok(Box::new(PpmWriter::new_layer(path, layer))),
+        "png" => Ok(Box::new(PngWriter::new_layer(path, layer))),
+        "pfm" => Ok(Box::new(PfmWriter::new_layer(path, layer))),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported image format for {} (expected .ppm, .png or .pfm)", path.display())
+        ))
+    }
+}
+
+// Path of the image saving `layer` next to `path`, e.g. img.depth.pfm for img.pfm.
+pub fn layer_path(path: impl AsRef<Path>, layer: Layer) -> PathBuf {
+    let path = path.as_ref();
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let mut file_name = format!("{}.{}", stem, layer.name());
+    if let Some(extension) = path.extension() {
+        file_name = format!("{}.{}", file_name, extension.to_string_lossy());
+    }
+    path.with_file_name(file_name)
+}
+
+// Writers for every AOV, each saving a separate image in the format of `path`.
+pub fn aov_writers_for_path(path: impl AsRef<Path>) -> Result<Vec<Box<dyn ImageSink>>> {
+    Layer::AOVS.iter().map(|layer| layer_writer_for_path(layer_path(&path, *layer), *layer)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{aov_writers_for_path, image_writer_for_path, layer_path, Layer};
+    use crate::raytracer::camera::Aov;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::framebuffer::FrameBuffer;
+
+    fn test_frame() -> FrameBuffer {
+        let mut frame = FrameBuffer::new(3, 2);
+        frame.add_sample(0, 0, &Color::new([1., 0., 0.]));
+        frame.add_sample(2, 1, &Color::new([0.25, 0.25, 0.25]));
+        frame.add_sample(2, 1, &Color::new([0.75, 0.75, 0.75]));
+        frame
+    }
+
+    #[test]
+    fn test_ppm() {
+        let path = std::env::temp_dir().join(format!("dray_test_{}.ppm", std::process::id()));
+        image_writer_for_path(&path).unwrap().finish(&test_frame()).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[..3], ["P3", "3 2", "255"]);
+        assert_eq!(lines.len(), 3 + 6);
+        assert_eq!(lines[3], "255 0 0");
+        // the two samples of the last pixel are averaged, then gamma corrected
+        assert_eq!(lines[8], "181 181 181");
+    }
+
+    #[test]
+    fn test_pfm() {
+        let path = std::env::temp_dir().join(format!("dray_test_{}.pfm", std::process::id()));
+        image_writer_for_path(&path).unwrap().finish(&test_frame()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let header = b"PF\n3 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(bytes.len(), header.len() + 3 * 2 * 3 * 4);
+        // the bottom row comes first, so pixel (2, 1) is the third pixel of the data
+        let offset = header.len() + 2 * 12;
+        let red = f32::from_le_bytes(bytes[offset..offset+4].try_into().unwrap());
+        assert_eq!(red, 0.5);
+    }
+
+    #[test]
+    fn test_aovs() {
+        let path = std::env::temp_dir().join(format!("dray_test_aov_{}.pfm", std::process::id()));
+        let mut frame = test_frame();
+        let aov = Aov { depth: 2., normal: Vec3::new_xyz(0., 1., 0.), albedo: Color::new([0.5, 0.5, 0.5]), object_id: 3 };
+        frame.add_aov(0, 1, &aov);
+        frame.add_aov(0, 1, &Aov { depth: 4., object_id: 7, ..aov });
+        for mut writer in aov_writers_for_path(&path).unwrap() {
+            writer.finish(&frame).unwrap();
+        }
+
+        // pixel (0, 1) is the first one of the bottom row, which the data starts with
+        let first_value = |layer: Layer| {
+            let bytes = std::fs::read(layer_path(&path, layer)).unwrap();
+            let offset = b"PF\n3 2\n-1.0\n".len();
+            f32::from_le_bytes(bytes[offset..offset+4].try_into().unwrap())
+        };
+        assert_eq!(first_value(Layer::Depth), 3.);
+        assert_eq!(first_value(Layer::Albedo), 0.5);
+        // the id of the first sample is kept
+        assert_eq!(first_value(Layer::ObjectId), 3.);
+        assert!(layer_path("out/img.png", Layer::Normal).ends_with("img.normal.png"));
+    }
+
+    #[test]
+    fn test_unsupported_extension() {
+        assert!(image_writer_for_path("img.tiff").is_err());
+    }
+}
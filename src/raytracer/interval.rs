@@ -1,6 +1,6 @@
 use crate::raytracer::prelude::*;
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct Interval {
     pub min: f64, pub max: f64
 }
@@ -17,6 +17,11 @@ impl Interval {
         Interval{min, max}
     }
 
+    pub fn new_enclosing(a: &Interval, b: &Interval) -> Self {
+        // Create the interval tightly enclosing the two input intervals.
+        Interval{min: f64::min(a.min, b.min), max: f64::max(a.max, b.max)}
+    }
+
     pub fn size(&self) -> f64 {
         self.max - self.min
     }
@@ -34,4 +39,9 @@ impl Interval {
         if x > self.max { return self.max };
         return x;
     }
+
+    pub fn expand(&self, delta: f64) -> Interval {
+        let padding = delta / 2.;
+        Interval{min: self.min - padding, max: self.max + padding}
+    }
 }
\ No newline at end of file
@@ -0,0 +1,130 @@
+use std::ops::Mul;
+use crate::raytracer::prelude::*;
+
+// Row-major 4x4 matrix for affine transforms of points and vectors in homogeneous coordinates.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Mat4 {
+    m: [[f64; 4]; 4]
+}
+
+impl Default for Mat4 {
+    fn default() -> Self {
+        Mat4::IDENTITY
+    }
+}
+
+impl Mat4 {
+    pub const IDENTITY: Mat4 = Mat4 { m: [
+        [1., 0., 0., 0.],
+        [0., 1., 0., 0.],
+        [0., 0., 1., 0.],
+        [0., 0., 0., 1.]
+    ]};
+
+    pub fn new(m: [[f64; 4]; 4]) -> Self {
+        Mat4 { m }
+    }
+
+    pub fn translation(offset: &Vec3) -> Self {
+        Mat4::new([
+            [1., 0., 0., offset.x()],
+            [0., 1., 0., offset.y()],
+            [0., 0., 1., offset.z()],
+            [0., 0., 0., 1.]
+        ])
+    }
+
+    pub fn scaling(factors: &Vec3) -> Self {
+        Mat4::new([
+            [factors.x(), 0., 0., 0.],
+            [0., factors.y(), 0., 0.],
+            [0., 0., factors.z(), 0.],
+            [0., 0., 0., 1.]
+        ])
+    }
+
+    // Counterclockwise rotation by `degrees` around `axis` (Rodrigues' rotation formula).
+    pub fn rotation(axis: &Vec3, degrees: f64) -> Self {
+        let a = unit_vector(axis);
+        let (x, y, z) = (a.x(), a.y(), a.z());
+        let radians = degrees_to_radians(degrees);
+        let (s, c) = (f64::sin(radians), f64::cos(radians));
+        let t = 1. - c;
+        Mat4::new([
+            [t*x*x + c,   t*x*y - s*z, t*x*z + s*y, 0.],
+            [t*x*y + s*z, t*y*y + c,   t*y*z - s*x, 0.],
+            [t*x*z - s*y, t*y*z + s*x, t*z*z + c,   0.],
+            [0., 0., 0., 1.]
+        ])
+    }
+
+    pub fn rotation_y(degrees: f64) -> Self {
+        Mat4::rotation(&Vec3::new_xyz(0., 1., 0.), degrees)
+    }
+
+    pub fn transpose(&self) -> Self {
+        Mat4::new(std::array::from_fn(|i| std::array::from_fn(|j| self.m[j][i])))
+    }
+
+    // Gauss-Jordan elimination with partial pivoting. Returns None for singular matrices.
+    pub fn inverse(&self) -> Option<Self> {
+        let mut a = self.m;
+        let mut inv = Mat4::IDENTITY.m;
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs())).unwrap();
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1. / a[col][col];
+            for k in 0..4 {
+                a[col][k] *= scale;
+                inv[col][k] *= scale;
+            }
+            for row in 0..4 {
+                if row != col {
+                    let factor = a[row][col];
+                    for k in 0..4 {
+                        a[row][k] -= factor * a[col][k];
+                        inv[row][k] -= factor * inv[col][k];
+                    }
+                }
+            }
+        }
+        Some(Mat4::new(inv))
+    }
+
+    pub fn transform_point(&self, p: &Point3) -> Point3 {
+        Point3::new(std::array::from_fn(|i| {
+            self.m[i][0]*p.x() + self.m[i][1]*p.y() + self.m[i][2]*p.z() + self.m[i][3]
+        }))
+    }
+
+    // Determinant of the upper left 3x3 block, the factor the transform scales volumes by.
+    pub fn linear_determinant(&self) -> f64 {
+        let m = &self.m;
+        m[0][0] * (m[1][1]*m[2][2] - m[1][2]*m[2][1])
+            - m[0][1] * (m[1][0]*m[2][2] - m[1][2]*m[2][0])
+            + m[0][2] * (m[1][0]*m[2][1] - m[1][1]*m[2][0])
+    }
+
+    // Like transform_point, but ignoring the translation.
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(std::array::from_fn(|i| {
+            self.m[i][0]*v.x() + self.m[i][1]*v.y() + self.m[i][2]*v.z()
+        }))
+    }
+}
+
+impl Mul for Mat4 {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        Mat4::new(std::array::from_fn(|i| std::array::from_fn(|j| {
+            (0..4).map(|k| self.m[i][k] * rhs.m[k][j]).sum()
+        })))
+    }
+}
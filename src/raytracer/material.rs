@@ -1,10 +1,45 @@
+use std::path::Path;
+use serde::Deserializer;
 use crate::raytracer::prelude::*;
 use crate::raytracer::hittable::HitRecord;
+use crate::raytracer::pdf::{CosinePdf, Onb, Pdf, SpherePdf};
+use crate::raytracer::texture::{deserialize_texture, SolidColor, Texture};
 
 #[typetag::serde(tag = "type")]
 pub trait Material : Send + Sync {
     // returns true if scattered otherwise false if absorbed
     fn scatter(&self, _r_in: &Ray, _hit_record: &HitRecord, _attenuation: &mut Color, _scattered: &mut Ray) -> bool;
+
+    // light given off by the surface at texture coordinates (u, v) and point p
+    fn emitted(&self, _u: f64, _v: f64, _p: &Point3) -> Color {
+        Color::new([0., 0., 0.])
+    }
+
+    // Probability density (per unit solid angle) of scatter picking the direction of `scattered`.
+    // Materials scattering into a single direction, like Metal and Dialectric, keep the default
+    // of 0, and don't get their light sampled explicitly.
+    fn scattering_pdf(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        0.
+    }
+
+    // Fraction of the light arriving along `scattered` that leaves along `r_in` (the BSDF times
+    // the cosine with the normal), for explicit light samples. Divided by scattering_pdf, it
+    // gives the attenuation of scatter for the same direction.
+    fn scattering_value(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> Color {
+        Color::new([0., 0., 0.])
+    }
+
+    // color of the surface at the hit, recorded in the albedo AOV (black for lights and other
+    // materials that don't reflect)
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        Color::new([0., 0., 0.])
+    }
+
+    // Loads the image files of the textures of the material, relative to `base_dir`, see
+    // Texture::load_images.
+    fn load_images(&self, _base_dir: &Path) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -28,43 +63,73 @@ impl Material for Transparent {
         *scattered = r_in.clone();
         return true;
     }
+
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        Color::new([1., 1., 1.])
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Lambertian {
-    albedo: Color
+    #[serde(deserialize_with = "deserialize_texture")]
+    albedo: Arc<dyn Texture>
 } 
 
 impl Lambertian {
     pub fn new(albedo: &Color) -> Self {
-        Lambertian { albedo: *albedo }
+        Lambertian::new_texture(Arc::new(SolidColor::new(albedo)))
+    }
+
+    pub fn new_texture(albedo: Arc<dyn Texture>) -> Self {
+        Lambertian { albedo }
     }
 }
 
 #[typetag::serde]
 impl Material for Lambertian {
-    fn scatter(&self, _r_in: &Ray, rec: &HitRecord, attenuation: &mut Color, scattered: &mut Ray) -> bool {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Color, scattered: &mut Ray) -> bool {
         let mut scatter_direction = rec.normal + random_unit_vector();
 
         if scatter_direction.near_zero() {
             scatter_direction = rec.normal;
         }
 
-        *scattered = Ray::new(rec.p, scatter_direction);
-        *attenuation = self.albedo;
+        *scattered = Ray::new_time(rec.p, scatter_direction, r_in.time());
+        *attenuation = self.albedo.value(rec.u, rec.v, &rec.p);
         return true;
     }
+
+    fn scattering_pdf(&self, _r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        CosinePdf::new(&rec.normal).value(scattered.direction())
+    }
+
+    fn scattering_value(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        self.albedo.value(rec.u, rec.v, &rec.p) * self.scattering_pdf(r_in, rec, scattered)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.albedo.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn load_images(&self, base_dir: &Path) -> Result<()> {
+        self.albedo.load_images(base_dir)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Metal {
-    albedo: Color,
+    #[serde(deserialize_with = "deserialize_texture")]
+    albedo: Arc<dyn Texture>,
     fuzz: f64
 } 
 
 impl Metal {
     pub fn new(albedo: &Color, fuzz: f64) -> Self {
-        Metal { albedo: *albedo, fuzz: if fuzz < 1.0 { fuzz } else { 1.0 } }
+        Metal::new_texture(Arc::new(SolidColor::new(albedo)), fuzz)
+    }
+
+    pub fn new_texture(albedo: Arc<dyn Texture>, fuzz: f64) -> Self {
+        Metal { albedo, fuzz: if fuzz < 1.0 { fuzz } else { 1.0 } }
     }
 }
 
@@ -73,11 +138,19 @@ impl Material for Metal {
     fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Color, scattered: &mut Ray) -> bool {
         let mut reflected: Vec3 = reflect(r_in.direction(), &rec.normal);
         reflected = unit_vector(&reflected) + (self.fuzz * random_unit_vector());
-        *scattered = Ray::new(rec.p, reflected);
-        *attenuation = self.albedo;
+        *scattered = Ray::new_time(rec.p, reflected, r_in.time());
+        *attenuation = self.albedo.value(rec.u, rec.v, &rec.p);
         // if the fuzzed reflection goes below the surface, absorb the ray
         dot(scattered.direction(), &rec.normal) > 0.
     }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.albedo.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn load_images(&self, base_dir: &Path) -> Result<()> {
+        self.albedo.load_images(base_dir)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -117,7 +190,302 @@ impl Material for Dialectric {
             refract(&unit_direction, &rec.normal, ri)
         };
 
-        *scattered = Ray::new(rec.p, direction);
+        *scattered = Ray::new_time(rec.p, direction, r_in.time());
         return true;
     }
-}
\ No newline at end of file
+
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        Color::new([1., 1., 1.])
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DiffuseLight {
+    #[serde(deserialize_with = "deserialize_texture")]
+    emit: Arc<dyn Texture>
+}
+
+impl DiffuseLight {
+    pub fn new(emit: &Color) -> Self {
+        DiffuseLight::new_texture(Arc::new(SolidColor::new(emit)))
+    }
+
+    pub fn new_texture(emit: Arc<dyn Texture>) -> Self {
+        DiffuseLight { emit }
+    }
+}
+
+#[typetag::serde]
+impl Material for DiffuseLight {
+    fn scatter(&self, _r_in: &Ray, _rec: &HitRecord, _attenuation: &mut Color, _scattered: &mut Ray) -> bool {
+        false
+    }
+
+    fn emitted(&self, u: f64, v: f64, p: &Point3) -> Color {
+        self.emit.value(u, v, p)
+    }
+
+    fn load_images(&self, base_dir: &Path) -> Result<()> {
+        self.emit.load_images(base_dir)
+    }
+}
+// Phase function of a participating medium, scattering uniformly in all directions.
+#[derive(Serialize, Deserialize)]
+pub struct Isotropic {
+    #[serde(deserialize_with = "deserialize_texture")]
+    albedo: Arc<dyn Texture>
+}
+
+impl Isotropic {
+    pub fn new(albedo: &Color) -> Self {
+        Isotropic::new_texture(Arc::new(SolidColor::new(albedo)))
+    }
+
+    pub fn new_texture(albedo: Arc<dyn Texture>) -> Self {
+        Isotropic { albedo }
+    }
+}
+
+#[typetag::serde]
+impl Material for Isotropic {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Color, scattered: &mut Ray) -> bool {
+        *scattered = Ray::new_time(rec.p, random_unit_vector(), r_in.time());
+        *attenuation = self.albedo.value(rec.u, rec.v, &rec.p);
+        true
+    }
+
+    fn scattering_pdf(&self, _r_in: &Ray, _rec: &HitRecord, scattered: &Ray) -> f64 {
+        SpherePdf.value(scattered.direction())
+    }
+
+    fn scattering_value(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        self.albedo.value(rec.u, rec.v, &rec.p) * self.scattering_pdf(r_in, rec, scattered)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.albedo.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn load_images(&self, base_dir: &Path) -> Result<()> {
+        self.albedo.load_images(base_dir)
+    }
+}
+
+// Metallic-roughness material in the style of the Disney and glTF principled BRDFs: a diffuse
+// base under a GGX (Trowbridge-Reitz) specular lobe. Metals tint their reflections with the base
+// color and have no diffuse part.
+#[derive(Serialize, Deserialize)]
+pub struct Principled {
+    #[serde(deserialize_with = "deserialize_texture")]
+    base_color: Arc<dyn Texture>,
+    #[serde(default = "default_roughness", deserialize_with = "deserialize_unit_interval")]
+    roughness: f64,
+    #[serde(default, deserialize_with = "deserialize_unit_interval")]
+    metalness: f64,
+    // reflectance of non-metals at normal incidence, where the default of 0.5 gives the 4% of
+    // most dielectrics
+    #[serde(default = "default_specular", deserialize_with = "deserialize_non_negative")]
+    specular: f64
+}
+
+fn default_roughness() -> f64 { 0.5 }
+fn default_specular() -> f64 { 0.5 }
+
+// Scene files can hold any number, so the parameters are clamped like in Principled::new_texture.
+fn deserialize_unit_interval<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<f64, D::Error> {
+    Ok(f64::deserialize(deserializer)?.clamp(0., 1.))
+}
+
+fn deserialize_non_negative<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<f64, D::Error> {
+    Ok(f64::max(f64::deserialize(deserializer)?, 0.))
+}
+
+// GGX distribution of microfacet normals at cosine `cos_h` with the macroscopic normal.
+fn ggx_d(cos_h: f64, alpha: f64) -> f64 {
+    let a2 = alpha * alpha;
+    let denom = cos_h * cos_h * (a2 - 1.) + 1.;
+    a2 / (PI * denom * denom)
+}
+
+// Smith masking for one direction at cosine `cos` with the normal.
+fn smith_g1(cos: f64, alpha: f64) -> f64 {
+    let a2 = alpha * alpha;
+    2. * cos / (cos + f64::sqrt(a2 + (1. - a2) * cos * cos))
+}
+
+fn schlick_fresnel(f0: &Color, cosine: f64) -> Color {
+    *f0 + (Color::new([1., 1., 1.]) - *f0) * (1. - cosine).clamp(0., 1.).powi(5)
+}
+
+fn average(c: &Color) -> f64 {
+    (c.x() + c.y() + c.z()) / 3.
+}
+
+// Random microfacet normal around +z, with density ggx_d times its cosine.
+fn random_ggx_normal(alpha: f64) -> Vec3 {
+    let r1 = random_f64();
+    let r2 = random_f64();
+    let tan2_theta = alpha * alpha * r1 / (1. - r1);
+    let cos_theta = 1. / f64::sqrt(1. + tan2_theta);
+    let sin_theta = f64::sqrt(f64::max(0., 1. - cos_theta * cos_theta));
+    let phi = 2. * PI * r2;
+    Vec3::new_xyz(f64::cos(phi) * sin_theta, f64::sin(phi) * sin_theta, cos_theta)
+}
+
+impl Principled {
+    pub fn new(base_color: &Color, roughness: f64, metalness: f64, specular: f64) -> Self {
+        Principled::new_texture(Arc::new(SolidColor::new(base_color)), roughness, metalness, specular)
+    }
+
+    pub fn new_texture(base_color: Arc<dyn Texture>, roughness: f64, metalness: f64, specular: f64) -> Self {
+        Principled {
+            base_color,
+            roughness: roughness.clamp(0., 1.),
+            metalness: metalness.clamp(0., 1.),
+            specular: f64::max(specular, 0.)
+        }
+    }
+
+    // Width of the GGX lobe, kept away from 0 where it turns into a perfect mirror.
+    fn alpha(&self) -> f64 {
+        f64::max(self.roughness * self.roughness, 1e-3)
+    }
+
+    fn f0(&self, base_color: &Color) -> Color {
+        let dielectric = 0.08 * self.specular;
+        (1. - self.metalness) * Color::new([dielectric; 3]) + self.metalness * *base_color
+    }
+
+    // Probability of sampling the specular lobe instead of the diffuse one, following how much
+    // each of them reflects.
+    fn specular_probability(&self, base_color: &Color, cos_v: f64) -> f64 {
+        let specular = average(&schlick_fresnel(&self.f0(base_color), cos_v));
+        let diffuse = (1. - self.metalness) * average(base_color) * (1. - specular);
+        if specular + diffuse <= 0. { 1. } else { specular / (specular + diffuse) }
+    }
+
+    // BSDF times cosine, and the density of scatter picking `l`, for light arriving along `l`
+    // and leaving along `v` (unit vectors pointing away from the surface).
+    fn evaluate(&self, rec: &HitRecord, v: &Vec3, l: &Vec3) -> (Color, f64) {
+        let cos_v = dot(&rec.normal, v);
+        let cos_l = dot(&rec.normal, l);
+        if cos_v <= 0. || cos_l <= 0. {
+            return (Color::default(), 0.);
+        }
+
+        let base_color = self.base_color.value(rec.u, rec.v, &rec.p);
+        let alpha = self.alpha();
+        let h = unit_vector(&(*v + *l));
+        let cos_h = dot(&rec.normal, &h);
+        let v_dot_h = dot(v, &h);
+
+        let d = ggx_d(cos_h, alpha);
+        let fresnel = schlick_fresnel(&self.f0(&base_color), v_dot_h);
+        let g = smith_g1(cos_v, alpha) * smith_g1(cos_l, alpha);
+        let specular = fresnel * (d * g / (4. * cos_v * cos_l));
+        let diffuse = (1. - self.metalness) * (Color::new([1., 1., 1.]) - fresnel) * base_color / PI;
+
+        let p_specular = self.specular_probability(&base_color, cos_v);
+        let pdf = p_specular * d * cos_h / (4. * v_dot_h) + (1. - p_specular) * cos_l / PI;
+        ((diffuse + specular) * cos_l, pdf)
+    }
+}
+
+#[typetag::serde]
+impl Material for Principled {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Color, scattered: &mut Ray) -> bool {
+        let v = -unit_vector(r_in.direction());
+        let cos_v = dot(&rec.normal, &v);
+        let base_color = self.base_color.value(rec.u, rec.v, &rec.p);
+
+        // Reflect around a sampled microfacet normal, or scatter diffusely.
+        let uvw = Onb::new(&rec.normal);
+        let l = if random_f64() < self.specular_probability(&base_color, cos_v) {
+            let h = uvw.transform(&random_ggx_normal(self.alpha()));
+            reflect(&(-v), &h)
+        } else {
+            uvw.transform(&random_cosine_direction())
+        };
+
+        // directions below the surface absorb the ray
+        let (value, pdf) = self.evaluate(rec, &v, &l);
+        if pdf <= 0. {
+            return false;
+        }
+        *scattered = Ray::new_time(rec.p, l, r_in.time());
+        *attenuation = value / pdf;
+        true
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.evaluate(rec, &(-unit_vector(r_in.direction())), &unit_vector(scattered.direction())).1
+    }
+
+    fn scattering_value(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        self.evaluate(rec, &(-unit_vector(r_in.direction())), &unit_vector(scattered.direction())).0
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.base_color.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn load_images(&self, base_dir: &Path) -> Result<()> {
+        self.base_color.load_images(base_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::rng;
+    use super::{Material, Principled};
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::hittable::HitRecord;
+
+    // Average reflectance for light leaving along -r_in, estimated by sampling scatter, and
+    // independently by integrating scattering_value over uniformly sampled directions.
+    fn reflectance(material: &dyn Material, r_in: &Ray, rec: &HitRecord, n: usize) -> (f64, f64) {
+        let mut sampled = 0.;
+        let mut integrated = 0.;
+        for _ in 0..n {
+            let mut attenuation = Color::default();
+            let mut scattered = Ray::default();
+            if material.scatter(r_in, rec, &mut attenuation, &mut scattered) {
+                sampled += attenuation.y();
+            }
+            let direction = random_on_hemisphere(&rec.normal);
+            let value = material.scattering_value(r_in, rec, &Ray::new(rec.p, direction));
+            integrated += value.y() * 2. * PI;
+        }
+        (sampled / n as f64, integrated / n as f64)
+    }
+
+    #[test]
+    fn test_principled_sampling_matches_evaluation() {
+        rng::seed_thread(1);
+        let mut rec = HitRecord::default();
+        rec.normal = Vec3::new_xyz(0., 0., 1.);
+        let r_in = Ray::new(Point3::new_xyz(-1., 0., 1.), Vec3::new_xyz(1., 0., -1.));
+
+        let materials = [
+            Principled::new(&Color::new([0.8, 0.8, 0.8]), 0.5, 0., 0.5),
+            Principled::new(&Color::new([0.9, 0.6, 0.3]), 0.6, 1., 0.5),
+            Principled::new(&Color::new([0.2, 0.7, 0.2]), 0.8, 0.3, 1.),
+        ];
+        for material in &materials {
+            let (sampled, integrated) = reflectance(material, &r_in, &rec, 100_000);
+            assert!((sampled - integrated).abs() < 0.02, "{} vs {}", sampled, integrated);
+            // and no energy gets created
+            assert!(sampled < 1.);
+        }
+    }
+
+    #[test]
+    fn test_principled_parameters_are_clamped() {
+        // out of range values from a scene file end up where Principled::new puts them
+        let json = r#"{"base_color": [0.5, 0.5, 0.5], "roughness": 1.5, "metalness": -1, "specular": -2}"#;
+        let material: Principled = serde_json::from_str(json).unwrap();
+        assert_eq!((material.roughness, material.metalness, material.specular), (1., 0., 0.));
+        let material: Principled = serde_json::from_str(r#"{"base_color": [0.5, 0.5, 0.5]}"#).unwrap();
+        assert_eq!((material.roughness, material.metalness, material.specular), (0.5, 0., 0.5));
+    }
+}
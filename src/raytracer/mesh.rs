@@ -0,0 +1,116 @@
+use std::sync::OnceLock;
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::bvh::Bvh;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::hittable_list::HittableList;
+use crate::raytracer::material::Material;
+use crate::raytracer::triangle::{intersect_triangle, set_triangle_hit};
+
+// Vertex data shared by every triangle of a mesh. Normals and uvs are either empty or hold one
+// entry per position.
+#[derive(Serialize, Deserialize, Default)]
+pub struct MeshData {
+    pub positions: Vec<Point3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f64, f64)>,
+    pub triangles: Vec<[usize; 3]>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TriangleMesh {
+    data: Arc<MeshData>,
+    mat: Arc<dyn Material>,
+    // built on first use, so deserialized meshes rebuild it on the receiving server
+    #[serde(skip)]
+    accelerator: OnceLock<Bvh>
+}
+
+impl TriangleMesh {
+    pub fn new(data: MeshData, mat: Arc<dyn Material>) -> Self {
+        TriangleMesh { data: Arc::new(data), mat, accelerator: OnceLock::new() }
+    }
+
+    pub fn data(&self) -> &MeshData {
+        &self.data
+    }
+
+    pub fn num_triangles(&self) -> usize {
+        self.data.triangles.len()
+    }
+
+    fn accelerator(&self) -> &Bvh {
+        self.accelerator.get_or_init(|| {
+            let mut faces = HittableList::new();
+            for index in 0..self.data.triangles.len() {
+                faces.add(Arc::new(MeshTriangle {
+                    mesh: self.data.clone(),
+                    mat: self.mat.clone(),
+                    index
+                }));
+            }
+            Bvh::new(&faces)
+        })
+    }
+}
+
+#[typetag::serde]
+impl Hittable for TriangleMesh {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        self.accelerator().hit(r, ray_t, rec)
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.data.positions.iter().fold(BoundingBox::default(), |bbox, p| {
+            BoundingBox::new_enclosing(&bbox, &BoundingBox::new_points(p, p))
+        })
+    }
+}
+
+// A single face of a mesh, only used as a leaf of the mesh's hierarchy.
+#[derive(Serialize, Deserialize)]
+struct MeshTriangle {
+    mesh: Arc<MeshData>,
+    mat: Arc<dyn Material>,
+    index: usize
+}
+
+impl MeshTriangle {
+    fn vertices(&self) -> [&Point3; 3] {
+        let [i0, i1, i2] = self.mesh.triangles[self.index];
+        [&self.mesh.positions[i0], &self.mesh.positions[i1], &self.mesh.positions[i2]]
+    }
+}
+
+#[typetag::serde]
+impl Hittable for MeshTriangle {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        let [p0, p1, p2] = self.vertices();
+        let Some(hit) = intersect_triangle(r, ray_t, p0, p1, p2) else {
+            return false;
+        };
+
+        let [i0, i1, i2] = self.mesh.triangles[self.index];
+        let normals = if self.mesh.normals.is_empty() {
+            None
+        } else {
+            Some([&self.mesh.normals[i0], &self.mesh.normals[i1], &self.mesh.normals[i2]])
+        };
+        let uvs = if self.mesh.uvs.is_empty() {
+            [(0., 0.), (1., 0.), (0., 1.)]
+        } else {
+            [self.mesh.uvs[i0], self.mesh.uvs[i1], self.mesh.uvs[i2]]
+        };
+
+        set_triangle_hit(r, rec, hit, [p0, p1, p2], normals, uvs, &self.mat);
+        true
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        let [p0, p1, p2] = self.vertices();
+        BoundingBox::new_enclosing(
+            &BoundingBox::new_points(p0, p1),
+            &BoundingBox::new_points(p2, p2)
+        )
+    }
+}
@@ -1,11 +1,33 @@
+pub mod background;
 pub mod bounding_box;
+pub mod bvh;
 pub mod camera;
 pub mod colors;
+pub mod constant_medium;
+pub mod denoise;
+pub mod framebuffer;
 pub mod hittable_list;
 pub mod hittable;
+pub mod image_writers;
 pub mod interval;
+pub mod mat4;
 pub mod material;
+pub mod mesh;
+pub mod motion;
+pub mod obj_loader;
+pub mod parallel_render;
+pub mod pdf;
+pub mod plane;
 pub mod prelude;
+pub mod quad;
 pub mod ray;
+pub mod rng;
+pub mod sampler;
+pub mod scene;
+pub mod shared;
 pub mod sphere;
+pub mod tagged;
+pub mod texture;
+pub mod transform;
+pub mod triangle;
 pub mod vec3;
\ No newline at end of file
@@ -0,0 +1,116 @@
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::shared::SharedObjects;
+
+// Moves `object` at constant speed, from where it is at time 0 to `offset` away from there at
+// time 1. Outside of that interval the object rests at the nearest end, so the bounding box of
+// both ends encloses it for any shutter interval.
+#[derive(Serialize, Deserialize)]
+pub struct LinearMotion {
+    object: Arc<dyn Hittable>,
+    offset: Vec3,
+    bbox: BoundingBox
+}
+
+impl LinearMotion {
+    pub fn new(object: Arc<dyn Hittable>, offset: &Vec3) -> Self {
+        let start = object.bounding_box();
+        let end = BoundingBox::new(std::array::from_fn(|n| {
+            let axis = start.axis_interval(n);
+            Interval::new_min_max(axis.min + offset[n], axis.max + offset[n])
+        }));
+        LinearMotion { object, offset: *offset, bbox: BoundingBox::new_enclosing(&start, &end) }
+    }
+
+    fn offset_at(&self, time: f64) -> Vec3 {
+        time.clamp(0., 1.) * self.offset
+    }
+}
+
+#[typetag::serde]
+impl Hittable for LinearMotion {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        // Move the ray back instead of moving the object forward.
+        let offset = self.offset_at(r.time());
+        let moved_r = Ray::new_time(*r.origin() - offset, *r.direction(), r.time());
+        if !self.object.hit(&moved_r, ray_t, rec) {
+            return false;
+        }
+
+        rec.p += offset;
+        true
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.bbox.clone()
+    }
+
+    // Like hit, with the origin moved back instead of the object forward.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3, time: f64) -> f64 {
+        self.object.pdf_value(&(*origin - self.offset_at(time)), direction, time)
+    }
+
+    fn random(&self, origin: &Point3, time: f64) -> Vec3 {
+        self.object.random(&(*origin - self.offset_at(time)), time)
+    }
+
+    fn resolve_shared(&self, shared: &SharedObjects) -> Result<()> {
+        self.object.resolve_shared(shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::rng;
+    use super::LinearMotion;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::hittable::{Hittable, HitRecord};
+    use crate::raytracer::material::{DiffuseLight, Lambertian};
+    use crate::raytracer::quad::Quad;
+    use crate::raytracer::sphere::Sphere;
+
+    #[test]
+    fn test_moving_sphere() {
+        // A unit sphere moving from the origin to (0, 2, 0), seen along -z from x = 0, y = 2.
+        let mat = Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])));
+        let sphere = Arc::new(Sphere::new(&Point3::default(), 1., mat));
+        let moving = LinearMotion::new(sphere, &Vec3::new_xyz(0., 2., 0.));
+        let ray_t = Interval::new_min_max(0.001, f64::INFINITY);
+
+        let mut rec = HitRecord::default();
+        let r = Ray::new_time(Point3::new_xyz(0., 2., 5.), Vec3::new_xyz(0., 0., -1.), 0.);
+        assert!(!moving.hit(&r, ray_t, &mut rec));
+        let r = Ray::new_time(Point3::new_xyz(0., 2., 5.), Vec3::new_xyz(0., 0., -1.), 1.);
+        assert!(moving.hit(&r, ray_t, &mut rec));
+        assert!((rec.p - Point3::new_xyz(0., 2., 1.)).length() < 1e-9);
+
+        // halfway there, the ray only grazes the sphere below its center
+        let r = Ray::new_time(Point3::new_xyz(0., 2., 5.), Vec3::new_xyz(0., 0., -1.), 0.5);
+        assert!(moving.hit(&r, ray_t, &mut rec));
+        assert!(rec.p.z().abs() < 1e-6);
+
+        let bbox = moving.bounding_box();
+        assert!((bbox.axis_interval(1).min + 1.).abs() < 1e-9);
+        assert!((bbox.axis_interval(1).max - 3.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_moving_light() {
+        rng::seed_thread(1);
+        // A quad light moving from above the origin to 10 along x, sampled where it is at the time.
+        let light = Arc::new(DiffuseLight::new(&Color::new([4., 4., 4.])));
+        let quad = Arc::new(Quad::new(&Point3::new_xyz(-1., 2., -1.), &Vec3::new_xyz(2., 0., 0.), &Vec3::new_xyz(0., 0., 2.), light));
+        let moving = LinearMotion::new(quad.clone(), &Vec3::new_xyz(10., 0., 0.));
+        let origin = Point3::default();
+
+        let up = Vec3::new_xyz(0., 1., 0.);
+        assert_eq!(moving.pdf_value(&origin, &up, 0.), quad.pdf_value(&origin, &up, 0.));
+        assert_eq!(moving.pdf_value(&origin, &up, 1.), 0.);
+        for _ in 0..100 {
+            let direction = moving.random(&origin, 1.);
+            assert!(direction.x() > 8.);
+            assert!(moving.pdf_value(&origin, &direction, 1.) > 0.);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Loader for Wavefront OBJ meshes and their MTL material libraries.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::Path;
+use crate::raytracer::prelude::*;
+use crate::raytracer::hittable_list::HittableList;
+use crate::raytracer::material::{Dialectric, DiffuseLight, Lambertian, Material, Metal, Principled};
+use crate::raytracer::mesh::{MeshData, TriangleMesh};
+
+// Material parameters from an MTL `newmtl` block, with the defaults from the MTL spec.
+#[derive(Clone)]
+pub struct MtlMaterial {
+    pub diffuse: Color,
+    pub specular: Color,
+    pub emission: Color,
+    pub shininess: f64,
+    pub refraction_index: f64,
+    pub dissolve: f64,
+    pub illum: i32,
+    // from the PBR extension (Pr and Pm), which selects the Principled material
+    pub roughness: Option<f64>,
+    pub metalness: Option<f64>
+}
+
+impl Default for MtlMaterial {
+    fn default() -> Self {
+        MtlMaterial {
+            diffuse: Color::new([0.8, 0.8, 0.8]),
+            specular: Color::default(),
+            emission: Color::default(),
+            shininess: 0.,
+            refraction_index: 1.5,
+            dissolve: 1.,
+            illum: 2,
+            roughness: None,
+            metalness: None
+        }
+    }
+}
+
+impl MtlMaterial {
+    pub fn to_material(&self) -> Arc<dyn Material> {
+        let max_component = |c: &Color| f64::max(c.x(), f64::max(c.y(), c.z()));
+
+        // Any emission makes the surface an area light.
+        if max_component(&self.emission) > 0. {
+            return Arc::new(DiffuseLight::new(&self.emission));
+        }
+
+        // Transparent or explicitly refractive illumination models become glass.
+        if self.dissolve < 1. || matches!(self.illum, 4 | 6 | 7 | 9) {
+            return Arc::new(Dialectric::new(self.refraction_index));
+        }
+
+        if self.roughness.is_some() || self.metalness.is_some() {
+            return Arc::new(Principled::new(
+                &self.diffuse,
+                self.roughness.unwrap_or(0.5),
+                self.metalness.unwrap_or(0.),
+                0.5
+            ));
+        }
+
+        // Reflective illumination models, or surfaces that are mostly specular, become metal.
+        // Phong shininess is mapped onto the fuzz radius, so a high exponent gives a sharp mirror.
+        if matches!(self.illum, 3 | 5 | 8) || max_component(&self.specular) > max_component(&self.diffuse) {
+            let fuzz = (2. / (self.shininess + 2.)).sqrt();
+            return Arc::new(Metal::new(&self.specular, fuzz));
+        }
+
+        Arc::new(Lambertian::new(&self.diffuse))
+    }
+}
+
+fn parse_error(path: &Path, line_num: usize, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), line_num + 1, msg))
+}
+
+fn parse_floats<const N: usize>(path: &Path, line_num: usize, tokens: &[&str]) -> Result<[f64; N]> {
+    if tokens.len() < N {
+        return Err(parse_error(path, line_num, &format!("expected {} numbers", N)));
+    }
+    let mut values = [0.; N];
+    for (value, token) in values.iter_mut().zip(tokens) {
+        *value = token.parse().map_err(|_| parse_error(path, line_num, &format!("invalid number '{}'", token)))?;
+    }
+    Ok(values)
+}
+
+pub fn load_mtl(path: impl AsRef<Path>) -> Result<HashMap<String, MtlMaterial>> {
+    let path = path.as_ref();
+    let reader = BufReader::new(File::open(path)?);
+    let mut materials: HashMap<String, MtlMaterial> = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((&keyword, args)) = tokens.split_first() else {
+            continue;
+        };
+
+        if keyword == "newmtl" {
+            if let Some((name, mtl)) = current.take() {
+                materials.insert(name, mtl);
+            }
+            current = Some((args.join(" "), MtlMaterial::default()));
+            continue;
+        }
+
+        let Some((_, mtl)) = current.as_mut() else {
+            continue;
+        };
+        match keyword {
+            "Kd" => mtl.diffuse = Color::new(parse_floats::<3>(path, line_num, args)?),
+            "Ks" => mtl.specular = Color::new(parse_floats::<3>(path, line_num, args)?),
+            "Ke" => mtl.emission = Color::new(parse_floats::<3>(path, line_num, args)?),
+            "Ns" => mtl.shininess = parse_floats::<1>(path, line_num, args)?[0],
+            "Ni" => mtl.refraction_index = parse_floats::<1>(path, line_num, args)?[0],
+            "d" => mtl.dissolve = parse_floats::<1>(path, line_num, args)?[0],
+            "Tr" => mtl.dissolve = 1. - parse_floats::<1>(path, line_num, args)?[0],
+            "illum" => mtl.illum = parse_floats::<1>(path, line_num, args)?[0] as i32,
+            "Pr" => mtl.roughness = Some(parse_floats::<1>(path, line_num, args)?[0]),
+            "Pm" => mtl.metalness = Some(parse_floats::<1>(path, line_num, args)?[0]),
+            // Texture maps and other parameters are not supported yet.
+            _ => {}
+        }
+    }
+
+    if let Some((name, mtl)) = current.take() {
+        materials.insert(name, mtl);
+    }
+    Ok(materials)
+}
+
+// Accumulates the faces that share a material into a single indexed mesh, de-duplicating the
+// OBJ position/uv/normal index triples into unified vertices.
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<Point3>,
+    normals: Vec<Option<Vec3>>,
+    uvs: Vec<Option<(f64, f64)>>,
+    triangles: Vec<[usize; 3]>,
+    vertex_map: HashMap<(usize, Option<usize>, Option<usize>), usize>
+}
+
+impl MeshBuilder {
+    fn vertex(&mut self, key: (usize, Option<usize>, Option<usize>), obj: &ObjData) -> usize {
+        if let Some(&index) = self.vertex_map.get(&key) {
+            return index;
+        }
+        let (v, vt, vn) = key;
+        let index = self.positions.len();
+        self.positions.push(obj.positions[v]);
+        self.uvs.push(vt.map(|i| obj.uvs[i]));
+        self.normals.push(vn.map(|i| obj.normals[i]));
+        self.vertex_map.insert(key, index);
+        index
+    }
+
+    fn build(self) -> MeshData {
+        // Attributes are only kept if every vertex of the mesh has them.
+        let normals = self.normals.into_iter().collect::<Option<Vec<Vec3>>>().unwrap_or_default();
+        let uvs = self.uvs.into_iter().collect::<Option<Vec<(f64, f64)>>>().unwrap_or_default();
+        MeshData { positions: self.positions, normals, uvs, triangles: self.triangles }
+    }
+}
+
+#[derive(Default)]
+struct ObjData {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f64, f64)>
+}
+
+fn resolve_index(path: &Path, line_num: usize, token: &str, count: usize) -> Result<usize> {
+    // OBJ indices are 1-based, and negative indices count back from the last element.
+    let index: i64 = token.parse().map_err(|_| parse_error(path, line_num, &format!("invalid index '{}'", token)))?;
+    let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(parse_error(path, line_num, &format!("index {} out of range", index)));
+    }
+    Ok(resolved as usize)
+}
+
+pub fn load_obj(path: impl AsRef<Path>) -> Result<HittableList> {
+    let path = path.as_ref();
+    let reader = BufReader::new(File::open(path)?);
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+
+    let mut obj = ObjData::default();
+    let mut mtl_materials: HashMap<String, MtlMaterial> = HashMap::new();
+    let mut builders: HashMap<String, MeshBuilder> = HashMap::new();
+    // keeps meshes in the order their material first appeared in the file
+    let mut material_order: Vec<String> = Vec::new();
+    let mut current_material = String::new();
+
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((&keyword, args)) = tokens.split_first() else {
+            continue;
+        };
+
+        match keyword {
+            "v" => obj.positions.push(Point3::new(parse_floats::<3>(path, line_num, args)?)),
+            "vn" => obj.normals.push(unit_vector(&Vec3::new(parse_floats::<3>(path, line_num, args)?))),
+            "vt" => {
+                let [u, v] = parse_floats::<2>(path, line_num, args)?;
+                obj.uvs.push((u, v));
+            }
+            "mtllib" => {
+                for lib in args {
+                    mtl_materials.extend(load_mtl(base_dir.join(lib))?);
+                }
+            }
+            "usemtl" => current_material = args.join(" "),
+            "f" => {
+                if args.len() < 3 {
+                    return Err(parse_error(path, line_num, "face needs at least 3 vertices"));
+                }
+                let mut keys = Vec::with_capacity(args.len());
+                for arg in args {
+                    let mut parts = arg.split('/');
+                    let v = resolve_index(path, line_num, parts.next().unwrap_or(""), obj.positions.len())?;
+                    let vt = match parts.next() {
+                        Some(token) if !token.is_empty() => Some(resolve_index(path, line_num, token, obj.uvs.len())?),
+                        _ => None
+                    };
+                    let vn = match parts.next() {
+                        Some(token) if !token.is_empty() => Some(resolve_index(path, line_num, token, obj.normals.len())?),
+                        _ => None
+                    };
+                    keys.push((v, vt, vn));
+                }
+
+                if !builders.contains_key(&current_material) {
+                    material_order.push(current_material.clone());
+                }
+                let builder = builders.entry(current_material.clone()).or_default();
+                let indices: Vec<usize> = keys.into_iter().map(|key| builder.vertex(key, &obj)).collect();
+                // Triangulate polygons as a fan around the first vertex.
+                for i in 1..indices.len()-1 {
+                    builder.triangles.push([indices[0], indices[i], indices[i+1]]);
+                }
+            }
+            // Groups, smoothing groups and other statements don't affect the geometry.
+            _ => {}
+        }
+    }
+
+    let mut world = HittableList::new();
+    for name in material_order {
+        let builder = builders.remove(&name).unwrap();
+        let material = mtl_materials.get(&name).cloned().unwrap_or_default().to_material();
+        world.add(Arc::new(TriangleMesh::new(builder.build(), material)));
+    }
+    Ok(world)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load_obj;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::hittable::{Hittable, HitRecord};
+
+    fn write_temp(name: &str, contents: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("dray_obj_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_quad_with_materials() {
+        write_temp("quad.mtl", "newmtl red\nKd 0.8 0.1 0.1\n\nnewmtl mirror\nKs 0.9 0.9 0.9\nNs 1000\nillum 3\n");
+        let path = write_temp("quad.obj", "\
+mtllib quad.mtl
+v -1 -1 -2
+v 1 -1 -2
+v 1 1 -2
+v -1 1 -2
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+usemtl red
+f 1/1/1 2/2/1 3/3/1 4/4/1
+usemtl mirror
+f -4 -2 -1
+");
+        let world = load_obj(&path).unwrap();
+        assert_eq!(world.len(), 2);
+
+        let r = Ray::new(Point3::new_xyz(0.5, -0.5, 0.), Vec3::new_xyz(0., 0., -1.));
+        let mut rec = HitRecord::default();
+        assert!(world.hit(&r, Interval::new_min_max(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.t - 2.).abs() < 1e-9);
+        assert_eq!(rec.normal, Vec3::new_xyz(0., 0., 1.));
+        assert!((rec.u - 0.75).abs() < 1e-9);
+        assert!((rec.v - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_invalid_index() {
+        let path = write_temp("bad.obj", "v 0 0 0\nv 1 0 0\nf 1 2 3\n");
+        assert!(load_obj(&path).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use crate::raytracer::prelude::*;
+use crate::raytracer::camera::{Aov, Camera};
+use crate::raytracer::framebuffer::{FrameBuffer, ImageSink};
+use crate::raytracer::hittable::Hittable;
+
+pub const TILE_SIZE: i32 = 32;
+
+#[derive(Clone, Copy)]
+struct Tile {
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32
+}
+
+fn split_tiles(width: i32, height: i32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y0 in (0..height).step_by(TILE_SIZE as usize) {
+        for x0 in (0..width).step_by(TILE_SIZE as usize) {
+            tiles.push(Tile {
+                x0,
+                y0,
+                x1: i32::min(x0 + TILE_SIZE, width),
+                y1: i32::min(y0 + TILE_SIZE, height)
+            });
+        }
+    }
+    tiles
+}
+
+pub fn default_num_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+impl Camera {
+    // Renders the image with `num_threads` workers. Every sample pass is split into tiles which
+    // the workers pick up in order, so the image refines progressively as whole passes complete.
+    // The rendered tiles are merged into the frame on the calling thread, which also drives the
+    // sinks (the preview window has to stay on the thread that created it).
+    //
+    // With adaptive sampling, the passes after the first min_samples only cover the pixels that
+    // still need samples.
+    pub fn render_parallel(
+        &mut self,
+        world: &dyn Hittable,
+        frame: &mut FrameBuffer,
+        sinks: &mut [Box<dyn ImageSink>],
+        num_threads: usize
+    ) -> Result<()> {
+        self.initialize();
+
+        let camera: &Camera = self;
+        match &camera.adaptive {
+            None => camera.render_passes(world, frame, sinks, num_threads, camera.samples_per_pixel, None)?,
+            Some(adaptive) => {
+                camera.render_passes(world, frame, sinks, num_threads, adaptive.min_samples, None)?;
+                loop {
+                    let active: Vec<bool> = (0..frame.height())
+                        .flat_map(|j| (0..frame.width()).map(move |i| (i, j)))
+                        .map(|(i, j)| adaptive.needs_samples(frame, i, j))
+                        .collect();
+                    let num_active = active.iter().filter(|&&active| active).count();
+                    if num_active == 0 {
+                        break;
+                    }
+                    println!("{} pixels above the error threshold", num_active);
+                    camera.render_passes(world, frame, sinks, num_threads, 1, Some(&active))?;
+                }
+            }
+        }
+
+        frame.finish();
+        for sink in sinks.iter_mut() {
+            sink.finish(frame)?;
+        }
+        Ok(())
+    }
+
+    // Takes `passes` samples of every pixel, or only of the pixels set in `active` (in row order).
+    fn render_passes(
+        &self,
+        world: &dyn Hittable,
+        frame: &mut FrameBuffer,
+        sinks: &mut [Box<dyn ImageSink>],
+        num_threads: usize,
+        passes: i32,
+        active: Option<&[bool]>
+    ) -> Result<()> {
+        let camera = self;
+        let width = camera.image_width;
+        let is_active = |i: i32, j: i32| active.is_none_or(|active| active[(j * width + i) as usize]);
+        let tiles = split_tiles(camera.image_width, camera.image_height());
+        // Sample numbers continue from the samples the pixels already have.
+        let first_samples: Vec<i32> = (0..frame.height())
+            .flat_map(|j| (0..frame.width()).map(move |i| (i, j)))
+            .map(|(i, j)| frame.sample_count(i, j))
+            .collect();
+        let num_work_items = tiles.len() * passes.max(0) as usize;
+        let next_work_item = AtomicUsize::new(0);
+
+        thread::scope(|scope| -> Result<()> {
+            let (tx, rx) = mpsc::channel::<(Tile, Vec<(Color, Option<Aov>)>)>();
+
+            for _ in 0..usize::max(num_threads, 1) {
+                let tx = tx.clone();
+                let tiles = &tiles;
+                let next_work_item = &next_work_item;
+                let first_samples = &first_samples;
+                scope.spawn(move || {
+                    loop {
+                        let work_item = next_work_item.fetch_add(1, Ordering::Relaxed);
+                        if work_item >= num_work_items {
+                            break;
+                        }
+                        let tile = tiles[work_item % tiles.len()];
+                        let pass = (work_item / tiles.len()) as i32;
+                        let mut samples = Vec::with_capacity(((tile.x1 - tile.x0) * (tile.y1 - tile.y0)) as usize);
+                        for j in tile.y0..tile.y1 {
+                            for i in tile.x0..tile.x1 {
+                                if !is_active(i, j) {
+                                    continue;
+                                }
+                                let sample_num = first_samples[(j * width + i) as usize] + pass;
+                                let sample = camera.trace_pixel(i, j, sample_num, world);
+                                samples.push((sample.color, sample.aov));
+                            }
+                        }
+                        if tx.send((tile, samples)).is_err() {
+                            // the receiving side gave up, e.g. because a sink failed
+                            break;
+                        }
+                    }
+                });
+            }
+            // Only the workers hold senders now, so the loop below ends once they are all done.
+            drop(tx);
+
+            for (tiles_done, (tile, samples)) in rx.iter().enumerate() {
+                let mut pixel_samples = samples.iter();
+                for j in tile.y0..tile.y1 {
+                    for i in tile.x0..tile.x1 {
+                        if !is_active(i, j) {
+                            continue;
+                        }
+                        let (color, aov) = pixel_samples.next().unwrap();
+                        frame.add_sample(i as usize, j as usize, color);
+                        if let Some(aov) = aov {
+                            frame.add_aov(i as usize, j as usize, aov);
+                        }
+                    }
+                }
+                for sink in sinks.iter_mut() {
+                    sink.update(frame)?;
+                }
+                if (tiles_done + 1) % tiles.len() == 0 {
+                    frame.finish_pass();
+                    if active.is_none() {
+                        println!("sample {} / {}", (tiles_done + 1) / tiles.len(), passes);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,201 @@
+//! Probability densities over directions, used to importance sample lights and materials.
+//!
+//! Adapted from https://raytracing.github.io/books/RayTracingTheRestOfYourLife.html
+
+use crate::raytracer::prelude::*;
+use crate::raytracer::background::Background;
+use crate::raytracer::hittable::Hittable;
+
+// Orthonormal basis with `w` along a given direction.
+pub struct Onb {
+    axis: [Vec3; 3]
+}
+
+impl Onb {
+    pub fn new(n: &Vec3) -> Self {
+        let w = unit_vector(n);
+        let a = if w.x().abs() > 0.9 { Vec3::new_xyz(0., 1., 0.) } else { Vec3::new_xyz(1., 0., 0.) };
+        let v = unit_vector(&cross(&w, &a));
+        let u = cross(&w, &v);
+        Onb { axis: [u, v, w] }
+    }
+
+    pub fn u(&self) -> &Vec3 { &self.axis[0] }
+    pub fn v(&self) -> &Vec3 { &self.axis[1] }
+    pub fn w(&self) -> &Vec3 { &self.axis[2] }
+
+    // Converts from coordinates in this basis to world coordinates.
+    pub fn transform(&self, v: &Vec3) -> Vec3 {
+        v.x() * self.axis[0] + v.y() * self.axis[1] + v.z() * self.axis[2]
+    }
+}
+
+pub trait Pdf {
+    // density per unit solid angle of generating `direction`
+    fn value(&self, direction: &Vec3) -> f64;
+    fn generate(&self) -> Vec3;
+}
+
+// Uniform over all directions.
+pub struct SpherePdf;
+
+impl Pdf for SpherePdf {
+    fn value(&self, _direction: &Vec3) -> f64 {
+        1. / (4. * PI)
+    }
+
+    fn generate(&self) -> Vec3 {
+        random_unit_vector()
+    }
+}
+
+// Proportional to the cosine with a normal, as scattered by Lambertian surfaces.
+pub struct CosinePdf {
+    uvw: Onb
+}
+
+impl CosinePdf {
+    pub fn new(w: &Vec3) -> Self {
+        CosinePdf { uvw: Onb::new(w) }
+    }
+}
+
+impl Pdf for CosinePdf {
+    fn value(&self, direction: &Vec3) -> f64 {
+        let cosine_theta = dot(&unit_vector(direction), self.uvw.w());
+        f64::max(0., cosine_theta / PI)
+    }
+
+    fn generate(&self) -> Vec3 {
+        self.uvw.transform(&random_cosine_direction())
+    }
+}
+
+// Directions from `origin` towards `objects`, using Hittable::pdf_value and Hittable::random.
+pub struct HittablePdf<'a> {
+    objects: &'a dyn Hittable,
+    origin: Point3,
+    // of the ray the directions are for, which moving objects depend on
+    time: f64
+}
+
+impl<'a> HittablePdf<'a> {
+    pub fn new(objects: &'a dyn Hittable, origin: &Point3, time: f64) -> Self {
+        HittablePdf { objects, origin: *origin, time }
+    }
+}
+
+impl Pdf for HittablePdf<'_> {
+    fn value(&self, direction: &Vec3) -> f64 {
+        self.objects.pdf_value(&self.origin, direction, self.time)
+    }
+
+    fn generate(&self) -> Vec3 {
+        self.objects.random(&self.origin, self.time)
+    }
+}
+
+// Directions towards the bright parts of a background.
+pub struct BackgroundPdf<'a> {
+    background: &'a dyn Background
+}
+
+impl<'a> BackgroundPdf<'a> {
+    pub fn new(background: &'a dyn Background) -> Self {
+        BackgroundPdf { background }
+    }
+}
+
+impl Pdf for BackgroundPdf<'_> {
+    fn value(&self, direction: &Vec3) -> f64 {
+        self.background.pdf_value(direction)
+    }
+
+    fn generate(&self) -> Vec3 {
+        self.background.random()
+    }
+}
+
+// Picks one of its densities with equal probability.
+pub struct MixturePdf<'a> {
+    pdfs: Vec<Box<dyn Pdf + 'a>>
+}
+
+impl<'a> MixturePdf<'a> {
+    pub fn new(pdfs: Vec<Box<dyn Pdf + 'a>>) -> Self {
+        MixturePdf { pdfs }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pdfs.is_empty()
+    }
+}
+
+impl Pdf for MixturePdf<'_> {
+    fn value(&self, direction: &Vec3) -> f64 {
+        if self.pdfs.is_empty() {
+            return 0.;
+        }
+        self.pdfs.iter().map(|pdf| pdf.value(direction)).sum::<f64>() / self.pdfs.len() as f64
+    }
+
+    fn generate(&self) -> Vec3 {
+        let idx = ((random_f64() * self.pdfs.len() as f64) as usize).min(self.pdfs.len() - 1);
+        self.pdfs[idx].generate()
+    }
+}
+
+// Multiple importance sampling weight of a sample drawn with density `pdf`, when `other_pdf` is
+// the density of the other strategy that could have produced it.
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0. { 0. } else { a / (a + b) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::rng;
+    use super::{CosinePdf, HittablePdf, MixturePdf, Pdf, SpherePdf};
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::hittable_list::HittableList;
+    use crate::raytracer::material::DiffuseLight;
+    use crate::raytracer::quad::Quad;
+    use crate::raytracer::sphere::Sphere;
+
+    // Monte Carlo estimate of the integral of `pdf` over the sphere, which should be 1.
+    fn integrate(pdf: &dyn Pdf) -> f64 {
+        let n = 200_000;
+        (0..n).map(|_| pdf.value(&random_unit_vector()) * 4. * PI).sum::<f64>() / n as f64
+    }
+
+    #[test]
+    fn test_pdfs_integrate_to_one() {
+        rng::seed_thread(2);
+        let light = Arc::new(DiffuseLight::new(&Color::new([1., 1., 1.])));
+        let mut lights = HittableList::new();
+        lights.add(Arc::new(Quad::new(
+            &Point3::new_xyz(-1., 2., -1.), &Vec3::new_xyz(2., 0., 0.), &Vec3::new_xyz(0., 0., 2.), light.clone())));
+        lights.add(Arc::new(Sphere::new(&Point3::new_xyz(3., 0., 0.), 1., light)));
+        let origin = Point3::default();
+
+        let pdfs: Vec<Box<dyn Pdf>> = vec![
+            Box::new(SpherePdf),
+            Box::new(CosinePdf::new(&Vec3::new_xyz(1., 1., 0.))),
+            Box::new(HittablePdf::new(&lights, &origin, 0.)),
+        ];
+        let mixture = MixturePdf::new(pdfs);
+        assert!((integrate(&mixture) - 1.).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_generated_directions_have_density() {
+        rng::seed_thread(1);
+        // every direction generated towards the lights has to be one pdf_value accounts for
+        let light = Arc::new(DiffuseLight::new(&Color::new([1., 1., 1.])));
+        let quad = Quad::new(&Point3::new_xyz(-1., 2., -1.), &Vec3::new_xyz(2., 0., 0.), &Vec3::new_xyz(0., 0., 2.), light);
+        let pdf = HittablePdf::new(&quad, &Point3::default(), 0.);
+        for _ in 0..1000 {
+            assert!(pdf.value(&pdf.generate()) > 0.);
+        }
+    }
+}
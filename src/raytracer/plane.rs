@@ -0,0 +1,63 @@
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::material::Material;
+
+// Infinite plane through `point`. Texture coordinates repeat every unit along two tangent
+// directions of the plane.
+#[derive(Serialize, Deserialize)]
+pub struct Plane {
+    point: Point3,
+    normal: Vec3,
+    mat: Arc<dyn Material>,
+    tangent: Vec3,
+    bitangent: Vec3
+}
+
+impl Plane {
+    pub fn new(point: &Point3, normal: &Vec3, mat: Arc<dyn Material>) -> Self {
+        let normal = unit_vector(normal);
+        // Any vector that isn't parallel to the normal gives a tangent.
+        let a = if normal.x().abs() > 0.9 { Vec3::new_xyz(0., 1., 0.) } else { Vec3::new_xyz(1., 0., 0.) };
+        let tangent = unit_vector(&cross(&a, &normal));
+        let bitangent = cross(&normal, &tangent);
+        Plane { point: *point, normal, mat, tangent, bitangent }
+    }
+}
+
+#[typetag::serde]
+impl Hittable for Plane {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        let denom = dot(&self.normal, r.direction());
+        if denom.abs() < 1e-8 {
+            return false;
+        }
+
+        let t = dot(&self.normal, &(self.point - *r.origin())) / denom;
+        if !ray_t.surrounds(t) {
+            return false;
+        }
+
+        rec.t = t;
+        rec.p = r.at(t);
+        let offset = rec.p - self.point;
+        rec.u = dot(&offset, &self.tangent).rem_euclid(1.);
+        rec.v = dot(&offset, &self.bitangent).rem_euclid(1.);
+        rec.mat = self.mat.clone();
+        rec.set_face_normal(r, &self.normal);
+        true
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        // The plane is unbounded, except along an axis it is perpendicular to.
+        let axes = std::array::from_fn(|n| {
+            let perpendicular = (0..3).all(|m| m == n || self.normal[m] == 0.);
+            if perpendicular {
+                Interval::new_min_max(self.point[n], self.point[n])
+            } else {
+                Interval::UNIVERSE
+            }
+        });
+        BoundingBox::new(axes)
+    }
+}
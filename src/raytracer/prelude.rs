@@ -3,7 +3,7 @@
 // Re-export common types from our modules.
 pub use crate::raytracer::vec3::*;
 pub use crate::raytracer::ray::Ray;
-pub use crate::raytracer::colors::{Color, write_color};
+pub use crate::raytracer::colors::Color;
 pub use crate::raytracer::interval::Interval;
 
 // Re-export common standard library items.
@@ -19,9 +19,10 @@ pub fn degrees_to_radians(degrees: f64) -> f64 {
     degrees * PI / 180.0
 }
 
+// see raytracer::rng for where the numbers come from
 #[inline]
 pub fn random_f64() -> f64 {
-    rand::random()
+    crate::raytracer::rng::next_f64()
 }
 
 #[inline]
@@ -0,0 +1,121 @@
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::hittable_list::HittableList;
+use crate::raytracer::material::Material;
+
+// Parallelogram with a corner at q and edges u and v, adapted from
+// https://raytracing.github.io/books/RayTracingTheNextWeek.html
+#[derive(Serialize, Deserialize)]
+pub struct Quad {
+    q: Point3,
+    u: Vec3,
+    v: Vec3,
+    mat: Arc<dyn Material>,
+    // cached values of the plane containing the quad
+    w: Vec3,
+    normal: Vec3,
+    d: f64,
+    area: f64,
+    bbox: BoundingBox
+}
+
+impl Quad {
+    pub fn new(q: &Point3, u: &Vec3, v: &Vec3, mat: Arc<dyn Material>) -> Self {
+        let n = cross(u, v);
+        let normal = unit_vector(&n);
+        let bbox = BoundingBox::new_enclosing(
+            &BoundingBox::new_points(q, &(*q + *u + *v)),
+            &BoundingBox::new_points(&(*q + *u), &(*q + *v))
+        );
+        Quad {
+            q: *q,
+            u: *u,
+            v: *v,
+            mat,
+            w: n / dot(&n, &n),
+            normal,
+            d: dot(&normal, q),
+            area: n.length(),
+            bbox
+        }
+    }
+}
+
+#[typetag::serde]
+impl Hittable for Quad {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        // No hit if the ray is parallel to the plane.
+        let denom = dot(&self.normal, r.direction());
+        if denom.abs() < 1e-8 {
+            return false;
+        }
+
+        let t = (self.d - dot(&self.normal, r.origin())) / denom;
+        if !ray_t.contains(t) {
+            return false;
+        }
+
+        // Express the hit point in the (u, v) frame of the quad, which doubles as its texture
+        // coordinates, and check that it lies inside.
+        let intersection = r.at(t);
+        let planar_hitpt_vector = intersection - self.q;
+        let alpha = dot(&self.w, &cross(&planar_hitpt_vector, &self.v));
+        let beta = dot(&self.w, &cross(&self.u, &planar_hitpt_vector));
+        if !(0. ..=1.).contains(&alpha) || !(0. ..=1.).contains(&beta) {
+            return false;
+        }
+
+        rec.t = t;
+        rec.p = intersection;
+        rec.u = alpha;
+        rec.v = beta;
+        rec.mat = self.mat.clone();
+        rec.set_face_normal(r, &self.normal);
+        true
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.bbox.clone()
+    }
+
+    // Points are sampled uniformly over the area, so the density per unit solid angle is the
+    // inverse area scaled by distance squared over cosine.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3, time: f64) -> f64 {
+        let mut rec = HitRecord::default();
+        if !self.hit(&Ray::new_time(*origin, *direction, time), Interval::new_min_max(0.001, f64::INFINITY), &mut rec) {
+            return 0.;
+        }
+
+        let distance_squared = rec.t * rec.t * direction.length_squared();
+        let cosine = (dot(direction, &rec.normal) / direction.length()).abs();
+        distance_squared / (cosine * self.area)
+    }
+
+    fn random(&self, origin: &Point3, _time: f64) -> Vec3 {
+        let p = self.q + (random_f64() * self.u) + (random_f64() * self.v);
+        p - *origin
+    }
+}
+
+// Returns the axis-aligned box with opposite corners a and b, as its six faces.
+pub fn make_box(a: &Point3, b: &Point3, mat: Arc<dyn Material>) -> HittableList {
+    let mut sides = HittableList::new();
+
+    let min = min_vec(a, b);
+    let max = max_vec(a, b);
+
+    let dx = Vec3::new_xyz(max.x() - min.x(), 0., 0.);
+    let dy = Vec3::new_xyz(0., max.y() - min.y(), 0.);
+    let dz = Vec3::new_xyz(0., 0., max.z() - min.z());
+
+    // front, right, back, left, top and bottom
+    sides.add(Arc::new(Quad::new(&Point3::new_xyz(min.x(), min.y(), max.z()), &dx, &dy, mat.clone())));
+    sides.add(Arc::new(Quad::new(&Point3::new_xyz(max.x(), min.y(), max.z()), &(-dz), &dy, mat.clone())));
+    sides.add(Arc::new(Quad::new(&Point3::new_xyz(max.x(), min.y(), min.z()), &(-dx), &dy, mat.clone())));
+    sides.add(Arc::new(Quad::new(&Point3::new_xyz(min.x(), min.y(), min.z()), &dz, &dy, mat.clone())));
+    sides.add(Arc::new(Quad::new(&Point3::new_xyz(min.x(), max.y(), max.z()), &dx, &(-dz), mat.clone())));
+    sides.add(Arc::new(Quad::new(&Point3::new_xyz(min.x(), min.y(), min.z()), &dx, &dz, mat)));
+
+    sides
+}
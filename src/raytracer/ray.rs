@@ -4,15 +4,22 @@ use crate::raytracer::prelude::*;
 pub struct Ray {
     origin: Point3,
     direction: Vec3,
+    // moment within the camera shutter interval the ray was sent at, for moving objects
+    time: f64,
 }
 
 impl Ray {
     pub fn new(origin: Point3, direction: Vec3) -> Self {
-        Ray{origin, direction}
+        Ray{origin, direction, time: 0.}
+    }
+
+    pub fn new_time(origin: Point3, direction: Vec3, time: f64) -> Self {
+        Ray{origin, direction, time}
     }
 
     pub fn origin(&self) -> &Point3 { &self.origin }
     pub fn direction(&self) -> &Vec3 { &self.direction }
+    pub fn time(&self) -> f64 { self.time }
 
     pub fn at(&self, t: f64) -> Point3 { 
         self.origin + t*self.direction
@@ -0,0 +1,143 @@
+//! Reproducible random numbers.
+//!
+//! random_f64 draws from a per-thread counter-based generator: the n-th number of a stream is a
+//! hash of the stream's key and n. Renderers switch to a stream keyed by the seed, pixel, sample
+//! and bounce (see Camera::get_ray and Camera::ray_color_iteration) for each step of a path, so a
+//! path takes the same random decisions on whichever thread or server takes the step. Outside of
+//! those, numbers come from a stream keyed randomly per thread, which debug builds reject, so a
+//! draw that was left out of every scope fails loudly instead of making renders differ.
+//!
+//! With a Sampler, the first numbers of the stream of a path step come from the sample pattern
+//! instead (see raytracer::sampler).
+
+use std::cell::RefCell;
+use crate::raytracer::prelude::*;
+use crate::raytracer::sampler::{SampleId, Sampler, DIMS_PER_STREAM};
+
+#[derive(Clone, Copy)]
+pub struct Rng {
+    key: u64,
+    counter: u64
+}
+
+impl Rng {
+    pub fn new(key: u64) -> Self {
+        Rng { key, counter: 0 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.counter += 1;
+        hash(&[self.key, self.counter])
+    }
+
+    // uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+struct Stream {
+    rng: Rng,
+    // the pattern, the sample and the first dimension of the step
+    sampled: Option<(Arc<dyn Sampler>, SampleId, u32)>,
+    // false for the unseeded stream a thread starts with
+    seeded: bool
+}
+
+thread_local! {
+    static STREAM: RefCell<Stream> = RefCell::new(Stream { rng: Rng::new(rand::random()), sampled: None, seeded: false });
+}
+
+// Finalizer of the SplitMix64 generator, which scrambles every bit of the input into all of the
+// output bits.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Hash of a sequence of values, for the keys of streams.
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e37_79b9_7f4a_7c15, |h, v| mix(h ^ mix(*v)))
+}
+
+// Next number of the current stream of this thread.
+pub fn next_f64() -> f64 {
+    STREAM.with_borrow_mut(|stream| {
+        debug_assert!(stream.seeded, "random number drawn outside of rng::with_rng and rng::with_sampler");
+        let dim = stream.rng.counter as u32;
+        if let Some((sampler, id, first_dim)) = &stream.sampled
+            && dim < DIMS_PER_STREAM
+            && let Some(x) = sampler.sample(id, first_dim + dim)
+        {
+            stream.rng.counter += 1;
+            return x;
+        }
+        stream.rng.next_f64()
+    })
+}
+
+// Skips the dimensions of the current stream before `dim`, so the next number comes from the
+// same dimension however many were drawn before.
+pub fn skip_to(dim: u32) {
+    STREAM.with_borrow_mut(|stream| stream.rng.counter = stream.rng.counter.max(dim as u64));
+}
+
+fn with_stream<T>(stream: Stream, f: impl FnOnce() -> T) -> T {
+    let previous = STREAM.replace(stream);
+    let result = f();
+    STREAM.set(previous);
+    result
+}
+
+// Runs `f` with random_f64 drawing from `rng`, then goes back to the previous stream.
+pub fn with_rng<T>(rng: Rng, f: impl FnOnce() -> T) -> T {
+    with_stream(Stream { rng, sampled: None, seeded: true }, f)
+}
+
+// Runs `f` with random_f64 drawing from the dimensions of `sampler` starting at `first_dim` for
+// sample `id`, and from a stream keyed by both past those, then goes back to the previous stream.
+// Without a sampler, all numbers come from the keyed stream.
+pub fn with_sampler<T>(sampler: Option<&Arc<dyn Sampler>>, id: SampleId, first_dim: u32, f: impl FnOnce() -> T) -> T {
+    let rng = Rng::new(hash(&[id.pixel, id.index as u64, first_dim as u64]));
+    let sampled = sampler.map(|sampler| (sampler.clone(), id, first_dim));
+    with_stream(Stream { rng, sampled, seeded: true }, f)
+}
+
+// Switches this thread to the stream keyed by `key` for good, for tests drawing random numbers
+// outside of a render.
+#[cfg(test)]
+pub fn seed_thread(key: u64) {
+    STREAM.set(Stream { rng: Rng::new(key), sampled: None, seeded: true });
+}
+
+// Fisher-Yates shuffle drawing from `rng`.
+pub fn shuffle<T>(items: &mut [T], rng: &mut Rng) {
+    for i in (1..items.len()).rev() {
+        let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_f64, with_rng, Rng};
+
+    #[test]
+    fn test_streams_repeat() {
+        let draw = |key| with_rng(Rng::new(key), || (0..4).map(|_| next_f64()).collect::<Vec<f64>>());
+        assert_eq!(draw(7), draw(7));
+        assert_ne!(draw(7), draw(8));
+
+        // the previous stream continues where it was
+        let mut expected = Rng::new(1);
+        with_rng(Rng::new(1), || {
+            assert_eq!(next_f64(), expected.next_f64());
+            draw(2);
+            assert_eq!(next_f64(), expected.next_f64());
+        });
+
+        let mean = with_rng(Rng::new(3), || (0..100_000).map(|_| next_f64()).sum::<f64>() / 100_000.);
+        assert!((mean - 0.5).abs() < 0.01);
+    }
+}
@@ -0,0 +1,256 @@
+//! Sample patterns for the random numbers of a pixel.
+//!
+//! Every step of a path (the camera ray, then each bounce) draws its random numbers from
+//! DIMS_PER_STREAM dimensions of its own. The n-th sample of a pixel takes the n-th point of the
+//! pattern in those dimensions, so e.g. the first bounces of the samples of a pixel spread evenly
+//! over the BSDF instead of clumping at random. Draws past the pattern fall back to plain random
+//! numbers, see raytracer::rng.
+
+use crate::raytracer::prelude::*;
+use crate::raytracer::rng::{self, Rng};
+
+// Dimensions of each step of a path. Materials take at most 3 numbers to scatter, and light
+// samples start at LIGHT_DIM.
+pub const DIMS_PER_STREAM: u32 = 8;
+pub const LIGHT_DIM: u32 = 4;
+
+// Sample `index` of a pixel, out of the `count` it's expected to get.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct SampleId {
+    // key of the pixel, different for every pixel and seed
+    pub pixel: u64,
+    pub index: u32,
+    pub count: u32
+}
+
+#[typetag::serde(tag = "type")]
+pub trait Sampler: Send + Sync {
+    // Coordinate `dim` of sample `id`, in [0, 1), or None where the pattern doesn't reach.
+    fn sample(&self, id: &SampleId, dim: u32) -> Option<f64>;
+}
+
+// Uniform random numbers over [0, 1), different for every pixel and dimension.
+fn hash_f64(values: &[u64]) -> f64 {
+    Rng::new(rng::hash(values)).next_f64()
+}
+
+// Plain random numbers, as without a sampler.
+#[derive(Serialize, Deserialize)]
+pub struct Independent;
+
+#[typetag::serde]
+impl Sampler for Independent {
+    fn sample(&self, _id: &SampleId, _dim: u32) -> Option<f64> {
+        None
+    }
+}
+
+// Jittered strata, one per sample, in a random order for every pixel and pair of dimensions.
+// Pairs of dimensions are stratified together on a grid when the sample count is a square, and
+// each dimension on its own otherwise. Samples past the count are plain random.
+#[derive(Serialize, Deserialize)]
+pub struct Stratified;
+
+#[typetag::serde]
+impl Sampler for Stratified {
+    fn sample(&self, id: &SampleId, dim: u32) -> Option<f64> {
+        if id.index >= id.count {
+            return None;
+        }
+        let jitter = hash_f64(&[id.pixel, id.index as u64, dim as u64]);
+        let side = (id.count as f64).sqrt() as u32;
+        if side * side == id.count {
+            let cell = permute(id.index, id.count, rng::hash(&[id.pixel, (dim / 2) as u64]) as u32);
+            let stratum = if dim.is_multiple_of(2) { cell % side } else { cell / side };
+            Some((stratum as f64 + jitter) / side as f64)
+        } else {
+            let stratum = permute(id.index, id.count, rng::hash(&[id.pixel, dim as u64]) as u32);
+            Some((stratum as f64 + jitter) / id.count as f64)
+        }
+    }
+}
+
+// Halton sequence, rotated by a random offset per pixel and dimension so pixels don't repeat
+// the same pattern. Covers the first PRIMES.len() dimensions, past which its dimensions correlate
+// too much to be useful anyway.
+#[derive(Serialize, Deserialize)]
+pub struct Halton;
+
+const PRIMES: [u32; 64] = first_primes();
+
+const fn first_primes<const N: usize>() -> [u32; N] {
+    let mut primes = [0; N];
+    let mut found = 0;
+    let mut candidate = 2;
+    while found < N {
+        let mut d = 2;
+        while d * d <= candidate && candidate % d != 0 {
+            d += 1;
+        }
+        if d * d > candidate {
+            primes[found] = candidate;
+            found += 1;
+        }
+        candidate += 1;
+    }
+    primes
+}
+
+fn radical_inverse(mut index: u32, base: u32) -> f64 {
+    let inv_base = 1. / base as f64;
+    let (mut result, mut scale) = (0., inv_base);
+    while index > 0 {
+        result += (index % base) as f64 * scale;
+        index /= base;
+        scale *= inv_base;
+    }
+    result
+}
+
+#[typetag::serde]
+impl Sampler for Halton {
+    fn sample(&self, id: &SampleId, dim: u32) -> Option<f64> {
+        let base = *PRIMES.get(dim as usize)?;
+        Some((radical_inverse(id.index, base) + hash_f64(&[id.pixel, dim as u64])).fract())
+    }
+}
+
+// Sobol sequence with Owen scrambling, after Burley, "Practical Hash-based Owen Scrambling"
+// (2020). Dimensions come in groups of 4 Sobol dimensions, each with its own scrambling and
+// order of the samples, which keeps every group well stratified without needing the direction
+// numbers of hundreds of dimensions.
+#[derive(Serialize, Deserialize)]
+pub struct Sobol;
+
+// Direction numbers of the first 4 dimensions, from Joe and Kuo's new-joe-kuo-6.21201 table.
+const SOBOL_DIRECTIONS: [[u32; 32]; 4] = [
+    sobol_directions(0, 0, [0; 3]),
+    sobol_directions(1, 0, [1, 0, 0]),
+    sobol_directions(2, 1, [1, 3, 0]),
+    sobol_directions(3, 1, [1, 3, 1])
+];
+
+// Direction numbers from the degree `s` primitive polynomial with inner coefficients `a` and the
+// initial numbers `m`. Degree 0 stands for the first dimension, the van der Corput sequence.
+const fn sobol_directions(s: usize, a: u32, m: [u32; 3]) -> [u32; 32] {
+    let mut v = [0; 32];
+    let mut k = 0;
+    while k < 32 {
+        if s == 0 {
+            v[k] = 1 << (31 - k);
+        } else if k < s {
+            v[k] = m[k] << (31 - k);
+        } else {
+            v[k] = v[k - s] ^ (v[k - s] >> s);
+            let mut l = 1;
+            while l < s {
+                if (a >> (s - 1 - l)) & 1 == 1 {
+                    v[k] ^= v[k - l];
+                }
+                l += 1;
+            }
+        }
+        k += 1;
+    }
+    v
+}
+
+fn sobol(index: u32, dim: usize) -> u32 {
+    let mut x = 0;
+    for (k, direction) in SOBOL_DIRECTIONS[dim].iter().enumerate() {
+        if (index >> k) & 1 == 1 {
+            x ^= direction;
+        }
+    }
+    x
+}
+
+// Permutation of the bits of `x` in which each bit only depends on itself and the bits below it,
+// from Laine and Karras, "Stratified Sampling for Stochastic Transparency" (2011).
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+// Owen scrambling of a 32 bit fraction: each bit only depends on itself and the bits above it.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Random permutation of 0..count, applied to `index`. The low bits of a Laine-Karras permutation
+// permute the numbers below the next power of two, and repeating it until the result is below
+// count permutes just those.
+fn permute(index: u32, count: u32, seed: u32) -> u32 {
+    let mask = count.next_power_of_two().wrapping_sub(1);
+    let mut x = index;
+    loop {
+        x = laine_karras_permutation(x, seed) & mask;
+        if x < count {
+            return x;
+        }
+    }
+}
+
+#[typetag::serde]
+impl Sampler for Sobol {
+    fn sample(&self, id: &SampleId, dim: u32) -> Option<f64> {
+        let seed = rng::hash(&[id.pixel, (dim / 4) as u64]);
+        let index = nested_uniform_scramble(id.index, seed as u32);
+        let x = nested_uniform_scramble(sobol(index, (dim % 4) as usize), (seed >> 32) as u32 ^ dim);
+        Some(x as f64 / (1u64 << 32) as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Halton, Independent, SampleId, Sampler, Sobol, Stratified};
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::rng::{self, Rng};
+
+    // Mean squared error of estimating the area of the quarter disk x^2 + y^2 < 1 with 16 samples
+    // of dimensions 6 and 7, over many pixels.
+    fn quarter_disk_error(sampler: &dyn Sampler) -> f64 {
+        let pixels = 2000;
+        (0..pixels).map(|pixel| {
+            let mut fallback = Rng::new(rng::hash(&[pixel]));
+            let mut coordinate = |id: &SampleId, dim| {
+                let x = sampler.sample(id, dim).unwrap_or_else(|| fallback.next_f64());
+                assert!((0. ..1.).contains(&x));
+                x
+            };
+            let inside = (0..16).filter(|&index| {
+                let id = SampleId { pixel, index, count: 16 };
+                let (x, y) = (coordinate(&id, 6), coordinate(&id, 7));
+                x * x + y * y < 1.
+            }).count();
+            (inside as f64 / 16. - PI / 4.).powi(2)
+        }).sum::<f64>() / pixels as f64
+    }
+
+    #[test]
+    fn test_samplers_beat_random() {
+        let random = quarter_disk_error(&Independent);
+        for sampler in [&Stratified as &dyn Sampler, &Halton, &Sobol] {
+            let error = quarter_disk_error(sampler);
+            assert!(error < 0.5 * random, "{} vs {}", error, random);
+        }
+    }
+
+    #[test]
+    fn test_samplers_are_uniform() {
+        // every sample on its own has to be uniform, or the estimates would be biased
+        for sampler in [&Stratified as &dyn Sampler, &Halton, &Sobol] {
+            for (index, count) in [(0, 16), (5, 16), (3, 7)] {
+                let n = 20000;
+                let mean = (0..n)
+                    .map(|pixel| sampler.sample(&SampleId { pixel, index, count }, 3).unwrap())
+                    .sum::<f64>() / n as f64;
+                assert!((mean - 0.5).abs() < 0.01, "{}", mean);
+            }
+        }
+    }
+}
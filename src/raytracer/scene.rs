@@ -0,0 +1,442 @@
+//! Declarative scene descriptions, loaded from JSON files.
+//!
+//! A scene file holds the camera parameters, a table of named materials and the list of objects
+//! referencing those materials by name, e.g.
+//!
+//! ```json
+//! {
+//!     "camera": { "image_width": 400, "lookfrom": [13, 2, 3] },
+//!     "materials": {
+//!         "ground": { "type": "Lambertian", "albedo": [0.5, 0.5, 0.5] }
+//!     },
+//!     "objects": [
+//!         { "type": "Sphere", "center": [0, -1000, 0], "radius": 1000, "material": "ground" }
+//!     ]
+//! }
+//! ```
+//!
+//! Geometry that appears several times goes in the `shared` table, as named lists of objects that
+//! `Instance` objects place in the world with a list of transforms, e.g.
+//! `{ "type": "Instance", "object": "tree", "transform": [{ "RotateY": 30 }, { "Translate": [4, 0, 1] }] }`.
+//!
+//! Camera fields that are left out keep the values from `Camera::new`. An optional `background`
+//! replaces the default sky, e.g. `{ "type": "EnvironmentMap", "path": "sky.hdr" }`.
+//!
+//! Whatever is random in the scene, such as noise textures or the `RandomSpheres` grid, comes out
+//! the same for the same camera `seed`.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use crate::raytracer::prelude::*;
+use crate::raytracer::background::{Background, Constant, EnvironmentMap, Gradient};
+use crate::raytracer::bvh::Bvh;
+use crate::raytracer::camera::Camera;
+use crate::raytracer::constant_medium::ConstantMedium;
+use crate::raytracer::hittable::Hittable;
+use crate::raytracer::hittable_list::HittableList;
+use crate::raytracer::mat4::Mat4;
+use crate::raytracer::material::{Dialectric, Lambertian, Material, Metal};
+use crate::raytracer::motion::LinearMotion;
+use crate::raytracer::obj_loader::load_obj;
+use crate::raytracer::plane::Plane;
+use crate::raytracer::rng::{self, Rng};
+use crate::raytracer::quad::{make_box, Quad};
+use crate::raytracer::shared::{SharedHittable, SharedObjects};
+use crate::raytracer::sphere::Sphere;
+use crate::raytracer::tagged::Tagged;
+use crate::raytracer::transform::Transform;
+use crate::raytracer::triangle::Triangle;
+
+pub const DEFAULT_SCENE: &str = "scenes/final_scene.json";
+
+// Stream ids of the random numbers used while loading and building scenes, see raytracer::rng.
+const LOAD_STREAM: u64 = 1;
+const BUILD_STREAM: u64 = 2;
+
+// Just the seed of a scene file, which has to be known before loading the rest.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SeedOnly {
+    camera: CameraSeed
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct CameraSeed {
+    seed: u64
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SceneObject {
+    Sphere { center: Point3, radius: f64, material: String },
+    Triangle { vertices: [Point3; 3], material: String },
+    // Parallelogram with a corner at `corner` and edges `u` and `v`.
+    Quad { corner: Point3, u: Vec3, v: Vec3, material: String },
+    Plane { point: Point3, normal: Vec3, material: String },
+    // Axis-aligned box with opposite corners `a` and `b`.
+    Box { a: Point3, b: Point3, material: String },
+    // Wavefront OBJ file, relative to the scene file, using the materials from its MTL files.
+    Obj { path: String },
+    // Volume of constant density inside `boundary`, usually with an Isotropic material.
+    Medium { boundary: Box<SceneObject>, density: f64, material: String },
+    // `object` moving by `offset` between times 0 and 1, blurred by the camera shutter interval.
+    Moving { object: Box<SceneObject>, offset: Vec3 },
+    // Copy of the `shared` object list named `object`, with the transforms applied in order.
+    Instance {
+        object: String,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    },
+    // The grid of small random spheres from the cover of "Ray Tracing in One Weekend", skipping
+    // any sphere that would land within `clearance` of one of the `avoid` points.
+    RandomSpheres {
+        #[serde(default = "default_grid_size")]
+        grid_size: i32,
+        #[serde(default)]
+        avoid: Vec<Point3>,
+        #[serde(default = "default_clearance")]
+        clearance: f64
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum TransformOp {
+    Translate(Vec3),
+    // rotation around the y axis, in degrees
+    RotateY(f64),
+    Rotate { axis: Vec3, angle: f64 },
+    Scale(Vec3),
+    Matrix([[f64; 4]; 4]),
+}
+
+impl TransformOp {
+    fn matrix(&self) -> Mat4 {
+        match self {
+            TransformOp::Translate(offset) => Mat4::translation(offset),
+            TransformOp::RotateY(angle) => Mat4::rotation_y(*angle),
+            TransformOp::Rotate { axis, angle } => Mat4::rotation(axis, *angle),
+            TransformOp::Scale(factors) => Mat4::scaling(factors),
+            TransformOp::Matrix(m) => Mat4::new(*m),
+        }
+    }
+}
+
+fn default_grid_size() -> i32 { 11 }
+fn default_clearance() -> f64 { 0.9 }
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SceneBackground {
+    Constant { color: Color },
+    Gradient { bottom: Color, top: Color },
+    // Equirectangular image (e.g. a Radiance .hdr file), relative to the scene file.
+    EnvironmentMap {
+        path: String,
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+        #[serde(default)]
+        rotation: f64
+    },
+}
+
+fn default_intensity() -> f64 { 1. }
+
+#[derive(Serialize, Deserialize)]
+pub struct SceneDescription {
+    pub camera: Camera,
+    #[serde(default)]
+    pub background: Option<SceneBackground>,
+    #[serde(default)]
+    pub materials: HashMap<String, Arc<dyn Material>>,
+    #[serde(default)]
+    pub shared: HashMap<String, Vec<SceneObject>>,
+    pub objects: Vec<SceneObject>,
+    // Objects that are rendered like `objects`, and also sampled explicitly as lights.
+    #[serde(default)]
+    pub lights: Vec<SceneObject>,
+}
+
+fn scene_error(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+impl SceneDescription {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        // any error shows up in the full parse below
+        let seed = serde_json::from_str::<SeedOnly>(&contents).unwrap_or_default().camera.seed;
+        let scene = rng::with_rng(Rng::new(rng::hash(&[seed, LOAD_STREAM])), || serde_json::from_str(&contents));
+        scene.map_err(|e| scene_error(format!("{}: {}", path.display(), e)))
+    }
+
+    fn material(&self, name: &str) -> Result<Arc<dyn Material>> {
+        self.materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| scene_error(format!("unknown material '{}'", name)))
+    }
+
+    // Creates the camera and world described by the scene, along with the shared objects the
+    // instances in the world reference. Relative paths (e.g. OBJ files and image textures) are
+    // resolved against `base_dir`.
+    pub fn build(&self, base_dir: &Path) -> Result<(Camera, HittableList, SharedObjects)> {
+        rng::with_rng(Rng::new(rng::hash(&[self.camera.seed, BUILD_STREAM])), || self.build_seeded(base_dir))
+    }
+
+    fn build_seeded(&self, base_dir: &Path) -> Result<(Camera, HittableList, SharedObjects)> {
+        for material in self.materials.values() {
+            material.load_images(base_dir)?;
+        }
+
+        // Shared objects are built once, in the order of their names, and numbered for the
+        // instances to reference.
+        let mut names: Vec<&String> = self.shared.keys().collect();
+        names.sort();
+        let mut shared: HashMap<String, Arc<dyn Hittable>> = HashMap::new();
+        let mut shared_objects = SharedObjects::new();
+        for (id, name) in names.into_iter().enumerate() {
+            let objects = &self.shared[name];
+            let mut list = HittableList::new();
+            self.add_objects(objects, base_dir, &HashMap::new(), &mut list)?;
+            let object: Arc<dyn Hittable> = if list.len() == 1 {
+                list.iter().next().unwrap().clone()
+            } else {
+                Arc::new(Bvh::new(&list))
+            };
+            shared_objects.insert(id as u64, object.clone());
+            shared.insert(name.clone(), Arc::new(SharedHittable::new(id as u64, object)));
+        }
+
+        // Entries are numbered from 1 in the order of the file, lights after the other objects.
+        let mut world = HittableList::new();
+        self.add_tagged_objects(&self.objects, 1, base_dir, &shared, &mut world)?;
+        let mut lights = HittableList::new();
+        self.add_tagged_objects(&self.lights, 1 + self.objects.len() as u32, base_dir, &shared, &mut lights)?;
+        for light in lights.iter() {
+            world.add(light.clone());
+        }
+
+        let mut camera = self.camera.clone();
+        if !lights.is_empty() {
+            camera.lights = Some(Arc::new(lights));
+        }
+        if let Some(background) = &self.background {
+            let background: Arc<dyn Background> = match background {
+                SceneBackground::Constant { color } => Arc::new(Constant::new(color)),
+                SceneBackground::Gradient { bottom, top } => Arc::new(Gradient::new(bottom, top)),
+                SceneBackground::EnvironmentMap { path, intensity, rotation } => {
+                    Arc::new(EnvironmentMap::load(base_dir.join(path), *intensity, *rotation)?)
+                }
+            };
+            camera.background = Some(background);
+        }
+
+        Ok((camera, world, shared_objects))
+    }
+
+    fn add_objects(
+        &self,
+        objects: &[SceneObject],
+        base_dir: &Path,
+        shared: &HashMap<String, Arc<dyn Hittable>>,
+        world: &mut HittableList
+    ) -> Result<()> {
+        for object in objects {
+            match object {
+                SceneObject::Sphere { center, radius, material } => {
+                    world.add(Arc::new(Sphere::new(center, *radius, self.material(material)?)));
+                }
+                SceneObject::Triangle { vertices: [p0, p1, p2], material } => {
+                    world.add(Arc::new(Triangle::new(p0, p1, p2, self.material(material)?)));
+                }
+                SceneObject::Quad { corner, u, v, material } => {
+                    world.add(Arc::new(Quad::new(corner, u, v, self.material(material)?)));
+                }
+                SceneObject::Plane { point, normal, material } => {
+                    world.add(Arc::new(Plane::new(point, normal, self.material(material)?)));
+                }
+                SceneObject::Box { a, b, material } => {
+                    world.add(Arc::new(make_box(a, b, self.material(material)?)));
+                }
+                SceneObject::Obj { path } => {
+                    for mesh in load_obj(base_dir.join(path))?.iter() {
+                        world.add(mesh.clone());
+                    }
+                }
+                SceneObject::Medium { boundary, density, material } => {
+                    let boundary = self.build_object(boundary, base_dir, shared)?;
+                    world.add(Arc::new(ConstantMedium::new(boundary, *density, self.material(material)?)));
+                }
+                SceneObject::Moving { object, offset } => {
+                    let object = self.build_object(object, base_dir, shared)?;
+                    world.add(Arc::new(LinearMotion::new(object, offset)));
+                }
+                SceneObject::Instance { object, transform } => {
+                    let shared_object = shared
+                        .get(object)
+                        .cloned()
+                        .ok_or_else(|| scene_error(format!("unknown shared object '{}'", object)))?;
+                    // Later operations apply on top of the earlier ones.
+                    let to_world = transform.iter().fold(Mat4::IDENTITY, |acc, op| op.matrix() * acc);
+                    if to_world.inverse().is_none() {
+                        return Err(scene_error(format!("transform of '{}' is not invertible", object)));
+                    }
+                    world.add(Arc::new(Transform::new(shared_object, &to_world)));
+                }
+                SceneObject::RandomSpheres { grid_size, avoid, clearance } => {
+                    for sphere in random_spheres(*grid_size, avoid, *clearance) {
+                        world.add(sphere);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Like add_objects, tagging everything built from the n-th entry with id `first_id + n`.
+    fn add_tagged_objects(
+        &self,
+        objects: &[SceneObject],
+        first_id: u32,
+        base_dir: &Path,
+        shared: &HashMap<String, Arc<dyn Hittable>>,
+        world: &mut HittableList
+    ) -> Result<()> {
+        for (n, object) in objects.iter().enumerate() {
+            let mut list = HittableList::new();
+            self.add_objects(std::slice::from_ref(object), base_dir, shared, &mut list)?;
+            for hittable in list.iter() {
+                world.add(Arc::new(Tagged::new(hittable.clone(), first_id + n as u32)));
+            }
+        }
+        Ok(())
+    }
+
+    // Builds a scene object that wraps another one, such as the boundary of a Medium, as a
+    // single hittable.
+    fn build_object(
+        &self,
+        object: &SceneObject,
+        base_dir: &Path,
+        shared: &HashMap<String, Arc<dyn Hittable>>
+    ) -> Result<Arc<dyn Hittable>> {
+        let mut list = HittableList::new();
+        self.add_objects(std::slice::from_ref(object), base_dir, shared, &mut list)?;
+        if list.len() == 1 {
+            Ok(list.iter().next().unwrap().clone())
+        } else {
+            Ok(Arc::new(list))
+        }
+    }
+}
+
+pub fn load_scene(path: impl AsRef<Path>) -> Result<(Camera, HittableList, SharedObjects)> {
+    let path = path.as_ref();
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    SceneDescription::from_file(path)?.build(base_dir)
+}
+
+pub fn random_spheres(grid_size: i32, avoid: &[Point3], clearance: f64) -> Vec<Arc<dyn Hittable>> {
+    let mut spheres: Vec<Arc<dyn Hittable>> = Vec::new();
+
+    for a in -grid_size..grid_size {
+        for b in -grid_size..grid_size {
+            let choose_mat = random_f64();
+            let center: Point3 = Point3::new_xyz((a as f64) + 0.9*random_f64(), 0.2, (b as f64) + 0.9*random_f64());
+
+            if avoid.iter().any(|p| (center - *p).length() <= clearance) {
+                continue;
+            }
+
+            let sphere_material: Arc<dyn Material> = if choose_mat < 0.8 {
+                // diffuse
+                let albedo = Color::random() * Color::random();
+                Arc::new(Lambertian::new(&albedo))
+            } else if choose_mat < 0.95 {
+                // metal
+                let albedo = Color::random_range(0.5, 1.);
+                let fuzz = random_f64_range(0., 0.5);
+                Arc::new(Metal::new(&albedo, fuzz))
+            } else {
+                // glass
+                Arc::new(Dialectric::new(1.5))
+            };
+
+            spheres.push(Arc::new(Sphere::new(&center, 0.2, sphere_material)));
+        }
+    }
+
+    spheres
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_scene, SceneDescription};
+    use std::path::Path;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::hittable::{Hittable, HitRecord};
+
+    #[test]
+    fn test_bundled_scenes_load() {
+        for entry in std::fs::read_dir("scenes").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let (_camera, world, _shared) = load_scene(&path).unwrap();
+                assert!(world.len() > 0, "{} has no objects", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn test_camera_defaults() {
+        let scene: SceneDescription = serde_json::from_str(r#"{
+            "camera": { "image_width": 320, "lookfrom": [1, 2, 3] },
+            "objects": []
+        }"#).unwrap();
+        let (camera, _world, _shared) = scene.build(Path::new(".")).unwrap();
+        assert_eq!(camera.image_width, 320);
+        assert_eq!(camera.lookfrom.z(), 3.);
+        // fields left out of the file fall back to Camera::new
+        assert_eq!(camera.samples_per_pixel, 10);
+        assert_eq!(camera.vfov, 90.);
+    }
+
+    #[test]
+    fn test_instances() {
+        let scene: SceneDescription = serde_json::from_str(r#"{
+            "camera": {},
+            "materials": { "white": { "type": "Lambertian", "albedo": [1, 1, 1] } },
+            "shared": {
+                "ball": [{ "type": "Sphere", "center": [0, 0, 0], "radius": 1, "material": "white" }]
+            },
+            "objects": [
+                { "type": "Instance", "object": "ball", "transform": [{ "Scale": [2, 2, 2] }, { "Translate": [0, 0, -10] }] },
+                { "type": "Instance", "object": "ball", "transform": [{ "Translate": [0, 0, 10] }] }
+            ]
+        }"#).unwrap();
+        let (_camera, world, _shared) = scene.build(Path::new(".")).unwrap();
+        assert_eq!(world.len(), 2);
+
+        let mut rec = HitRecord::default();
+        let r = Ray::new(Point3::default(), Vec3::new_xyz(0., 0., -1.));
+        assert!(world.hit(&r, Interval::new_min_max(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.t - 8.).abs() < 1e-9);
+        assert_eq!(rec.object_id, 1);
+        let r = Ray::new(Point3::default(), Vec3::new_xyz(0., 0., 1.));
+        assert!(world.hit(&r, Interval::new_min_max(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.t - 9.).abs() < 1e-9);
+        assert_eq!(rec.object_id, 2);
+    }
+
+    #[test]
+    fn test_unknown_material() {
+        let scene: SceneDescription = serde_json::from_str(r#"{
+            "camera": {},
+            "objects": [{ "type": "Sphere", "center": [0, 0, 0], "radius": 1, "material": "missing" }]
+        }"#).unwrap();
+        assert!(scene.build(Path::new(".")).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! Geometry shared between instances, which is serialized once and referenced by id afterwards.
+//!
+//! Building a scene gives a table of its shared objects along with the world. The client sends
+//! the table ahead of the objects referencing it, and object servers resolve the ids in the
+//! objects they receive against the table of the render, see Hittable::resolve_shared.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+
+// The shared objects of a scene, by id
+pub type SharedObjects = HashMap<u64, Arc<dyn Hittable>>;
+
+// Handle to a shared object. Only the id and bounding box get serialized; the receiving process
+// links the object again with resolve_shared.
+#[derive(Serialize, Deserialize)]
+pub struct SharedHittable {
+    id: u64,
+    bbox: BoundingBox,
+    #[serde(skip)]
+    object: OnceLock<Arc<dyn Hittable>>
+}
+
+impl SharedHittable {
+    // `object` as entry `id` of the shared objects of its scene.
+    pub fn new(id: u64, object: Arc<dyn Hittable>) -> Self {
+        SharedHittable { id, bbox: object.bounding_box(), object: OnceLock::from(object) }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+#[typetag::serde]
+impl Hittable for SharedHittable {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        self.object.get().expect("shared object used before resolve_shared").hit(r, ray_t, rec)
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.bbox.clone()
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3, time: f64) -> f64 {
+        self.object.get().expect("shared object used before resolve_shared").pdf_value(origin, direction, time)
+    }
+
+    fn random(&self, origin: &Point3, time: f64) -> Vec3 {
+        self.object.get().expect("shared object used before resolve_shared").random(origin, time)
+    }
+
+    fn resolve_shared(&self, shared: &SharedObjects) -> Result<()> {
+        let object = shared.get(&self.id)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("unknown shared object {}", self.id)))?;
+        let _ = self.object.set(object.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SharedHittable, SharedObjects};
+    use std::sync::OnceLock;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::bounding_box::BoundingBox;
+    use crate::raytracer::hittable::{Hittable, HitRecord};
+    use crate::raytracer::material::Lambertian;
+    use crate::raytracer::sphere::Sphere;
+    use crate::raytracer::transform::Transform;
+
+    #[test]
+    fn test_instances_serialize_by_id() {
+        let mat = Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])));
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere::new(&Point3::default(), 1., mat));
+        let shared: Arc<dyn Hittable> = Arc::new(SharedHittable::new(7, sphere.clone()));
+        let instance: Arc<dyn Hittable> = Arc::new(Transform::translate(shared, &Vec3::new_xyz(0., 0., -5.)));
+
+        // the instance only carries the id, and finds the sphere in the table of the scene
+        let config = bincode::config::standard();
+        let bytes = bincode::serde::encode_to_vec(&instance, config).unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("Lambertian"));
+        let (decoded, _): (Arc<dyn Hittable>, usize) = bincode::serde::decode_from_slice(&bytes, config).unwrap();
+        decoded.resolve_shared(&SharedObjects::from([(7, sphere)])).unwrap();
+
+        let r = Ray::new(Point3::default(), Vec3::new_xyz(0., 0., -1.));
+        let mut rec = HitRecord::default();
+        assert!(decoded.hit(&r, Interval::new_min_max(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.t - 4.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_unknown_id() {
+        // an id missing from the table is an error rather than an instance that never gets hit
+        let unknown = SharedHittable { id: 0, bbox: BoundingBox::new([Interval::UNIVERSE; 3]), object: OnceLock::new() };
+        let error = unknown.resolve_shared(&SharedObjects::new()).unwrap_err();
+        assert!(error.to_string().contains("unknown shared object 0"), "{}", error);
+    }
+}
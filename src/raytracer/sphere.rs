@@ -1,6 +1,21 @@
 use crate::raytracer::prelude::*;
 use crate::raytracer::hittable::{Hittable, HitRecord};
 use crate::raytracer::material::Material;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::pdf::Onb;
+
+// Random direction around +z within the cone subtended by a sphere of `radius` at squared
+// distance `distance_squared`.
+fn random_to_sphere(radius: f64, distance_squared: f64) -> Vec3 {
+    let r1 = random_f64();
+    let r2 = random_f64();
+    let cos_theta_max = f64::sqrt(f64::max(0., 1. - radius * radius / distance_squared));
+    let z = 1. + r2 * (cos_theta_max - 1.);
+
+    let phi = 2. * PI * r1;
+    let sin_theta = f64::sqrt(1. - z * z);
+    Vec3::new_xyz(f64::cos(phi) * sin_theta, f64::sin(phi) * sin_theta, z)
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Sphere {
@@ -21,6 +36,14 @@ impl Sphere {
     pub fn radius(&self) -> f64 {
         return self.radius;
     }
+
+    // Returns the texture coordinates of point p on the unit sphere around the origin, with u
+    // going around the y axis starting from -x and v going from the bottom (y = -1) to the top.
+    pub fn get_sphere_uv(p: &Point3) -> (f64, f64) {
+        let theta = f64::acos(-p.y());
+        let phi = f64::atan2(-p.z(), p.x()) + PI;
+        (phi / (2. * PI), theta / PI)
+    }
 }
 
 #[typetag::serde]
@@ -51,8 +74,34 @@ impl Hittable for Sphere {
         rec.p = r.at(rec.t);
         let outward_normal: Vec3 = (rec.p - self.center) / self.radius;
         rec.set_face_normal(r, &outward_normal);
+        (rec.u, rec.v) = Sphere::get_sphere_uv(&outward_normal);
         rec.mat = self.mat.clone();
 
         return true;
     }
+
+    fn bounding_box(&self) -> BoundingBox {
+        let rvec = Vec3::new_xyz(self.radius, self.radius, self.radius);
+        BoundingBox::new_points(&(self.center - rvec), &(self.center + rvec))
+    }
+
+    // Directions are sampled uniformly within the cone the sphere subtends from `origin`.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3, time: f64) -> f64 {
+        let mut rec = HitRecord::default();
+        if !self.hit(&Ray::new_time(*origin, *direction, time), Interval::new_min_max(0.001, f64::INFINITY), &mut rec) {
+            return 0.;
+        }
+
+        let distance_squared = (self.center - *origin).length_squared();
+        let cos_theta_max = f64::sqrt(f64::max(0., 1. - self.radius * self.radius / distance_squared));
+        let solid_angle = 2. * PI * (1. - cos_theta_max);
+        1. / solid_angle
+    }
+
+    fn random(&self, origin: &Point3, _time: f64) -> Vec3 {
+        let direction = self.center - *origin;
+        let distance_squared = direction.length_squared();
+        let uvw = Onb::new(&direction);
+        uvw.transform(&random_to_sphere(self.radius, distance_squared))
+    }
 }
\ No newline at end of file
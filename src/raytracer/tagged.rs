@@ -0,0 +1,46 @@
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::shared::SharedObjects;
+
+// Marks the hits on `object` with an id, which ends up in the object id AOV. The scene loader
+// tags every entry of the scene file, so the ids are the same on every server.
+#[derive(Serialize, Deserialize)]
+pub struct Tagged {
+    object: Arc<dyn Hittable>,
+    id: u32
+}
+
+impl Tagged {
+    pub fn new(object: Arc<dyn Hittable>, id: u32) -> Self {
+        Tagged { object, id }
+    }
+}
+
+#[typetag::serde]
+impl Hittable for Tagged {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        if !self.object.hit(r, ray_t, rec) {
+            return false;
+        }
+
+        rec.object_id = self.id;
+        true
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.object.bounding_box()
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3, time: f64) -> f64 {
+        self.object.pdf_value(origin, direction, time)
+    }
+
+    fn random(&self, origin: &Point3, time: f64) -> Vec3 {
+        self.object.random(origin, time)
+    }
+
+    fn resolve_shared(&self, shared: &SharedObjects) -> Result<()> {
+        self.object.resolve_shared(shared)
+    }
+}
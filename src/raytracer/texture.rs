@@ -0,0 +1,423 @@
+//! Textures, giving the color of a surface at texture coordinates (u, v) and point p.
+
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::OnceLock;
+use serde::{Deserializer, Serializer};
+use crate::raytracer::prelude::*;
+
+#[typetag::serde(tag = "type")]
+pub trait Texture: Send + Sync {
+    fn value(&self, u: f64, v: f64, p: &Point3) -> Color;
+
+    // Loads the image files the texture refers to, relative to `base_dir`. Called when the scene
+    // is built, see SceneDescription::build.
+    fn load_images(&self, _base_dir: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorOrTexture {
+    Color(Color),
+    Texture(Arc<dyn Texture>)
+}
+
+// Deserializes a texture field, which the (human readable) scene files may also give as a plain
+// color. Binary formats always hold the texture itself.
+pub fn deserialize_texture<'de, D>(deserializer: D) -> std::result::Result<Arc<dyn Texture>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    if !deserializer.is_human_readable() {
+        return Arc::<dyn Texture>::deserialize(deserializer);
+    }
+    Ok(match ColorOrTexture::deserialize(deserializer)? {
+        ColorOrTexture::Color(color) => Arc::new(SolidColor::new(&color)),
+        ColorOrTexture::Texture(texture) => texture
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SolidColor {
+    color: Color
+}
+
+impl SolidColor {
+    pub fn new(color: &Color) -> Self {
+        SolidColor { color: *color }
+    }
+}
+
+#[typetag::serde]
+impl Texture for SolidColor {
+    fn value(&self, _u: f64, _v: f64, _p: &Point3) -> Color {
+        self.color
+    }
+}
+
+// Solid 3D checker pattern of cubes with sides of length `scale`.
+#[derive(Serialize, Deserialize)]
+pub struct Checker {
+    scale: f64,
+    #[serde(deserialize_with = "deserialize_texture")]
+    even: Arc<dyn Texture>,
+    #[serde(deserialize_with = "deserialize_texture")]
+    odd: Arc<dyn Texture>
+}
+
+impl Checker {
+    pub fn new(scale: f64, even: Arc<dyn Texture>, odd: Arc<dyn Texture>) -> Self {
+        Checker { scale, even, odd }
+    }
+
+    pub fn new_colors(scale: f64, even: &Color, odd: &Color) -> Self {
+        Checker::new(scale, Arc::new(SolidColor::new(even)), Arc::new(SolidColor::new(odd)))
+    }
+}
+
+#[typetag::serde]
+impl Texture for Checker {
+    fn value(&self, u: f64, v: f64, p: &Point3) -> Color {
+        let sum: i64 = (0..3).map(|n| f64::floor(p[n] / self.scale) as i64).sum();
+        if sum.rem_euclid(2) == 0 {
+            self.even.value(u, v, p)
+        } else {
+            self.odd.value(u, v, p)
+        }
+    }
+
+    fn load_images(&self, base_dir: &Path) -> Result<()> {
+        self.even.load_images(base_dir)?;
+        self.odd.load_images(base_dir)
+    }
+}
+
+// 8-bit image mapped onto the (u, v) square, with v = 0 at the bottom row. Scene files give the
+// image `path`, relative to the scene file, and the pixels are loaded when the scene is built.
+// The pixels are kept in the texture, so it can be sent to other servers.
+pub struct ImageTexture {
+    path: Option<String>,
+    image: OnceLock<Image>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 3]>
+}
+
+impl Image {
+    fn check(&self) -> Result<()> {
+        if self.pixels.len() != self.width * self.height {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "image texture needs width * height pixels"));
+        }
+        Ok(())
+    }
+}
+
+// Either way of giving the image in human readable formats.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ImageSource<'a> {
+    File { path: Cow<'a, str> },
+    Pixels(Cow<'a, Image>)
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, pixels: Vec<[u8; 3]>) -> Self {
+        let image = Image { width, height, pixels };
+        image.check().expect("image texture needs width * height pixels");
+        ImageTexture { path: None, image: OnceLock::from(image) }
+    }
+
+    // A texture of the image at `path`, once load_images loads it.
+    pub fn new_path(path: &str) -> Self {
+        ImageTexture { path: Some(path.to_string()), image: OnceLock::new() }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let img = image::open(path)
+            .map_err(|e| std::io::Error::other(format!("{}: {}", path.display(), e)))?
+            .into_rgb8();
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        Ok(ImageTexture::new(width, height, img.pixels().map(|p| p.0).collect()))
+    }
+}
+
+// Human readable formats get the path of the image if there is one, like the scene files, and
+// binary formats get the pixels along with it.
+impl Serialize for ImageTexture {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let source = match (&self.path, self.image.get()) {
+                (Some(path), _) => ImageSource::File { path: path.into() },
+                (None, Some(image)) => ImageSource::Pixels(Cow::Borrowed(image)),
+                (None, None) => return Err(serde::ser::Error::custom("image texture without an image"))
+            };
+            source.serialize(serializer)
+        } else {
+            (&self.path, self.image.get()).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageTexture {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let (path, image) = if deserializer.is_human_readable() {
+            match ImageSource::deserialize(deserializer)? {
+                ImageSource::File { path } => (Some(path.into_owned()), None),
+                ImageSource::Pixels(image) => (None, Some(image.into_owned()))
+            }
+        } else {
+            <(Option<String>, Option<Image>)>::deserialize(deserializer)?
+        };
+        let texture = ImageTexture { path, image: OnceLock::new() };
+        if let Some(image) = image {
+            image.check().map_err(serde::de::Error::custom)?;
+            let _ = texture.image.set(image);
+        }
+        Ok(texture)
+    }
+}
+
+#[typetag::serde(name = "Image")]
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _p: &Point3) -> Color {
+        // cyan where the image is missing
+        let Some(image) = self.image.get().filter(|image| !image.pixels.is_empty()) else {
+            return Color::new([0., 1., 1.]);
+        };
+        // Flip v to image rows, which go from top to bottom.
+        let u = u.clamp(0., 1.);
+        let v = 1. - v.clamp(0., 1.);
+        let i = ((u * image.width as f64) as usize).min(image.width - 1);
+        let j = ((v * image.height as f64) as usize).min(image.height - 1);
+
+        // Undo the gamma 2 encoding that colors::linear_to_gamma applies on output.
+        let pixel = image.pixels[j * image.width + i];
+        Color::new(pixel.map(|c| {
+            let c = c as f64 / 255.;
+            c * c
+        }))
+    }
+
+    fn load_images(&self, base_dir: &Path) -> Result<()> {
+        if let Some(path) = &self.path && self.image.get().is_none() {
+            let loaded = ImageTexture::load(base_dir.join(path))?;
+            let _ = self.image.set(loaded.image.into_inner().unwrap());
+        }
+        Ok(())
+    }
+}
+
+// Gradient noise with a random vector at each lattice point, from "Ray Tracing: The Next Week".
+// The tables are generated once and serialized with the texture, so every server sees the same
+// noise.
+#[derive(Serialize, Deserialize)]
+pub struct Perlin {
+    randvec: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>
+}
+
+impl Perlin {
+    const POINT_COUNT: usize = 256;
+
+    pub fn new() -> Self {
+        Perlin {
+            randvec: (0..Self::POINT_COUNT).map(|_| unit_vector(&Vec3::random_range(-1., 1.))).collect(),
+            perm_x: Self::generate_perm(),
+            perm_y: Self::generate_perm(),
+            perm_z: Self::generate_perm()
+        }
+    }
+
+    fn generate_perm() -> Vec<usize> {
+        let mut p: Vec<usize> = (0..Self::POINT_COUNT).collect();
+        for i in (1..Self::POINT_COUNT).rev() {
+            let target = (random_f64() * (i + 1) as f64) as usize;
+            p.swap(i, target.min(i));
+        }
+        p
+    }
+
+    // Noise value in roughly [-1, 1].
+    pub fn noise(&self, p: &Point3) -> f64 {
+        let (i, j, k) = (f64::floor(p.x()), f64::floor(p.y()), f64::floor(p.z()));
+        let (u, v, w) = (p.x() - i, p.y() - j, p.z() - k);
+        let (i, j, k) = (i as i64, j as i64, k as i64);
+
+        // Hermite smoothing of the trilinear interpolation weights.
+        let (uu, vv, ww) = (u*u*(3.-2.*u), v*v*(3.-2.*v), w*w*(3.-2.*w));
+        let mask = Self::POINT_COUNT as i64 - 1;
+        let mut accum = 0.;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.perm_x[((i + di) & mask) as usize]
+                        ^ self.perm_y[((j + dj) & mask) as usize]
+                        ^ self.perm_z[((k + dk) & mask) as usize];
+                    let weight_v = Vec3::new_xyz(u - di as f64, v - dj as f64, w - dk as f64);
+                    let (fi, fj, fk) = (di as f64, dj as f64, dk as f64);
+                    accum += (fi*uu + (1.-fi)*(1.-uu))
+                        * (fj*vv + (1.-fj)*(1.-vv))
+                        * (fk*ww + (1.-fk)*(1.-ww))
+                        * dot(&self.randvec[index], &weight_v);
+                }
+            }
+        }
+        accum
+    }
+
+    // Sum of `depth` octaves of noise, each at twice the frequency and half the weight.
+    pub fn turbulence(&self, p: &Point3, depth: i32) -> f64 {
+        let mut accum = 0.;
+        let mut temp_p = *p;
+        let mut weight = 1.;
+        for _ in 0..depth {
+            accum += weight * self.noise(&temp_p);
+            weight *= 0.5;
+            temp_p = 2. * temp_p;
+        }
+        accum.abs()
+    }
+}
+
+impl Default for Perlin {
+    fn default() -> Self {
+        Perlin::new()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum NoiseStyle {
+    // plain Perlin noise, mapped to [0, 1]
+    Smooth,
+    // absolute value of several octaves of noise
+    Turbulence,
+    // sine stripes along z, phase shifted by turbulence
+    #[default]
+    Marble
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NoiseTexture {
+    // frequency of the noise
+    scale: f64,
+    #[serde(default = "default_noise_color")]
+    color: Color,
+    #[serde(default)]
+    style: NoiseStyle,
+    #[serde(default)]
+    noise: Perlin
+}
+
+fn default_noise_color() -> Color { Color::new([1., 1., 1.]) }
+
+impl NoiseTexture {
+    pub fn new(scale: f64, style: NoiseStyle) -> Self {
+        NoiseTexture { scale, color: default_noise_color(), style, noise: Perlin::new() }
+    }
+}
+
+#[typetag::serde(name = "Noise")]
+impl Texture for NoiseTexture {
+    fn value(&self, _u: f64, _v: f64, p: &Point3) -> Color {
+        let intensity = match self.style {
+            NoiseStyle::Smooth => 0.5 * (1. + self.noise.noise(&(self.scale * *p))),
+            NoiseStyle::Turbulence => self.noise.turbulence(&(self.scale * *p), 7),
+            NoiseStyle::Marble => 0.5 * (1. + f64::sin(self.scale * p.z() + 10. * self.noise.turbulence(p, 7)))
+        };
+        intensity * self.color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::rng;
+    use super::*;
+    use crate::raytracer::hittable::HitRecord;
+    use crate::raytracer::material::Material;
+
+    #[test]
+    fn test_color_or_texture() {
+        let plain: Arc<dyn Material> = serde_json::from_str(
+            r#"{ "type": "Lambertian", "albedo": [0.5, 0.25, 0.0] }"#).unwrap();
+        let checker: Arc<dyn Material> = serde_json::from_str(r#"{
+            "type": "Lambertian",
+            "albedo": { "type": "Checker", "scale": 1, "even": [1, 1, 1], "odd": [0, 0, 0] }
+        }"#).unwrap();
+        let albedo_at = |material: &dyn Material, p: Point3| material.albedo(&HitRecord { p, ..Default::default() });
+
+        // the plain color survives a round trip through JSON, and the checker one through the
+        // binary encoding
+        let json = serde_json::to_string(&plain).unwrap();
+        let plain: Arc<dyn Material> = serde_json::from_str(&json).unwrap();
+        assert_eq!(albedo_at(plain.as_ref(), Point3::new_xyz(3., 1., 2.)), Color::new([0.5, 0.25, 0.]));
+        let bytes = bincode::serde::encode_to_vec(&checker, bincode::config::standard()).unwrap();
+        let (decoded, _): (Arc<dyn Material>, usize) =
+            bincode::serde::decode_from_slice(&bytes, bincode::config::standard()).unwrap();
+        assert_eq!(albedo_at(decoded.as_ref(), Point3::new_xyz(0.5, 0.5, 0.5)), Color::new([1., 1., 1.]));
+        assert_eq!(albedo_at(decoded.as_ref(), Point3::new_xyz(1.5, 0.5, 0.5)), Color::new([0., 0., 0.]));
+    }
+
+    #[test]
+    fn test_checker() {
+        let checker = Checker::new_colors(0.5, &Color::new([1., 1., 1.]), &Color::new([0., 0., 0.]));
+        assert_eq!(checker.value(0., 0., &Point3::new_xyz(0.1, 0.1, 0.1)).x(), 1.);
+        assert_eq!(checker.value(0., 0., &Point3::new_xyz(0.6, 0.1, 0.1)).x(), 0.);
+        assert_eq!(checker.value(0., 0., &Point3::new_xyz(-0.1, 0.1, 0.1)).x(), 0.);
+        assert_eq!(checker.value(0., 0., &Point3::new_xyz(-0.6, 0.1, 0.1)).x(), 1.);
+    }
+
+    #[test]
+    fn test_image_texture() {
+        // 2x2 image, red in the top left and blue in the bottom right
+        let path = std::env::temp_dir().join(format!("dray_texture_test_{}.png", std::process::id()));
+        let img = image::RgbImage::from_fn(2, 2, |i, j| match (i, j) {
+            (0, 0) => image::Rgb([255, 0, 0]),
+            (1, 1) => image::Rgb([0, 0, 255]),
+            _ => image::Rgb([0, 0, 0])
+        });
+        img.save(&path).unwrap();
+
+        let texture = ImageTexture::load(&path).unwrap();
+        let p = Point3::default();
+        assert_eq!(texture.value(0.25, 0.75, &p), Color::new([1., 0., 0.]));
+        assert_eq!(texture.value(0.75, 0.25, &p), Color::new([0., 0., 1.]));
+
+        let bytes = bincode::serde::encode_to_vec(&texture, bincode::config::standard()).unwrap();
+        let (decoded, _): (ImageTexture, usize) =
+            bincode::serde::decode_from_slice(&bytes, bincode::config::standard()).unwrap();
+        assert_eq!(decoded.value(0.25, 0.75, &p), Color::new([1., 0., 0.]));
+
+        // scene files give the path, which stays the same through a round trip, and the image is
+        // loaded relative to the scene
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let json = format!(r#"{{ "type": "Image", "path": "{}" }}"#, file_name);
+        let texture: Arc<dyn Texture> = serde_json::from_str(&json).unwrap();
+        assert_eq!(texture.value(0.25, 0.75, &p), Color::new([0., 1., 1.]));
+        let texture: Arc<dyn Texture> = serde_json::from_str(&serde_json::to_string(&texture).unwrap()).unwrap();
+        texture.load_images(path.parent().unwrap()).unwrap();
+        assert_eq!(texture.value(0.25, 0.75, &p), Color::new([1., 0., 0.]));
+        assert!(ImageTexture::new_path("missing.png").load_images(path.parent().unwrap()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_noise_range() {
+        rng::seed_thread(1);
+        let perlin = Perlin::new();
+        for _ in 0..1000 {
+            let p = 10. * Point3::random_range(-1., 1.);
+            assert!(perlin.noise(&p).abs() <= 1.01);
+            // noise vanishes on the lattice points
+            assert_eq!(perlin.noise(&Point3::new_xyz(p.x().floor(), p.y().floor(), p.z().floor())), 0.);
+        }
+    }
+}
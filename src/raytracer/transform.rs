@@ -0,0 +1,203 @@
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::shared::SharedObjects;
+use crate::raytracer::mat4::Mat4;
+
+// Places `object` in the world with an affine transform. Rays are moved into object space for
+// the hit test, and the hit point and normal are moved back out. Without renormalizing the ray
+// direction, t stays the same in both spaces.
+#[derive(Serialize, Deserialize)]
+pub struct Transform {
+    object: Arc<dyn Hittable>,
+    to_world: Mat4,
+    to_object: Mat4,
+    // normals transform with the inverse transpose
+    normal_to_world: Mat4,
+    bbox: BoundingBox
+}
+
+impl Transform {
+    // Panics if `to_world` isn't invertible.
+    pub fn new(object: Arc<dyn Hittable>, to_world: &Mat4) -> Self {
+        let to_object = to_world.inverse().expect("transform matrix must be invertible");
+        let object_box = object.bounding_box();
+
+        // Enclose the eight transformed corners of the object's box. Unbounded objects stay
+        // unbounded, since their infinite corners don't survive the matrix product.
+        let bbox = if object_box.is_empty() {
+            BoundingBox::default()
+        } else if !object_box.surface_area().is_finite() {
+            BoundingBox::new([Interval::UNIVERSE; 3])
+        } else {
+            (0..8).fold(BoundingBox::default(), |acc, corner| {
+                let p = Point3::new(std::array::from_fn(|n| {
+                    let axis = object_box.axis_interval(n);
+                    if corner & (1 << n) == 0 { axis.min } else { axis.max }
+                }));
+                let p = to_world.transform_point(&p);
+                BoundingBox::new_enclosing(&acc, &BoundingBox::new_points(&p, &p))
+            })
+        };
+
+        Transform {
+            object,
+            to_world: *to_world,
+            to_object,
+            normal_to_world: to_object.transpose(),
+            bbox
+        }
+    }
+
+    pub fn translate(object: Arc<dyn Hittable>, offset: &Vec3) -> Self {
+        Transform::new(object, &Mat4::translation(offset))
+    }
+
+    pub fn rotate_y(object: Arc<dyn Hittable>, degrees: f64) -> Self {
+        Transform::new(object, &Mat4::rotation_y(degrees))
+    }
+
+    pub fn rotate(object: Arc<dyn Hittable>, axis: &Vec3, degrees: f64) -> Self {
+        Transform::new(object, &Mat4::rotation(axis, degrees))
+    }
+
+    pub fn scale(object: Arc<dyn Hittable>, factors: &Vec3) -> Self {
+        Transform::new(object, &Mat4::scaling(factors))
+    }
+}
+
+#[typetag::serde]
+impl Hittable for Transform {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        let object_r = Ray::new_time(
+            self.to_object.transform_point(r.origin()),
+            self.to_object.transform_vector(r.direction()),
+            r.time()
+        );
+
+        if !self.object.hit(&object_r, ray_t, rec) {
+            return false;
+        }
+
+        // The face orientation carries over, since the transformed normal and direction keep
+        // the sign of their dot product.
+        rec.p = self.to_world.transform_point(&rec.p);
+        rec.normal = unit_vector(&self.normal_to_world.transform_vector(&rec.normal));
+        true
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.bbox.clone()
+    }
+
+    // The density of the object, sampled in object space. Unless the transform is rigid, it
+    // changes solid angles, by |det| / |d|^3 for the object space image d of a unit direction.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3, time: f64) -> f64 {
+        let object_direction = self.to_object.transform_vector(&unit_vector(direction));
+        let object_pdf = self.object.pdf_value(&self.to_object.transform_point(origin), &object_direction, time);
+        object_pdf * self.to_object.linear_determinant().abs() / object_direction.length().powi(3)
+    }
+
+    fn random(&self, origin: &Point3, time: f64) -> Vec3 {
+        let object_direction = self.object.random(&self.to_object.transform_point(origin), time);
+        self.to_world.transform_vector(&object_direction)
+    }
+
+    fn resolve_shared(&self, shared: &SharedObjects) -> Result<()> {
+        self.object.resolve_shared(shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::rng;
+    use super::Transform;
+    use crate::raytracer::prelude::*;
+    use crate::raytracer::hittable::{Hittable, HitRecord};
+    use crate::raytracer::mat4::Mat4;
+    use crate::raytracer::material::{DiffuseLight, Lambertian};
+    use crate::raytracer::quad::{make_box, Quad};
+    use crate::raytracer::sphere::Sphere;
+
+    #[test]
+    fn test_inverse() {
+        let m = Mat4::translation(&Vec3::new_xyz(1., -2., 3.))
+            * Mat4::rotation(&Vec3::new_xyz(1., 2., 3.), 40.)
+            * Mat4::scaling(&Vec3::new_xyz(2., 0.5, 3.));
+        let inverse = m.inverse().unwrap();
+        let p = Point3::new_xyz(0.3, -4., 7.);
+        assert!((inverse.transform_point(&m.transform_point(&p)) - p).length() < 1e-9);
+        assert!(((m * inverse).transform_vector(&p) - p).length() < 1e-9);
+        assert!(Mat4::scaling(&Vec3::new_xyz(1., 0., 1.)).inverse().is_none());
+    }
+
+    #[test]
+    fn test_scaled_translated_sphere() {
+        // A unit sphere scaled by 2 along x and moved to (5, 0, 0) spans x in [3, 7].
+        let mat = Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])));
+        let sphere = Arc::new(Sphere::new(&Point3::default(), 1., mat));
+        let to_world = Mat4::translation(&Vec3::new_xyz(5., 0., 0.)) * Mat4::scaling(&Vec3::new_xyz(2., 1., 1.));
+        let ellipsoid = Transform::new(sphere, &to_world);
+
+        let r = Ray::new(Point3::new_xyz(0., 0., 0.), Vec3::new_xyz(1., 0., 0.));
+        let mut rec = HitRecord::default();
+        assert!(ellipsoid.hit(&r, Interval::new_min_max(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.t - 3.).abs() < 1e-9);
+        assert!((rec.p - Point3::new_xyz(3., 0., 0.)).length() < 1e-9);
+        assert!((rec.normal - Vec3::new_xyz(-1., 0., 0.)).length() < 1e-9);
+
+        // The normal of a stretched sphere isn't the stretched normal.
+        let r = Ray::new(Point3::new_xyz(5., 5., 0.), Vec3::new_xyz(0., -1., 0.));
+        assert!(ellipsoid.hit(&r, Interval::new_min_max(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.normal - Vec3::new_xyz(0., 1., 0.)).length() < 1e-9);
+
+        // (up to the padding of the corner boxes)
+        let bbox = ellipsoid.bounding_box();
+        assert!((bbox.axis_interval(0).min - 3.).abs() < 1e-3);
+        assert!((bbox.axis_interval(0).max - 7.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_rotated_box() {
+        // Rotating a box 90 degrees around y swaps its x and z extents.
+        let mat = Arc::new(Lambertian::new(&Color::new([0.5, 0.5, 0.5])));
+        let cuboid = Arc::new(make_box(&Point3::new_xyz(0., 0., 0.), &Point3::new_xyz(1., 1., 3.), mat));
+        let rotated = Transform::rotate_y(cuboid, 90.);
+
+        let bbox = rotated.bounding_box();
+        assert!((bbox.axis_interval(0).size() - 3.).abs() < 1e-3);
+        assert!((bbox.axis_interval(2).size() - 1.).abs() < 1e-3);
+
+        let r = Ray::new(Point3::new_xyz(1.5, 0.5, 0.5), Vec3::new_xyz(0., 0., 1.));
+        let mut rec = HitRecord::default();
+        assert!(!rotated.hit(&r, Interval::new_min_max(0.001, f64::INFINITY), &mut rec));
+        let r = Ray::new(Point3::new_xyz(1.5, 0.5, -5.), Vec3::new_xyz(0., 0., 1.));
+        assert!(rotated.hit(&r, Interval::new_min_max(0.001, f64::INFINITY), &mut rec));
+        assert!((rec.p.z() + 1.).abs() < 1e-9);
+        assert!(rec.front_face);
+    }
+
+    #[test]
+    fn test_transformed_light() {
+        rng::seed_thread(1);
+        // A quad light stretched, tilted and raised, sampled through the transform, has to have
+        // the same density as the quad built where it ends up.
+        let light = Arc::new(DiffuseLight::new(&Color::new([4., 4., 4.])));
+        let (q, u, v) = (Point3::new_xyz(-1., 0., -1.), Vec3::new_xyz(2., 0., 0.), Vec3::new_xyz(0., 0., 2.));
+        let to_world = Mat4::translation(&Vec3::new_xyz(0., 3., 0.))
+            * Mat4::rotation(&Vec3::new_xyz(1., 0., 0.), 30.)
+            * Mat4::scaling(&Vec3::new_xyz(2., 1., 0.5));
+        let transformed = Transform::new(Arc::new(Quad::new(&q, &u, &v, light.clone())), &to_world);
+        let placed = Quad::new(
+            &to_world.transform_point(&q), &to_world.transform_vector(&u), &to_world.transform_vector(&v), light);
+
+        let origin = Point3::new_xyz(0.5, 0., 0.2);
+        for _ in 0..1000 {
+            let direction = transformed.random(&origin, 0.);
+            let expected = placed.pdf_value(&origin, &direction, 0.);
+            assert!(expected > 0.);
+            assert!((transformed.pdf_value(&origin, &direction, 0.) - expected).abs() < 1e-9 * expected);
+        }
+        assert_eq!(transformed.pdf_value(&origin, &Vec3::new_xyz(0., -1., 0.), 0.), 0.);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::raytracer::prelude::*;
+use crate::raytracer::bounding_box::BoundingBox;
+use crate::raytracer::hittable::{Hittable, HitRecord};
+use crate::raytracer::material::Material;
+
+// Möller–Trumbore ray/triangle intersection. Returns the ray parameter along with the
+// barycentric coordinates (b1, b2) of the hit point relative to p1 and p2.
+pub fn intersect_triangle(r: &Ray, ray_t: Interval, p0: &Point3, p1: &Point3, p2: &Point3) -> Option<(f64, f64, f64)> {
+    let edge1 = *p1 - *p0;
+    let edge2 = *p2 - *p0;
+    let pvec = cross(r.direction(), &edge2);
+    let det = dot(&edge1, &pvec);
+
+    // The ray is parallel to the triangle plane.
+    if det.abs() < 1e-12 {
+        return None;
+    }
+
+    let inv_det = 1. / det;
+    let tvec = *r.origin() - *p0;
+    let b1 = dot(&tvec, &pvec) * inv_det;
+    if !(0. ..=1.).contains(&b1) {
+        return None;
+    }
+
+    let qvec = cross(&tvec, &edge1);
+    let b2 = dot(r.direction(), &qvec) * inv_det;
+    if b2 < 0. || b1 + b2 > 1. {
+        return None;
+    }
+
+    let t = dot(&edge2, &qvec) * inv_det;
+    if !ray_t.surrounds(t) {
+        return None;
+    }
+
+    Some((t, b1, b2))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Triangle {
+    vertices: [Point3; 3],
+    normals: Option<[Vec3; 3]>,
+    uvs: [(f64, f64); 3],
+    mat: Arc<dyn Material>
+}
+
+impl Triangle {
+    pub fn new(p0: &Point3, p1: &Point3, p2: &Point3, mat: Arc<dyn Material>) -> Self {
+        Triangle {
+            vertices: [*p0, *p1, *p2],
+            normals: None,
+            uvs: [(0., 0.), (1., 0.), (0., 1.)],
+            mat
+        }
+    }
+
+    pub fn new_w_attributes(
+        vertices: [Point3; 3],
+        normals: Option<[Vec3; 3]>,
+        uvs: [(f64, f64); 3],
+        mat: Arc<dyn Material>
+    ) -> Self {
+        Triangle { vertices, normals, uvs, mat }
+    }
+
+    pub fn vertices(&self) -> &[Point3; 3] {
+        &self.vertices
+    }
+}
+
+// Fills in the hit record for a triangle hit, shared between standalone triangles and meshes.
+// The geometric normal decides the front face, while the optional vertex normals give the
+// interpolated shading normal.
+pub(crate) fn set_triangle_hit(
+    r: &Ray,
+    rec: &mut HitRecord,
+    (t, b1, b2): (f64, f64, f64),
+    vertices: [&Point3; 3],
+    normals: Option<[&Vec3; 3]>,
+    uvs: [(f64, f64); 3],
+    mat: &Arc<dyn Material>
+) {
+    let b0 = 1. - b1 - b2;
+    rec.t = t;
+    rec.p = r.at(t);
+
+    let geometric_normal = unit_vector(&cross(&(*vertices[1] - *vertices[0]), &(*vertices[2] - *vertices[0])));
+    rec.set_face_normal(r, &geometric_normal);
+    if let Some([n0, n1, n2]) = normals {
+        let shading_normal = unit_vector(&(b0 * *n0 + b1 * *n1 + b2 * *n2));
+        rec.normal = if rec.front_face { shading_normal } else { -shading_normal };
+    }
+
+    rec.u = b0 * uvs[0].0 + b1 * uvs[1].0 + b2 * uvs[2].0;
+    rec.v = b0 * uvs[0].1 + b1 * uvs[1].1 + b2 * uvs[2].1;
+    rec.mat = mat.clone();
+}
+
+#[typetag::serde]
+impl Hittable for Triangle {
+    fn hit(&self, r: &Ray, ray_t: Interval, rec: &mut HitRecord) -> bool {
+        let [p0, p1, p2] = &self.vertices;
+        match intersect_triangle(r, ray_t, p0, p1, p2) {
+            Some(hit) => {
+                set_triangle_hit(
+                    r, rec, hit,
+                    [p0, p1, p2],
+                    self.normals.as_ref().map(|[n0, n1, n2]| [n0, n1, n2]),
+                    self.uvs,
+                    &self.mat
+                );
+                true
+            }
+            None => false
+        }
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        let [p0, p1, p2] = &self.vertices;
+        BoundingBox::new_enclosing(
+            &BoundingBox::new_points(p0, p1),
+            &BoundingBox::new_points(p2, p2)
+        )
+    }
+
+    // Uniform over the area of the triangle, like Quad.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3, time: f64) -> f64 {
+        let mut rec = HitRecord::default();
+        if !self.hit(&Ray::new_time(*origin, *direction, time), Interval::new_min_max(0.001, f64::INFINITY), &mut rec) {
+            return 0.;
+        }
+
+        let [p0, p1, p2] = &self.vertices;
+        let n = cross(&(*p1 - *p0), &(*p2 - *p0));
+        let area = 0.5 * n.length();
+        let distance_squared = rec.t * rec.t * direction.length_squared();
+        let cosine = (dot(direction, &n) / (direction.length() * n.length())).abs();
+        distance_squared / (cosine * area)
+    }
+
+    fn random(&self, origin: &Point3, _time: f64) -> Vec3 {
+        // folding the square onto the triangle keeps the points uniform
+        let (mut b1, mut b2) = (random_f64(), random_f64());
+        if b1 + b2 > 1. {
+            (b1, b2) = (1. - b1, 1. - b2);
+        }
+        let [p0, p1, p2] = &self.vertices;
+        *p0 + b1 * (*p1 - *p0) + b2 * (*p2 - *p0) - *origin
+    }
+}
@@ -5,6 +5,7 @@ use std::ops::{
 use crate::raytracer::prelude::*;
 
 #[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Vec3 {
     coords: [f64; 3]
 }
@@ -74,13 +75,12 @@ pub fn cross(u: &Vec3, v: &Vec3) -> Vec3 {
 }
 
 pub fn random_unit_vector() -> Vec3 {
-    loop {
-        let p = Vec3::random_range(-1.,1.);
-        let lensq = p.length_squared();
-        if 1e-160 <= lensq && lensq <= 1. {
-            return p / lensq.sqrt();
-        }
-    }
+    // Uniform in height and angle around z, which is uniform over the sphere (Archimedes' hat-box
+    // theorem). Always takes two random numbers, so sample patterns map to even directions.
+    let z = 1. - 2. * random_f64();
+    let phi = 2. * PI * random_f64();
+    let r = f64::sqrt(f64::max(0., 1. - z * z));
+    Vec3::new_xyz(r * f64::cos(phi), r * f64::sin(phi), z)
 }
 
 pub fn random_on_hemisphere(normal: &Vec3) -> Vec3 {
@@ -94,6 +94,14 @@ pub fn random_on_hemisphere(normal: &Vec3) -> Vec3 {
     }
 }
 
+pub fn random_cosine_direction() -> Vec3 {
+    // Random direction around +z, with density cos(theta) / pi.
+    let r1 = random_f64();
+    let r2 = random_f64();
+    let phi = 2. * PI * r1;
+    Vec3::new_xyz(f64::cos(phi) * r2.sqrt(), f64::sin(phi) * r2.sqrt(), (1. - r2).sqrt())
+}
+
 pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
     // dot(v,n)*(*n) is scaling n by projection of v onto n (no need to divide since n is unit)
     let b =  dot(v,n)*(*n);
@@ -109,12 +117,14 @@ pub fn refract(uv: &Vec3, n: &Vec3, etai_over_etat: f64) -> Vec3 {
 }
 
 pub fn random_in_unit_disk() -> Vec3 {
-    loop {
-        let p = Vec3::new_xyz(random_f64_range(-1.,1.), random_f64_range(-1.,1.), 0.0);
-        if p.length_squared() < 1. {
-            return p;
-        }
-    }
+    // Shirley and Chiu's concentric mapping of the square [-1, 1]^2 onto the disk, which keeps
+    // evenly spread points evenly spread.
+    let (a, b) = (random_f64_range(-1., 1.), random_f64_range(-1., 1.));
+    if a == 0. && b == 0. {
+        return Vec3::default();
+    }
+    let (r, phi) = if a.abs() > b.abs() { (a, PI / 4. * (b / a)) } else { (b, PI / 2. - PI / 4. * (a / b)) };
+    Vec3::new_xyz(r * f64::cos(phi), r * f64::sin(phi), 0.)
 }
 
 pub fn unit_vector(v: &Vec3) -> Vec3 {